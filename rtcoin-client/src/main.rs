@@ -4,12 +4,17 @@
 //
 
 use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
 use std::thread;
 use std::time::Duration;
 
 use clap::{crate_version, value_t}; // the macros
 use clap::{App, Arg, SubCommand};
 
+mod proof;
+
 fn main() -> Result<(), Box<dyn Error>> {
     println!();
     let args = App::new("rtcoin")
@@ -69,8 +74,46 @@ fn main() -> Result<(), Box<dyn Error>> {
         // I think. If init has happened. Else, the
         // default action should be to init.
         .subcommand(SubCommand::with_name("balance").about("Retrieve your rtcoin balance"))
+        // Checks a Merkle inclusion proof for an
+        // archived transfer, entirely on this end.
+        // The proof is the rows the server sent
+        // back, one per line. The root it has to
+        // lead to is given separately, since the
+        // server could send back any root at all.
+        .subcommand(
+            SubCommand::with_name("proof")
+                .about("Check the server's inclusion proof for an archived receipt")
+                .arg(
+                    Arg::with_name("receipt_id")
+                        .help("Receipt id from the transfer")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("receipt_hash")
+                        .help("Receipt hash from the transfer")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .help("Merkle root of the archive, as published when it was closed")
+                        .required(true)
+                        .index(3),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .help("File holding the proof. Reads stdin if left out.")
+                        .required(false)
+                        .index(4),
+                ),
+        )
         .get_matches();
 
+    if let ("proof", Some(proof_args)) = args.subcommand() {
+        return check_proof(proof_args);
+    }
+
     // let this_user = User::new("Bob Bobson");
 
     // Obviously concurrency isn't strictly
@@ -88,6 +131,52 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn check_proof(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let receipt_id = value_t!(args, "receipt_id", u32)?;
+    let receipt_hash = args.value_of("receipt_hash").unwrap_or_default();
+    let root = proof::from_hex(args.value_of("root").unwrap_or_default())?;
+
+    let body = match args.value_of("file") {
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let mut body = String::new();
+            io::stdin().read_to_string(&mut body)?;
+            body
+        }
+    };
+    let rows = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<&str>>();
+
+    let proof = proof::Proof::parse(&rows)?;
+    if let Err(err) = proof.check_receipt(receipt_id, receipt_hash) {
+        eprintln!(" {}", err);
+        process::exit(1);
+    }
+    if proof.root != root {
+        eprintln!(
+            " The server gave a different Merkle root for archive {} than the one expected",
+            proof.archive_id
+        );
+        process::exit(1);
+    }
+    if !proof.verify(&root) {
+        eprintln!(
+            " Proof does NOT lead to the Merkle root of archive {}",
+            proof.archive_id
+        );
+        process::exit(1);
+    }
+
+    let cols = proof.leaf.split('\t').collect::<Vec<&str>>();
+    println!(
+        " Receipt {} is in archive {}\n Ledger Entry: {}\n From: {}\n To: {}\n Amount: {} milli-tcoin\n At: {}",
+        receipt_id, proof.archive_id, cols[0], cols[3], cols[4], cols[5], cols[2]
+    );
+    Ok(())
+}
+
 // Currently, the following are just stubs meant to help me
 // mentally track the program's execution. They will most
 // likely not exist in the near future, replaced with
@@ -0,0 +1,131 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{fmt, str::FromStr};
+
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+// Number of milli-tcoin in one tcoin. Amounts can't
+// be any finer than this.
+pub const SCALE: i64 = 1000;
+
+// A quantity of tcoin, held as a whole number of
+// milli-tcoin. Integer arithmetic means balances
+// never drift the way they would with floats.
+// Stored in the database as an INTEGER.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_tcoin(tcoin: i64) -> Amount {
+        Amount(tcoin * SCALE)
+    }
+
+    pub const fn from_milli(milli: i64) -> Amount {
+        Amount(milli)
+    }
+
+    pub fn milli(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    // None on overflow, so callers can't wrap
+    // a balance around by accident.
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+}
+
+// Formats as a decimal with trailing zeroes
+// trimmed, so 1000 tcoin prints as "1000" and
+// 12.5 tcoin prints as "12.5".
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let whole = abs / SCALE as u64;
+        let frac = abs % SCALE as u64;
+
+        if frac == 0 {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            let frac = format!("{:03}", frac);
+            write!(f, "{}{}.{}", sign, whole, frac.trim_end_matches('0'))
+        }
+    }
+}
+
+// Accepts plain decimals like "5", "5.25" or ".5",
+// with at most three places after the point.
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid amount: {}", s);
+
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let mut parts = digits.splitn(2, '.');
+        let whole = parts.next().unwrap_or("");
+        let frac = parts.next().unwrap_or("");
+
+        if (whole.is_empty() && frac.is_empty())
+            || frac.len() > 3
+            || !whole.chars().all(|c| c.is_ascii_digit())
+            || !frac.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let whole = if whole.is_empty() {
+            0
+        } else {
+            whole.parse::<i64>().map_err(|_| invalid())?
+        };
+        let mut milli = if frac.is_empty() {
+            0
+        } else {
+            frac.parse::<i64>().map_err(|_| invalid())?
+        };
+        for _ in frac.len()..3 {
+            milli *= 10;
+        }
+
+        let total = whole
+            .checked_mul(SCALE)
+            .and_then(|val| val.checked_add(milli))
+            .ok_or_else(invalid)?;
+
+        if negative {
+            Ok(Amount(-total))
+        } else {
+            Ok(Amount(total))
+        }
+    }
+}
+
+impl ToSql for Amount {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for Amount {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        i64::column_result(value).map(Amount)
+    }
+}
@@ -0,0 +1,688 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{fs, path::Path};
+
+use merkle::{MerkleTree, Positioned};
+use ring::digest;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+
+use crate::{amount::Amount, db, err, ledger, query, receipt, user};
+
+// Where closed ledger periods are written out.
+pub const DIR: &str = "/tmp/rtcoinserver-archive";
+
+// Columns in an archive line. Archives written before
+// lines carried everything a row's hash covers have only
+// the first nine.
+const COLUMNS: usize = 14;
+const LEGACY_COLUMNS: usize = 9;
+
+// Closes out a ledger period. Accepts the args
+//     vec![admin, password, cutoff]
+// where the cutoff is an RFC3339 timestamp. Every ledger
+// row older than it gets moved into an archive file.
+// Admin only.
+pub fn close(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, cutoff",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+
+    let cutoff = match ledger::parse_timestamp(&args[2]) {
+        Some(val) => val,
+        None => {
+            let details = format!("Invalid cutoff: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match close_tx(conn, &cutoff, DIR) {
+        Ok(entry) => {
+            log::info!(
+                "Archive {}: ledger entries {} through {} moved to {}",
+                entry.id,
+                entry.first_id,
+                entry.last_id,
+                entry.filename
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Archived ledger entries {} through {} to {}. Merkle root: {}",
+                entry.first_id,
+                entry.last_id,
+                entry.filename,
+                ledger::to_hex(&entry.merkle_hash)
+            )));
+        }
+        Err(resp) => {
+            log::error!("Archive before {} failed: {}", cutoff, resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// The file is written last, so if anything before it
+// fails the transaction is simply dropped. If the commit
+// itself fails, the file has to go too.
+fn close_tx(
+    conn: &mut rusqlite::Connection,
+    cutoff: &str,
+    dir: &str,
+) -> Result<db::ArchiveEntry, err::Resp> {
+    let tx = conn.transaction()?;
+    let entry = close_before(&tx, cutoff, dir)?;
+    if let Err(error) = tx.commit() {
+        if let Err(error) = fs::remove_file(&entry.filename) {
+            log::error!("Could not remove {}: {:?}", entry.filename, error);
+        }
+        return Err(error.into());
+    }
+    Ok(entry)
+}
+
+// Moves every ledger row older than the cutoff into a file
+// under dir and records it in the archive table. Like
+// ledger::transfer(), this expects to be handed a
+// transaction.
+//
+// Rows are only ever archived from the front of the ledger,
+// so the live chain picks up from the last archived row's
+// hash, which is kept as the archive's hash. Each user's net
+// movement over the period is carried in archive_balances,
+// under the name they have now, so balances still reconcile.
+pub fn close_before(
+    conn: &rusqlite::Connection,
+    cutoff: &str,
+    dir: &str,
+) -> Result<db::ArchiveEntry, err::Resp> {
+    // Taking everything up to the newest row before the
+    // cutoff keeps the archive contiguous, even if the
+    // clock ever went backwards between two rows.
+    let last_id = conn.query_row_named(
+        "SELECT MAX(id) FROM ledger WHERE timestamp < :cutoff",
+        &[(":cutoff", &cutoff)],
+        |row| row.get::<usize, Option<i64>>(0),
+    )?;
+    let last_id = match last_id {
+        Some(val) => val,
+        None => {
+            let details = format!("No ledger entries older than {}", cutoff);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+
+    // An open dispute might still need its entry
+    // reversed, so it has to be settled first.
+    let disputed = conn.query_row_named(
+        "SELECT MIN(ledger_id) FROM disputes WHERE state = 'open' AND ledger_id <= :last_id",
+        &[(":last_id", &last_id)],
+        |row| row.get::<usize, Option<i64>>(0),
+    )?;
+    if let Some(id) = disputed {
+        let details = format!("Ledger entry {} has an open dispute", id);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM ledger WHERE id <= :last_id ORDER BY id",
+        query::LEDGER_COLUMNS
+    ))?;
+    let entries = stmt
+        .query_map_named(&[(":last_id", &last_id)], query::ledger_row)?
+        .collect::<rusqlite::Result<Vec<db::LedgerEntry>>>()?;
+    let lines = entries.iter().map(line).collect::<Vec<String>>();
+    let tree = MerkleTree::from_vec(&digest::SHA256, lines.clone());
+
+    let first = &entries[0];
+    let last = &entries[entries.len() - 1];
+    let filename = Path::new(dir)
+        .join(format!("ledger-{}-{}.tsv", first.id, last.id))
+        .to_string_lossy()
+        .into_owned();
+
+    let mut entry = db::ArchiveEntry {
+        id: 0,
+        transaction_type: "ledger".into(),
+        timestamp: ledger::now(),
+        state: "closed".into(),
+        merkle_hash: tree.root_hash().clone(),
+        hash: last.ledger_hash.clone(),
+        filename,
+        first_id: first.id,
+        last_id: last.id,
+    };
+
+    conn.execute_named(
+        "INSERT INTO archive (type, timestamp, state, merkle_hash, hash, filename, first_id, last_id)
+            VALUES (:type, :timestamp, :state, :merkle_hash, :hash, :filename, :first_id, :last_id)",
+        &[
+            (":type", &entry.transaction_type),
+            (":timestamp", &entry.timestamp),
+            (":state", &entry.state),
+            (":merkle_hash", &entry.merkle_hash),
+            (":hash", &entry.hash),
+            (":filename", &entry.filename),
+            (":first_id", &entry.first_id),
+            (":last_id", &entry.last_id),
+        ],
+    )?;
+    entry.id = conn.last_insert_rowid() as u32;
+
+    conn.execute_named(
+        "INSERT INTO archive_balances (archive_id, name, net)
+            SELECT :archive_id, name, SUM(net) FROM (
+                SELECT COALESCE(
+                    (SELECT name FROM users WHERE id = destination_id), destination
+                ) AS name, amount AS net FROM ledger WHERE id <= :last_id
+                UNION ALL
+                SELECT COALESCE(
+                    (SELECT name FROM users WHERE id = source_id), source
+                ) AS name, -amount AS net FROM ledger WHERE id <= :last_id
+            ) GROUP BY name",
+        &[(":archive_id", &entry.id), (":last_id", &last_id)],
+    )?;
+    conn.execute_named(
+        "DELETE FROM ledger WHERE id <= :last_id",
+        &[(":last_id", &last_id)],
+    )?;
+
+    let write = fs::create_dir_all(dir).and_then(|_| {
+        let mut body = lines.join("\n");
+        body.push('\n');
+        fs::write(&entry.filename, body)
+    });
+    if let Err(error) = write {
+        let details = format!("Could not write {}: {}", entry.filename, error);
+        return Err(err::Resp::new(1, "Worker Error", &details));
+    }
+
+    Ok(entry)
+}
+
+// One ledger row as a line of the archive file: every
+// column in LEDGER_COLUMNS order, tab-separated, with the
+// amount in milli-tcoin and a missing id left empty, so
+// the row can still be re-hashed once it's archived.
+// These lines are the leaves of the archive's Merkle tree.
+pub fn line(entry: &db::LedgerEntry) -> String {
+    let id = |val: Option<u32>| val.map(|id| id.to_string()).unwrap_or_default();
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        entry.id,
+        entry.transaction_type,
+        entry.timestamp,
+        entry.source,
+        entry.destination,
+        entry.amount.milli(),
+        entry.ledger_hash,
+        entry.receipt_id,
+        entry.receipt_hash,
+        id(entry.source_id),
+        id(entry.destination_id),
+        entry.hash_version,
+        id(entry.batch_id),
+        id(entry.reverses)
+    )
+}
+
+// Reads an archive line back into the row it was written
+// from. None for a legacy line, which is missing what the
+// row's hash covers, or for one that doesn't parse.
+pub fn parse(line: &str) -> Option<db::LedgerEntry> {
+    let cols = line.split('\t').collect::<Vec<&str>>();
+    if cols.len() != COLUMNS {
+        return None;
+    }
+    let id = |col: &str| match col {
+        "" => Some(None),
+        val => val.parse::<u32>().ok().map(Some),
+    };
+    Some(db::LedgerEntry {
+        id: cols[0].parse().ok()?,
+        transaction_type: cols[1].to_string(),
+        timestamp: cols[2].to_string(),
+        source: cols[3].to_string(),
+        destination: cols[4].to_string(),
+        amount: Amount::from_milli(cols[5].parse().ok()?),
+        ledger_hash: cols[6].to_string(),
+        receipt_id: cols[7].parse().ok()?,
+        receipt_hash: cols[8].to_string(),
+        source_id: id(cols[9])?,
+        destination_id: id(cols[10])?,
+        hash_version: cols[11].parse().ok()?,
+        batch_id: id(cols[12])?,
+        reverses: id(cols[13])?,
+    })
+}
+
+// Walks the archives in order, recomputing each archived
+// row's hash from the one before it, starting from
+// genesis, and checks each archive ends on the hash it
+// recorded, which is where the live chain picks up.
+// Legacy archives can't be re-hashed row by row, so the
+// chain is carried across them by their recorded hash.
+pub fn verify_chain(conn: &rusqlite::Connection) -> Result<ledger::Chain, err::Resp> {
+    let mut prev = ledger::GENESIS_HASH.to_string();
+    let mut count = 0;
+    for entry in in_order(conn)? {
+        let lines = checked(&entry)?;
+        if lines[0].split('\t').count() == COLUMNS {
+            for line in &lines {
+                let row = match parse(line) {
+                    Some(val) => val,
+                    None => {
+                        let details = format!("{} is not laid out as expected", entry.filename);
+                        return Err(err::Resp::new(13, "Archive Corrupt", &details));
+                    }
+                };
+                if ledger::chain_hash(&prev, &row) != row.ledger_hash {
+                    return Ok(ledger::Chain::Broken(row.id));
+                }
+                prev = row.ledger_hash;
+                count += 1;
+            }
+            if prev != entry.hash {
+                return Ok(ledger::Chain::Broken(entry.last_id));
+            }
+        }
+        prev = entry.hash;
+    }
+    Ok(ledger::Chain::Intact(count))
+}
+
+// A batch's archived rows, each checked against the row
+// before it in the chain. Only archives that carry every
+// hashed column know which batch a row belonged to.
+pub fn batch_rows(
+    conn: &rusqlite::Connection,
+    batch_id: u32,
+) -> Result<Vec<db::LedgerEntry>, err::Resp> {
+    let mut rows = Vec::new();
+    let mut prev = ledger::GENESIS_HASH.to_string();
+    for entry in in_order(conn)? {
+        for line in checked(&entry)? {
+            let row = match parse(&line) {
+                Some(val) => val,
+                None => continue,
+            };
+            let hash = row.ledger_hash.clone();
+            if row.batch_id == Some(batch_id) {
+                if ledger::chain_hash(&prev, &row) != hash {
+                    let details = format!("Archived ledger entry {} has been altered", row.id);
+                    return Err(err::Resp::new(10, "Chain Broken", &details));
+                }
+                rows.push(row);
+            }
+            prev = hash;
+        }
+        prev = entry.hash;
+    }
+    Ok(rows)
+}
+
+// The archived row that reversed a ledger entry, if
+// there is one. A reversal always comes after what it
+// reverses, so only archives reaching past it are read.
+pub fn reversal_of(conn: &rusqlite::Connection, ledger_id: u32) -> Result<Option<u32>, err::Resp> {
+    for entry in in_order(conn)? {
+        if entry.last_id <= ledger_id {
+            continue;
+        }
+        let found = checked(&entry)?
+            .iter()
+            .filter_map(|line| parse(line))
+            .find(|row| row.reverses == Some(ledger_id));
+        if let Some(row) = found {
+            return Ok(Some(row.id));
+        }
+    }
+    Ok(None)
+}
+
+// Archived rows an account sent that are stamped after
+// since. An archive closed before then can't hold any,
+// so only the ones closed since are read.
+pub fn sent_since(
+    conn: &rusqlite::Connection,
+    id: u32,
+    since: &str,
+) -> Result<Vec<db::LedgerEntry>, err::Resp> {
+    let mut rows = Vec::new();
+    for entry in in_order(conn)? {
+        if entry.timestamp.as_str() <= since {
+            continue;
+        }
+        rows.extend(
+            checked(&entry)?
+                .iter()
+                .filter_map(|line| parse(line))
+                .filter(|row| row.source_id == Some(id) && row.timestamp.as_str() > since),
+        );
+    }
+    Ok(rows)
+}
+
+// Every archive, oldest first.
+fn in_order(conn: &rusqlite::Connection) -> Result<Vec<db::ArchiveEntry>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, timestamp, state, merkle_hash, hash, filename, first_id, last_id
+            FROM archive ORDER BY first_id",
+    )?;
+    let entries = stmt
+        .query_map(NO_PARAMS, archive_row)?
+        .collect::<rusqlite::Result<Vec<db::ArchiveEntry>>>()?;
+    Ok(entries)
+}
+
+// Hash of the last row archived so far, which the first
+// live ledger row chains from. Genesis if nothing has
+// been archived yet.
+pub fn anchor(conn: &rusqlite::Connection) -> Result<String, err::Resp> {
+    let hash = conn
+        .query_row(
+            "SELECT hash FROM archive ORDER BY last_id DESC LIMIT 1",
+            NO_PARAMS,
+            |row| row.get::<usize, String>(0),
+        )
+        .optional()?;
+
+    Ok(hash.unwrap_or_else(|| ledger::GENESIS_HASH.to_string()))
+}
+
+// Answers a proof request. Accepts the args
+//     vec![receipt_id, receipt_hash]
+// for a transfer that has been archived, and replies
+// with rows the client can check for itself:
+//     archive     <archive id>    <merkle root>
+//     leaf        <archive line>
+//     left|right  <sibling hash>
+// with one sibling row per level of the tree, starting
+// from the leaf. Like verify, there's no authentication.
+pub fn prove(comm: db::Comm, conn: &rusqlite::Connection) {
+    let args = comm.args();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: receipt_id, receipt_hash",
+        ));
+        return;
+    }
+    let receipt_id = match args[0].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid receipt id: {}", args[0]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match proof(conn, receipt_id, &args[1]) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::warn!("No proof for receipt {}: {}", receipt_id, resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Builds the inclusion proof rows described above.
+// The archive file is checked against its recorded
+// root first, so a proof never vouches for a file
+// that's been edited since it was written.
+pub fn proof(
+    conn: &rusqlite::Connection,
+    receipt_id: u32,
+    hash: &str,
+) -> Result<Vec<String>, err::Resp> {
+    let live = conn.query_row_named(
+        "SELECT COUNT(*) FROM ledger WHERE receipt_id = :receipt_id",
+        &[(":receipt_id", &receipt_id)],
+        |row| row.get::<usize, i64>(0),
+    )?;
+    if live > 0 {
+        let details = format!(
+            "Receipt {} is still in the live ledger, verify it instead",
+            receipt_id
+        );
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    let (entry, lines, line) = find(conn, receipt_id)?;
+    let cols = line.split('\t').collect::<Vec<&str>>();
+    if cols[8] != hash || receipt::receipt_hash(receipt_id, cols[6]) != hash {
+        let details = format!(
+            "Receipt {} does not match archived entry {}",
+            receipt_id, cols[0]
+        );
+        return Err(err::Resp::new(11, "Receipt Mismatch", &details));
+    }
+
+    let tree = MerkleTree::from_vec(&digest::SHA256, lines);
+    if tree.root_hash() != &entry.merkle_hash {
+        let details = format!("{} does not match archive {}", entry.filename, entry.id);
+        return Err(err::Resp::new(13, "Archive Corrupt", &details));
+    }
+    let proof = match tree.gen_proof(line.clone()) {
+        Some(val) => val,
+        None => {
+            let details = format!("Could not build a proof for receipt {}", receipt_id);
+            return Err(err::Resp::new(1, "Worker Error", &details));
+        }
+    };
+
+    // The lemma runs from the root down to the leaf.
+    // Clients fold from the leaf up, so flip it.
+    let mut steps = Vec::new();
+    let mut lemma = &proof.lemma;
+    while let (Some(sibling), Some(sub)) = (&lemma.sibling_hash, &lemma.sub_lemma) {
+        steps.push(match sibling {
+            Positioned::Left(hash) => format!("left\t{}", ledger::to_hex(hash)),
+            Positioned::Right(hash) => format!("right\t{}", ledger::to_hex(hash)),
+        });
+        lemma = sub;
+    }
+    steps.reverse();
+
+    let mut rows = vec![
+        format!(
+            "archive\t{}\t{}",
+            entry.id,
+            ledger::to_hex(&entry.merkle_hash)
+        ),
+        format!("leaf\t{}", line),
+    ];
+    rows.append(&mut steps);
+    Ok(rows)
+}
+
+// Looks through the archive files, newest first, for
+// the line carrying the given receipt. Returns the
+// archive, all of its lines, and the line itself.
+fn find(
+    conn: &rusqlite::Connection,
+    receipt_id: u32,
+) -> Result<(db::ArchiveEntry, Vec<String>, String), err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, timestamp, state, merkle_hash, hash, filename, first_id, last_id
+            FROM archive ORDER BY id DESC",
+    )?;
+    let entries = stmt
+        .query_map(NO_PARAMS, archive_row)?
+        .collect::<rusqlite::Result<Vec<db::ArchiveEntry>>>()?;
+
+    let needle = receipt_id.to_string();
+    for entry in entries {
+        let lines = read(&entry)?;
+        let found = lines
+            .iter()
+            .find(|line| line.split('\t').nth(7) == Some(needle.as_str()))
+            .cloned();
+        if let Some(line) = found {
+            return Ok((entry, lines, line));
+        }
+    }
+
+    let details = format!("No archived entry carries receipt {}", receipt_id);
+    Err(err::Resp::new(11, "Receipt Mismatch", &details))
+}
+
+// What an account carried out of every archive that
+// ends at or before the given ledger id.
+pub fn carried_through(
+    conn: &rusqlite::Connection,
+    name: &str,
+    last_id: u32,
+) -> Result<Amount, err::Resp> {
+    Ok(conn.query_row_named(
+        "SELECT COALESCE(SUM(archive_balances.net), 0) FROM archive_balances
+            JOIN archive ON archive.id = archive_balances.archive_id
+            WHERE archive_balances.name = :name AND archive.last_id <= :last_id",
+        &[(":name", &name), (":last_id", &last_id)],
+        |row| row.get::<usize, Amount>(0),
+    )?)
+}
+
+// The archive a ledger row went into, if it's been
+// archived at all.
+pub fn holding(
+    conn: &rusqlite::Connection,
+    ledger_id: u32,
+) -> Result<Option<db::ArchiveEntry>, err::Resp> {
+    Ok(conn
+        .query_row_named(
+            "SELECT id, type, timestamp, state, merkle_hash, hash, filename, first_id, last_id
+                FROM archive WHERE first_id <= :ledger_id AND last_id >= :ledger_id",
+            &[(":ledger_id", &ledger_id)],
+            archive_row,
+        )
+        .optional()?)
+}
+
+// The newest archived row at or before the timestamp.
+// Archives are searched newest first, so this only reads
+// back as far as it has to.
+pub fn last_id_at(conn: &rusqlite::Connection, timestamp: &str) -> Result<Option<u32>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, timestamp, state, merkle_hash, hash, filename, first_id, last_id
+            FROM archive ORDER BY id DESC",
+    )?;
+    let entries = stmt
+        .query_map(NO_PARAMS, archive_row)?
+        .collect::<rusqlite::Result<Vec<db::ArchiveEntry>>>()?;
+
+    for entry in entries {
+        let found = checked(&entry)?
+            .iter()
+            .map(|line| line.split('\t').collect::<Vec<&str>>())
+            .filter(|cols| cols[2] <= timestamp)
+            .filter_map(|cols| cols[0].parse::<u32>().ok())
+            .max();
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+// What the given names netted over one archive's rows,
+// up to and including last_id. The archive only has the
+// names rows were written under, so the caller passes
+// every name the account has gone by.
+pub fn net_through(
+    entry: &db::ArchiveEntry,
+    names: &[String],
+    last_id: u32,
+) -> Result<Amount, err::Resp> {
+    let mut net = Amount::ZERO;
+    for line in checked(entry)? {
+        let cols = line.split('\t').collect::<Vec<&str>>();
+        match cols[0].parse::<u32>() {
+            Ok(id) if id <= last_id => {}
+            _ => continue,
+        }
+        let amount = match cols[5].parse::<i64>() {
+            Ok(val) => Amount::from_milli(val),
+            Err(_) => {
+                let details = format!("{} is not laid out as expected", entry.filename);
+                return Err(err::Resp::new(13, "Archive Corrupt", &details));
+            }
+        };
+        let moved = if names.iter().any(|name| name == cols[4]) {
+            net.checked_add(amount)
+        } else if names.iter().any(|name| name == cols[3]) {
+            net.checked_sub(amount)
+        } else {
+            Some(net)
+        };
+        net = moved.ok_or_else(|| {
+            let details = format!("Archive {} overflows a balance", entry.id);
+            err::Resp::new(9, "Balance Mismatch", &details)
+        })?;
+    }
+    Ok(net)
+}
+
+fn archive_row(row: &rusqlite::Row) -> rusqlite::Result<db::ArchiveEntry> {
+    Ok(db::ArchiveEntry {
+        id: row.get(0)?,
+        transaction_type: row.get(1)?,
+        timestamp: row.get(2)?,
+        state: row.get(3)?,
+        merkle_hash: row.get(4)?,
+        hash: row.get(5)?,
+        filename: row.get(6)?,
+        first_id: row.get(7)?,
+        last_id: row.get(8)?,
+    })
+}
+
+// Reads an archive file back into its lines. Every line
+// has to have the same columns, in either layout, or the
+// file is corrupt.
+fn read(entry: &db::ArchiveEntry) -> Result<Vec<String>, err::Resp> {
+    let body = match fs::read_to_string(&entry.filename) {
+        Ok(val) => val,
+        Err(error) => {
+            let details = format!("Could not read {}: {}", entry.filename, error);
+            return Err(err::Resp::new(13, "Archive Corrupt", &details));
+        }
+    };
+    let lines = body.lines().map(String::from).collect::<Vec<String>>();
+
+    let columns = lines.first().map(|line| line.split('\t').count());
+    let laid_out = match columns {
+        Some(COLUMNS) | Some(LEGACY_COLUMNS) => lines
+            .iter()
+            .all(|line| Some(line.split('\t').count()) == columns),
+        _ => false,
+    };
+    if !laid_out {
+        let details = format!("{} is not laid out as expected", entry.filename);
+        return Err(err::Resp::new(13, "Archive Corrupt", &details));
+    }
+    Ok(lines)
+}
+
+// Same, but the lines also have to add up to the
+// archive's recorded root.
+fn checked(entry: &db::ArchiveEntry) -> Result<Vec<String>, err::Resp> {
+    let lines = read(entry)?;
+    let tree = MerkleTree::from_vec(&digest::SHA256, lines.clone());
+    if tree.root_hash() != &entry.merkle_hash {
+        let details = format!("{} does not match archive {}", entry.filename, entry.id);
+        return Err(err::Resp::new(13, "Archive Corrupt", &details));
+    }
+    Ok(lines)
+}
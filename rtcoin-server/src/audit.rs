@@ -0,0 +1,284 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{fmt, sync::mpsc, thread, time::Duration};
+
+use chrono::prelude::*;
+use rusqlite::NO_PARAMS;
+
+use crate::{amount::Amount, archive, db, err, escrow, ledger, treasury, user};
+
+const DAY: i64 = 60 * 60 * 24;
+
+// One line of an audit report. Serialized as
+//     check   ok|fail     details
+// so the report fits in a Reply::Rows.
+#[derive(Debug)]
+pub struct Finding {
+    pub check: &'static str,
+    pub ok: bool,
+    pub details: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = if self.ok { "ok" } else { "fail" };
+        write!(f, "{}\t{}\t{}", self.check, status, self.details)
+    }
+}
+
+impl Finding {
+    fn new(check: &'static str, ok: bool, details: String) -> Finding {
+        Finding { check, ok, details }
+    }
+}
+
+// Runs a full audit on behalf of an admin. Accepts the args
+//     vec![admin, password]
+// and replies with the report's rows, summary first.
+pub fn audit(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+
+    log::info!("Audit requested by {}", args[0]);
+    reply_report(&comm, conn);
+}
+
+// Same, for the nightly run. That comes in as an
+// internal Query, so there's nobody to authenticate.
+pub fn scheduled(comm: db::Comm, conn: &rusqlite::Connection) {
+    log::info!("Running scheduled audit");
+    reply_report(&comm, conn);
+}
+
+fn reply_report(comm: &db::Comm, conn: &rusqlite::Connection) {
+    match report(conn) {
+        Ok(findings) => {
+            let failed = findings.iter().filter(|finding| !finding.ok).count();
+            for finding in findings.iter().filter(|finding| !finding.ok) {
+                log::error!("Audit: {}: {}", finding.check, finding.details);
+            }
+            log::info!("Audit finished with {} problems", failed);
+
+            let summary = Finding::new("audit", failed == 0, format!("{} problems found", failed));
+            let mut rows = vec![summary.to_string()];
+            rows.extend(findings.iter().map(|finding| finding.to_string()));
+            comm.reply(db::Reply::Rows(rows));
+        }
+        Err(resp) => {
+            log::error!("Audit could not finish: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// The full consistency pass. A failed check shows up as a
+// finding rather than an error; errors are for when the
+// checks themselves couldn't run.
+pub fn report(conn: &rusqlite::Connection) -> Result<Vec<Finding>, err::Resp> {
+    let mut findings = vec![supply(conn)?];
+    findings.append(&mut balances(conn)?);
+    findings.push(held(conn)?);
+    findings.push(archived(conn)?);
+    findings.push(chain(conn)?);
+    findings.append(&mut names(conn)?);
+    Ok(findings)
+}
+
+// Only mint and burn rows change how much tcoin there
+// is, so everything users hold, plus whatever is sitting
+// in escrow and the treasury, should add up to what was
+// minted less what was burned.
+fn supply(conn: &rusqlite::Connection) -> Result<Finding, err::Resp> {
+    let balances = conn.query_row(
+        "SELECT COALESCE(SUM(balance), 0) FROM users",
+        NO_PARAMS,
+        |row| row.get::<usize, Amount>(0),
+    )?;
+    let treasury = treasury::balance(conn)?;
+    let held = balances
+        .checked_add(escrow::total_held(conn)?)
+        .and_then(|val| val.checked_add(treasury));
+    let held = match held {
+        Some(val) => val,
+        None => {
+            return Ok(Finding::new(
+                "supply",
+                false,
+                "Balances, escrow and the treasury together overflow".into(),
+            ))
+        }
+    };
+    let issued = treasury::issued(conn)?;
+
+    Ok(Finding::new(
+        "supply",
+        held == issued,
+        format!("{} tcoin held, {} tcoin issued", held, issued),
+    ))
+}
+
+// One finding per user whose balance doesn't match
+// their ledger history, or a single passing one.
+fn balances(conn: &rusqlite::Connection) -> Result<Vec<Finding>, err::Resp> {
+    let mut stmt = conn.prepare("SELECT name FROM users ORDER BY name")?;
+    let names = stmt
+        .query_map(NO_PARAMS, |row| row.get::<usize, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut findings = Vec::new();
+    for name in &names {
+        let (stored, computed) = ledger::reconcile(conn, name)?;
+        if stored != computed {
+            findings.push(Finding::new(
+                "balance",
+                false,
+                format!(
+                    "{} holds {} tcoin, ledger history says {}",
+                    name, stored, computed
+                ),
+            ));
+        }
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::new(
+            "balance",
+            true,
+            format!("{} users match their ledger history", names.len()),
+        ));
+    }
+    Ok(findings)
+}
+
+// What the escrow table says it's holding should be
+// what the ledger has moved into the escrow account and
+// not yet back out.
+fn held(conn: &rusqlite::Connection) -> Result<Finding, err::Resp> {
+    let held = escrow::total_held(conn)?;
+    let net = ledger::net(conn, escrow::ACCOUNT)?;
+    Ok(Finding::new(
+        "escrow",
+        held == net,
+        format!("{} tcoin held in escrow, ledger history says {}", held, net),
+    ))
+}
+
+fn archived(conn: &rusqlite::Connection) -> Result<Finding, err::Resp> {
+    Ok(match archive::verify_chain(conn)? {
+        ledger::Chain::Intact(count) => Finding::new(
+            "archive",
+            true,
+            format!("{} archived entries intact", count),
+        ),
+        ledger::Chain::Broken(id) => Finding::new(
+            "archive",
+            false,
+            format!("First broken link at archived entry {}", id),
+        ),
+    })
+}
+
+fn chain(conn: &rusqlite::Connection) -> Result<Finding, err::Resp> {
+    Ok(match ledger::verify_chain(conn)? {
+        ledger::Chain::Intact(count) => {
+            Finding::new("chain", true, format!("{} entries intact", count))
+        }
+        ledger::Chain::Broken(id) => Finding::new(
+            "chain",
+            false,
+            format!("First broken link at ledger entry {}", id),
+        ),
+    })
+}
+
+// Ledger rows naming someone who isn't in the users
+// table can't be reconciled against anyone. Users are
+// matched by id, so a rename doesn't count, but a row's
+// id has to belong to the name on it, now or before a
+// rename. Older rows don't have their ids in the hash
+// chain, so this is what notices one being changed.
+fn names(conn: &rusqlite::Connection) -> Result<Vec<Finding>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT entry.id, entry.name, users.name FROM (
+            SELECT id, source AS name, source_id AS user_id FROM ledger
+            UNION ALL
+            SELECT id, destination AS name, destination_id AS user_id FROM ledger
+        ) AS entry LEFT JOIN users ON users.id = entry.user_id
+        WHERE (entry.user_id IS NULL AND entry.name NOT IN (:escrow, :treasury, :mint))
+            OR (entry.user_id IS NOT NULL
+                AND entry.name IS NOT users.name
+                AND entry.name NOT IN
+                    (SELECT name FROM aliases WHERE aliases.user_id = entry.user_id))
+        ORDER BY entry.id",
+    )?;
+    let unknown = stmt
+        .query_map_named(
+            &[
+                (":escrow", &escrow::ACCOUNT),
+                (":treasury", &treasury::ACCOUNT),
+                (":mint", &treasury::MINT),
+            ],
+            |row| {
+                Ok((
+                    row.get::<usize, u32>(0)?,
+                    row.get::<usize, String>(1)?,
+                    row.get::<usize, Option<String>>(2)?,
+                ))
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<(u32, String, Option<String>)>>>()?;
+
+    if unknown.is_empty() {
+        return Ok(vec![Finding::new(
+            "users",
+            true,
+            "Every ledger entry names known users".into(),
+        )]);
+    }
+    Ok(unknown
+        .into_iter()
+        .map(|(id, name, owner)| {
+            let details = match owner {
+                Some(owner) => format!(
+                    "Ledger entry {} names {} but counts toward {}",
+                    id, name, owner
+                ),
+                None => format!("Ledger entry {} names unknown user {}", id, name),
+            };
+            Finding::new("users", false, details)
+        })
+        .collect())
+}
+
+// Asks the ledger worker for an audit every night at
+// midnight UTC. The worker logs the findings, so the
+// reply only matters for knowing it's done.
+pub fn nightly(pipe: mpsc::Sender<db::Comm>) {
+    loop {
+        let wait = DAY - Utc::now().timestamp().rem_euclid(DAY);
+        thread::sleep(Duration::from_secs(wait as u64));
+
+        let (tx, rx) = mpsc::channel::<db::Reply>();
+        let comm = db::Comm::new(Some(db::Kind::Query), Some(vec!["audit".into()]), Some(tx));
+        if pipe.send(comm).is_err() {
+            log::warn!("Ledger worker is gone, stopping nightly audits");
+            return;
+        }
+        if let Err(err) = rx.recv() {
+            log::warn!("No reply to scheduled audit: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use crate::{amount::Amount, db, err, ledger, receipt, user};
+
+// Most recipients a single batch can pay.
+pub const MAX_PAYMENTS: usize = 100;
+
+// Pays several recipients at once. Accepts the args
+//     vec![sender, password, recipient=amount...]
+// Every payment lands, or none of them do. Each gets its
+// own ledger row, tied together by a batch id, and the
+// reply carries one receipt covering all of them.
+pub fn send(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: sender, password, recipient=amount...",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let batch =
+        parse_payments(conn, &args[2..]).and_then(|payments| send_tx(conn, &args[0], &payments));
+    match batch {
+        Ok(batch) => {
+            log::info!(
+                "Batch {}: {} tcoin from {} in {} payments",
+                batch.id,
+                batch.total,
+                args[0],
+                batch.count
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Sent {} tcoin in {} payments. Receipt {}: {}",
+                batch.total, batch.count, batch.receipt_id, batch.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!("Batch from {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Reads recipient=amount pairs. Each recipient is
+// looked up under the name they have now, and can only
+// appear once, even by an old name, so a batch can't be
+// padded out with repeats of the same payment by mistake.
+pub fn parse_payments(
+    conn: &rusqlite::Connection,
+    args: &[String],
+) -> Result<Vec<(String, Amount)>, err::Resp> {
+    if args.len() > MAX_PAYMENTS {
+        let details = format!("A batch can pay at most {} recipients", MAX_PAYMENTS);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    let mut payments: Vec<(String, Amount)> = Vec::new();
+    for arg in args {
+        let invalid = |details: String| err::Resp::new(3, "Invalid Request", &details);
+        let mut pair = arg.splitn(2, '=');
+        let (name, amount) = match (pair.next(), pair.next()) {
+            (Some(name), Some(amount)) if !name.is_empty() => (name, amount),
+            _ => return Err(invalid(format!("Invalid payment: {}", arg))),
+        };
+        let amount = amount.parse::<Amount>().map_err(invalid)?;
+        let current = user::resolve(conn, name)?;
+        if payments.iter().any(|(seen, _)| *seen == current) {
+            return Err(invalid(format!("{} is paid twice", name)));
+        }
+        payments.push((current, amount));
+    }
+    Ok(payments)
+}
+
+fn send_tx(
+    conn: &mut rusqlite::Connection,
+    source: &str,
+    payments: &[(String, Amount)],
+) -> Result<db::BatchEntry, err::Resp> {
+    let tx = conn.transaction()?;
+    let batch = pay(&tx, source, payments)?;
+    tx.commit()?;
+    Ok(batch)
+}
+
+// Makes every payment through ledger::transfer() and
+// issues the combined receipt over the rows it wrote.
+// Expects to be handed a transaction, since it's the
+// rollback that makes the batch all or nothing.
+pub fn pay(
+    conn: &rusqlite::Connection,
+    source: &str,
+    payments: &[(String, Amount)],
+) -> Result<db::BatchEntry, err::Resp> {
+    let created = ledger::now();
+    conn.execute_named(
+        "INSERT INTO batches (source, created, total, count, receipt_id, receipt_hash)
+            VALUES (:source, :created, 0, 0, 0, '')",
+        &[(":source", &source), (":created", &created)],
+    )?;
+    let id = conn.last_insert_rowid() as u32;
+
+    let mut total = Amount::from_milli(0);
+    let mut hashes = Vec::new();
+    for (destination, amount) in payments {
+        let links = ledger::Links {
+            batch_id: Some(id),
+            reverses: None,
+        };
+        let entry = ledger::transfer_linked(conn, "batch", source, destination, *amount, links)?;
+        total = match total.checked_add(*amount) {
+            Some(val) => val,
+            None => {
+                let details = format!("Batch total overflows from {}", source);
+                return Err(err::Resp::new(3, "Invalid Request", &details));
+            }
+        };
+        hashes.push(entry.ledger_hash);
+    }
+
+    let receipt_id = receipt::new_id(conn)?;
+    let receipt_hash = receipt::batch_hash(receipt_id, &hashes);
+    conn.execute_named(
+        "UPDATE batches SET total = :total, count = :count,
+            receipt_id = :receipt_id, receipt_hash = :receipt_hash
+            WHERE id = :id",
+        &[
+            (":total", &total),
+            (":count", &(hashes.len() as u32)),
+            (":receipt_id", &receipt_id),
+            (":receipt_hash", &receipt_hash),
+            (":id", &id),
+        ],
+    )?;
+
+    Ok(db::BatchEntry {
+        id,
+        source: source.to_string(),
+        created,
+        total,
+        count: hashes.len() as u32,
+        receipt_id,
+        receipt_hash,
+    })
+}
@@ -9,10 +9,18 @@ use rusqlite::{Connection, OpenFlags, NO_PARAMS};
 
 use zeroize::Zeroize;
 
-use crate::{err, query, user};
+use crate::{
+    amount::{self, Amount},
+    archive, audit, batch, dispute, err, escrow, freeze, idempotency, invoice, ledger, limit,
+    message, order, pending, query, receipt, reversal, stats, treasury, user,
+};
 
 pub const PATH: &str = "/tmp/rtcoinserver.db";
 
+// Bump this and add a step to migrate() whenever the
+// schema changes, so existing databases get upgraded.
+const SCHEMA_VERSION: i64 = 13;
+
 // Wrapper for the database connection and the
 // communication channel.
 #[derive(Debug)]
@@ -24,16 +32,22 @@ pub struct DB {
 // Represents a single request, or communication,
 // intended for the database worker thread.
 // Includes an outbound channel for the response.
+// The key is the client's optional idempotency key.
+// Internal requests are ones the server makes itself on
+// a user's behalf, like a standing order's payments. They
+// never come from a client, and carry no password.
 #[derive(Debug, Clone)]
 pub struct Comm {
     pub kind: Option<Kind>,
     pub args: Option<Vec<String>>,
     pub origin: Option<mpsc::Sender<Reply>>,
+    pub key: Option<String>,
+    pub internal: bool,
 }
 
 // Type of transaction we're doing with the
 // database.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Kind {
     Register,
     Query,
@@ -47,6 +61,38 @@ pub enum Kind {
     Audit,
     Resolve,
     Second,
+    Receipts,
+    Archive,
+    Proof,
+    Propose,
+    History,
+    Messages,
+    Order,
+    Orders,
+    Cancel,
+    Escrow,
+    Release,
+    Refund,
+    Batch,
+    Invoice,
+    Invoices,
+    Approve,
+    Decline,
+    Mint,
+    Burn,
+    Grant,
+    Reverse,
+    Deactivate,
+    Freeze,
+    Unfreeze,
+    Limit,
+    Limits,
+    AsOf,
+    Stats,
+    Report,
+    Privacy,
+    Quorum,
+    Signers,
     Disconnect,
     Empty,
     Quit,
@@ -74,13 +120,21 @@ pub struct LedgerEntry {
     pub timestamp: String,
     pub source: String,
     pub destination: String,
-    pub amount: f64,
+    pub amount: Amount,
     pub ledger_hash: String,
     pub receipt_id: u32,
     pub receipt_hash: String,
+    pub source_id: Option<u32>,
+    pub destination_id: Option<u32>,
+    pub hash_version: u32,
+    pub batch_id: Option<u32>,
+    pub reverses: Option<u32>,
 }
 
-// Same, but for archive table rows.
+// Same, but for archive table rows. The hash is
+// the ledger_hash of the last row archived, and
+// first_id and last_id bound the ledger ids the
+// archive file holds.
 #[derive(Debug)]
 pub struct ArchiveEntry {
     pub id: u32,
@@ -90,6 +144,105 @@ pub struct ArchiveEntry {
     pub merkle_hash: Vec<u8>,
     pub hash: String,
     pub filename: String,
+    pub first_id: u32,
+    pub last_id: u32,
+}
+
+// A contested ledger entry. State is one of open,
+// upheld or dismissed. The last three fields are
+// filled in once it's resolved, the reversal only
+// if it was upheld.
+#[derive(Debug)]
+pub struct DisputeEntry {
+    pub id: u32,
+    pub ledger_id: u32,
+    pub contestant: String,
+    pub reason: String,
+    pub state: String,
+    pub opened: String,
+    pub resolved: Option<String>,
+    pub resolver: Option<String>,
+    pub reversal_id: Option<u32>,
+}
+
+// A transfer waiting on signatures until it expires.
+// State is one of pending, settled, declined or expired.
+// Once it settles, the ledger id points at the row it
+// became.
+#[derive(Debug)]
+pub struct PendingEntry {
+    pub id: u32,
+    pub source: String,
+    pub destination: String,
+    pub amount: Amount,
+    pub created: String,
+    pub expires: String,
+    pub state: String,
+    pub ledger_id: Option<u32>,
+}
+
+// A standing order: the same transfer every interval
+// seconds, with the next one due at next_due. State is
+// one of active, cancelled or finished.
+#[derive(Debug)]
+pub struct OrderEntry {
+    pub id: u32,
+    pub owner: String,
+    pub destination: String,
+    pub amount: Amount,
+    pub interval: i64,
+    pub next_due: String,
+    pub ends: Option<String>,
+    pub message: Option<String>,
+    pub state: String,
+}
+
+// Funds held for a recipient. State is one of held,
+// released or refunded. hold_id is the ledger row that
+// took the funds in, settled_id the one that paid them
+// out.
+#[derive(Debug)]
+pub struct EscrowEntry {
+    pub id: u32,
+    pub source: String,
+    pub destination: String,
+    pub arbiter: Option<String>,
+    pub amount: Amount,
+    pub created: String,
+    pub expires: String,
+    pub state: String,
+    pub hold_id: u32,
+    pub settled_id: Option<u32>,
+}
+
+// Several payments from one sender made together. The
+// receipt covers every ledger row carrying the batch id.
+#[derive(Debug)]
+pub struct BatchEntry {
+    pub id: u32,
+    pub source: String,
+    pub created: String,
+    pub total: Amount,
+    pub count: u32,
+    pub receipt_id: u32,
+    pub receipt_hash: String,
+}
+
+// A request from the payee for the payer to send them
+// tcoin. State is one of open, paid or declined; an open
+// invoice past its expiry can no longer be paid. Once
+// paid, the ledger id points at the payment.
+#[derive(Debug)]
+pub struct InvoiceEntry {
+    pub id: u32,
+    pub payee: String,
+    pub payer: String,
+    pub amount: Amount,
+    pub memo: Option<String>,
+    pub created: String,
+    pub expires: String,
+    pub state: String,
+    pub ledger_id: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -98,7 +251,7 @@ pub struct UserEntry {
     pub name: String,
     pub pass: String,
     pub pubkey: String,
-    pub balance: f64,
+    pub balance: Amount,
     pub messages: Vec<String>,
     pub created: String,
     pub last_login: String,
@@ -112,7 +265,26 @@ impl Comm {
         args: Option<Vec<String>>,
         origin: Option<mpsc::Sender<Reply>>,
     ) -> Comm {
-        Comm { kind, args, origin }
+        Comm {
+            kind,
+            args,
+            origin,
+            key: None,
+            internal: false,
+        }
+    }
+
+    // Attaches the client's idempotency key, if any.
+    pub fn with_key(mut self, key: Option<String>) -> Comm {
+        self.key = key;
+        self
+    }
+
+    // Marks a request the server is making itself. Only
+    // server threads call this; json::from_str() never does.
+    pub fn internal(mut self) -> Comm {
+        self.internal = true;
+        self
     }
 
     pub fn kind(&self) -> &Kind {
@@ -127,6 +299,71 @@ impl Comm {
             None => Vec::<String>::new(),
         }
     }
+
+    // Send a reply back to whoever made the request.
+    // If they've hung up, there's nobody left to tell,
+    // so just log it.
+    pub fn reply(&self, reply: Reply) {
+        if let Some(tx) = &self.origin {
+            if let Err(err) = tx.send(reply) {
+                log::warn!("Failed to send reply: {:?}", err);
+            }
+        }
+    }
+
+    pub fn reply_error(&self, resp: err::Resp) {
+        self.reply(Reply::Error(resp.to_string()));
+    }
+}
+
+impl Kind {
+    // Requests that change something. An idempotency key
+    // only means anything on these; the rest can simply
+    // be asked again.
+    pub fn changes_state(&self) -> bool {
+        const CHANGES: [Kind; 29] = [
+            Kind::Register,
+            Kind::Rename,
+            Kind::Send,
+            Kind::Sign,
+            Kind::Contest,
+            Kind::Resolve,
+            Kind::Second,
+            Kind::Archive,
+            Kind::Propose,
+            Kind::Order,
+            Kind::Cancel,
+            Kind::Escrow,
+            Kind::Release,
+            Kind::Refund,
+            Kind::Batch,
+            Kind::Invoice,
+            Kind::Approve,
+            Kind::Decline,
+            Kind::Mint,
+            Kind::Burn,
+            Kind::Grant,
+            Kind::Reverse,
+            Kind::Deactivate,
+            Kind::Freeze,
+            Kind::Unfreeze,
+            Kind::Limit,
+            Kind::Privacy,
+            Kind::Quorum,
+            Kind::Signers,
+        ];
+        CHANGES.contains(self)
+    }
+
+    // Where the password sits in a request's args. Nearly
+    // everything leads with user, password; rename puts
+    // the new name between them.
+    pub fn password_at(&self) -> usize {
+        match self {
+            Kind::Rename => 2,
+            _ => 1,
+        }
+    }
 }
 
 impl DB {
@@ -140,7 +377,7 @@ impl DB {
         db_flags.set(OpenFlags::SQLITE_OPEN_PRIVATE_CACHE, true); // Use private cache even if shared is enabled.
                                                                   // See: https://www.sqlite.org/c3ref/open.html
         let path = Path::new(path);
-        let conn = Connection::open_with_flags(path, db_flags).unwrap_or_else(|error| {
+        let mut conn = Connection::open_with_flags(path, db_flags).unwrap_or_else(|error| {
             err::log_then_panic("Could not open database connection", error);
             panic!();
         });
@@ -158,41 +395,158 @@ impl DB {
 
         pragma.zeroize();
 
-        // This has a dual purpose: First, create the three
-        // tables on first startup. If subsequent startups
-        // fail to execute these statements, the key is
-        // incorrect.
+        // Databases from before the schema was versioned
+        // have tables but no user_version, so look for one
+        // before startup_check_tables() creates it. This
+        // is also the first read, so it's where an
+        // incorrect key shows up.
+        let existing = has_table(&conn, "users");
+
+        // Create the three tables on first startup.
         startup_check_tables(&conn);
+        migrate(&mut conn, existing);
 
         DB { conn, pipe }
     }
 
     // Continually read from the channel to
     // process the incoming Comms.
-    pub fn worker_thread(&self) -> Comm {
+    pub fn worker_thread(&mut self) -> Comm {
         while let Ok(comm) = self.pipe.recv() {
             log::info!("Ledger Worker :: Received {:?}", comm);
-            match comm.kind {
-                Some(Kind::Register) => user::register(comm.clone(), &self.conn),
-                Some(Kind::Whoami) => query::whoami(comm.clone(), &self.conn),
-                Some(Kind::Rename) => user::rename(comm.clone(), &self.conn),
-                Some(Kind::Send) => user::send(comm.clone(), &self.conn),
-                Some(Kind::Sign) => {}
-                Some(Kind::Balance) => user::balance(comm.clone(), &self.conn),
-                Some(Kind::Verify) => {}
-                Some(Kind::Contest) => {}
-                Some(Kind::Audit) => {}
-                Some(Kind::Resolve) => {}
-                Some(Kind::Second) => {}
-                Some(Kind::Query) => {}
-                Some(Kind::Disconnect) => return comm,
-                _ => continue,
+            if let Some(Kind::Disconnect) = comm.kind {
+                return comm;
+            }
+            match comm.key.clone() {
+                Some(key) if comm.kind().changes_state() => self.once(comm, &key),
+                _ => self.dispatch(comm),
             }
         }
-        Comm {
-            kind: None,
-            args: None,
-            origin: None,
+        Comm::new(None, None, None)
+    }
+
+    // Runs a keyed request, unless the same request came in
+    // under the same key before. Then the original reply
+    // goes back out instead and nothing runs a second time.
+    fn once(&mut self, mut comm: Comm, key: &str) {
+        let kind = comm.kind().clone();
+        let mut args = comm.args();
+        // Keying expects the password second, where it's
+        // left out of the fingerprint, so move it there
+        // from wherever this kind carries it.
+        let at = kind.password_at();
+        if at < args.len() {
+            let pass = args.remove(at);
+            args.insert(1, pass);
+        }
+        match idempotency::recall(&self.conn, &kind, &args, key) {
+            Ok(Some(reply)) => {
+                if !self.reauth(&comm, &kind, &mut args) {
+                    return;
+                }
+                log::info!("Replaying reply to {:?} under key {}", kind, key);
+                comm.reply(reply);
+                return;
+            }
+            Ok(None) => {}
+            Err(resp) => {
+                comm.reply_error(resp);
+                return;
+            }
+        }
+
+        if let Err(resp) = idempotency::reserve(&self.conn, &kind, &args, key) {
+            log::error!("Could not reserve key {}: {}", key, resp.details());
+            comm.reply_error(resp);
+            return;
+        }
+
+        // Handlers reply before they return, so the reply
+        // is already waiting once dispatch() is done.
+        let origin = comm.origin.take();
+        let (tx, rx) = mpsc::channel::<Reply>();
+        comm.origin = Some(tx);
+        self.dispatch(comm);
+
+        let reply = match rx.try_recv() {
+            Ok(val) => val,
+            Err(_) => {
+                if let Err(resp) = idempotency::forget(&self.conn, &kind, &args, key) {
+                    log::error!("Could not release key {}: {}", key, resp.details());
+                }
+                return;
+            }
+        };
+        if let Err(resp) = idempotency::remember(&self.conn, &kind, &args, key, &reply) {
+            log::error!("Could not remember key {}: {}", key, resp.details());
+        }
+        let origin = Comm::new(None, None, origin);
+        origin.reply(reply);
+    }
+
+    // Whoever retries a request still has to know the
+    // password. Registration has nobody to check it against
+    // yet, and only renames that went through are
+    // remembered, so a renamed account answers to its new
+    // name.
+    fn reauth(&self, comm: &Comm, kind: &Kind, args: &mut [String]) -> bool {
+        match kind {
+            Kind::Register => true,
+            Kind::Rename if args.len() > 2 => {
+                args.swap(0, 2);
+                user::auth_args(comm, args, &self.conn)
+            }
+            _ => args.len() > 1 && user::auth_args(comm, args, &self.conn),
+        }
+    }
+
+    fn dispatch(&mut self, comm: Comm) {
+        match comm.kind {
+            Some(Kind::Register) => user::register(comm.clone(), &mut self.conn),
+            Some(Kind::Whoami) => query::whoami(comm.clone(), &self.conn),
+            Some(Kind::Rename) => user::rename(comm.clone(), &mut self.conn),
+            Some(Kind::Send) => user::send(comm.clone(), &mut self.conn),
+            Some(Kind::Sign) => pending::sign(comm.clone(), &mut self.conn),
+            Some(Kind::Balance) => user::balance(comm.clone(), &self.conn),
+            Some(Kind::Verify) => ledger::verify(comm.clone(), &self.conn),
+            Some(Kind::Contest) => dispute::contest(comm.clone(), &self.conn),
+            Some(Kind::Audit) => audit::audit(comm.clone(), &self.conn),
+            Some(Kind::Resolve) => dispute::resolve(comm.clone(), &mut self.conn),
+            Some(Kind::Second) => dispute::second(comm.clone(), &mut self.conn),
+            Some(Kind::Receipts) => receipt::list(comm.clone(), &self.conn),
+            Some(Kind::Archive) => archive::close(comm.clone(), &mut self.conn),
+            Some(Kind::Proof) => archive::prove(comm.clone(), &self.conn),
+            Some(Kind::Propose) => pending::propose(comm.clone(), &mut self.conn),
+            Some(Kind::History) => query::history(comm.clone(), &self.conn),
+            Some(Kind::Messages) => message::list(comm.clone(), &self.conn),
+            Some(Kind::Order) => order::create(comm.clone(), &self.conn),
+            Some(Kind::Orders) => order::list(comm.clone(), &self.conn),
+            Some(Kind::Cancel) => order::cancel(comm.clone(), &self.conn),
+            Some(Kind::Escrow) => escrow::hold(comm.clone(), &mut self.conn),
+            Some(Kind::Release) => escrow::release(comm.clone(), &mut self.conn),
+            Some(Kind::Refund) => escrow::refund(comm.clone(), &mut self.conn),
+            Some(Kind::Batch) => batch::send(comm.clone(), &mut self.conn),
+            Some(Kind::Invoice) => invoice::create(comm.clone(), &self.conn),
+            Some(Kind::Invoices) => invoice::list(comm.clone(), &self.conn),
+            Some(Kind::Approve) => invoice::approve(comm.clone(), &mut self.conn),
+            Some(Kind::Decline) => invoice::decline(comm.clone(), &mut self.conn),
+            Some(Kind::Mint) => treasury::mint(comm.clone(), &mut self.conn),
+            Some(Kind::Burn) => treasury::burn(comm.clone(), &mut self.conn),
+            Some(Kind::Grant) => treasury::set_grant(comm.clone(), &self.conn),
+            Some(Kind::Reverse) => reversal::reverse(comm.clone(), &mut self.conn),
+            Some(Kind::Deactivate) => user::deactivate(comm.clone(), &mut self.conn),
+            Some(Kind::Freeze) => freeze::freeze(comm.clone(), &mut self.conn),
+            Some(Kind::Unfreeze) => freeze::unfreeze(comm.clone(), &mut self.conn),
+            Some(Kind::Limit) => limit::set(comm.clone(), &self.conn),
+            Some(Kind::Limits) => limit::show(comm.clone(), &self.conn),
+            Some(Kind::AsOf) => user::balance_as_of(comm.clone(), &self.conn),
+            Some(Kind::Stats) => stats::stats(comm.clone(), &self.conn),
+            Some(Kind::Report) => stats::report(comm.clone(), &self.conn),
+            Some(Kind::Privacy) => stats::privacy(comm.clone(), &self.conn),
+            Some(Kind::Quorum) => dispute::set_quorum(comm.clone(), &self.conn),
+            Some(Kind::Signers) => pending::set_signers(comm.clone(), &mut self.conn),
+            Some(Kind::Query) => query::internal(comm.clone(), &mut self.conn),
+            _ => {}
         }
     }
 }
@@ -206,10 +560,15 @@ fn startup_check_tables(conn: &rusqlite::Connection) {
                 timestamp       TEXT NOT NULL,
                 source          TEXT NOT NULL,
                 destination     TEXT NOT NULL,
-                amount          REAL NOT NULL,
+                amount          INTEGER NOT NULL,
                 ledger_hash     TEXT NOT NULL,
                 receipt_id      INTEGER NOT NULL,
-                receipt_hash    TEXT NOT NULL
+                receipt_hash    TEXT NOT NULL,
+                batch_id        INTEGER,
+                reverses        INTEGER UNIQUE,
+                source_id       INTEGER,
+                destination_id  INTEGER,
+                hash_version    INTEGER NOT NULL DEFAULT 1
             )",
         NO_PARAMS,
     )
@@ -223,24 +582,669 @@ fn startup_check_tables(conn: &rusqlite::Connection) {
                 state           TEXT NOT NULL,
                 merkle_hash     TEXT NOT NULL,
                 hash            TEXT NOT NULL,
-                filename        TEXT NOT NULL
+                filename        TEXT NOT NULL,
+                first_id        INTEGER NOT NULL,
+                last_id         INTEGER NOT NULL
             )",
         NO_PARAMS,
     )
     .expect("Could not create archive table");
 
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS archive_balances (
+                archive_id      INTEGER NOT NULL,
+                name            TEXT NOT NULL,
+                net             INTEGER NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create archive_balances table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS disputes (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                ledger_id       INTEGER NOT NULL,
+                contestant      TEXT NOT NULL,
+                reason          TEXT NOT NULL,
+                state           TEXT NOT NULL,
+                opened          TEXT NOT NULL,
+                resolved        TEXT,
+                resolver        TEXT,
+                reversal_id     INTEGER
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create disputes table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dispute_seconds (
+                dispute_id      INTEGER NOT NULL,
+                name            TEXT NOT NULL,
+                timestamp       TEXT NOT NULL,
+                UNIQUE (dispute_id, name)
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create dispute_seconds table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pending (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                source          TEXT NOT NULL,
+                destination     TEXT NOT NULL,
+                amount          INTEGER NOT NULL,
+                created         TEXT NOT NULL,
+                expires         TEXT NOT NULL,
+                state           TEXT NOT NULL,
+                ledger_id       INTEGER
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create pending table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pending_signers (
+                pending_id      INTEGER NOT NULL,
+                name            TEXT NOT NULL,
+                signature       TEXT,
+                signed          TEXT,
+                UNIQUE (pending_id, name)
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create pending_signers table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS signers (
+                user_id     INTEGER NOT NULL,
+                signer_id   INTEGER NOT NULL,
+                PRIMARY KEY (user_id, signer_id)
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create signers table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+                ledger_id   INTEGER PRIMARY KEY,
+                message     TEXT NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create messages table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS orders (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner       TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                amount      INTEGER NOT NULL,
+                interval    INTEGER NOT NULL,
+                next_due    TEXT NOT NULL,
+                ends        TEXT,
+                message     TEXT,
+                state       TEXT NOT NULL,
+                created     TEXT NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create orders table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS escrow (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                source      TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                arbiter     TEXT,
+                amount      INTEGER NOT NULL,
+                created     TEXT NOT NULL,
+                expires     TEXT NOT NULL,
+                state       TEXT NOT NULL,
+                hold_id     INTEGER NOT NULL,
+                settled_id  INTEGER
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create escrow table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS batches (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                source          TEXT NOT NULL,
+                created         TEXT NOT NULL,
+                total           INTEGER NOT NULL,
+                count           INTEGER NOT NULL,
+                receipt_id      INTEGER NOT NULL,
+                receipt_hash    TEXT NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create batches table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS invoices (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                payee       TEXT NOT NULL,
+                payer       TEXT NOT NULL,
+                amount      INTEGER NOT NULL,
+                memo        TEXT,
+                created     TEXT NOT NULL,
+                expires     TEXT NOT NULL,
+                state       TEXT NOT NULL,
+                ledger_id   INTEGER
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create invoices table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS idempotency (
+                key         TEXT NOT NULL,
+                kind        TEXT NOT NULL,
+                name        TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                reply_type  TEXT NOT NULL,
+                reply       TEXT NOT NULL,
+                created     TEXT NOT NULL,
+                UNIQUE (key, kind, name)
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create idempotency table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+                name    TEXT PRIMARY KEY,
+                value   INTEGER NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create settings table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS limits (
+                user_id     INTEGER NOT NULL,
+                kind        TEXT NOT NULL,
+                value       INTEGER NOT NULL,
+                PRIMARY KEY (user_id, kind)
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create limits table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS aliases (
+                name        TEXT PRIMARY KEY,
+                user_id     INTEGER NOT NULL,
+                retired     TEXT NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create aliases table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS freeze_log (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id     INTEGER NOT NULL,
+                action      TEXT NOT NULL,
+                reason      TEXT NOT NULL,
+                admin       TEXT NOT NULL,
+                timestamp   TEXT NOT NULL
+            )",
+        NO_PARAMS,
+    )
+    .expect("Could not create freeze log table");
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (
                 id          INTEGER PRIMARY KEY AUTOINCREMENT,
                 name        TEXT NOT NULL,
                 pass        TEXT NOT NULL,
                 pubkey      TEXT NOT NULL,
-                balance     REAL NOT NULL,
+                balance     INTEGER NOT NULL,
                 messages    TEXT,
                 created     TEXT NOT NULL,
-                last_login  TEXT NOT NULL
+                last_login  TEXT NOT NULL,
+                admin       INTEGER NOT NULL DEFAULT 0,
+                active      INTEGER NOT NULL DEFAULT 1,
+                frozen      INTEGER NOT NULL DEFAULT 0,
+                frozen_incoming INTEGER NOT NULL DEFAULT 0,
+                private     INTEGER NOT NULL DEFAULT 0
             )",
         NO_PARAMS,
     )
     .expect("Could not create users table");
 }
+
+fn has_table(conn: &Connection, name: &str) -> bool {
+    let count = conn
+        .query_row_named(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, i64>(0),
+        )
+        .unwrap_or_else(|error| {
+            err::log_then_panic("Database authentication failure", error);
+            panic!();
+        });
+    count > 0
+}
+
+// Brings an existing database up to SCHEMA_VERSION. Each
+// step runs in its own transaction along with the bump
+// to user_version, so a step that fails is rolled back
+// and tried again on the next startup.
+fn migrate(conn: &mut Connection, existing: bool) {
+    if !existing {
+        set_schema_version(conn, SCHEMA_VERSION);
+        return;
+    }
+
+    let version = conn
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| {
+            row.get::<usize, i64>(0)
+        })
+        .expect("Could not read schema version");
+
+    if version < 1 {
+        log::info!("Migrating database to schema version 1: fixed-point amounts");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_fixed_point(&tx);
+        set_schema_version(&tx, 1);
+        tx.commit()
+            .expect("Could not commit migration to version 1");
+    }
+
+    // Every step from here on may write or read ledger
+    // rows the way the code does now.
+    add_ledger_columns(conn);
+
+    if version < 2 {
+        log::info!("Migrating database to schema version 2: ledger hash chain");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_hash_chain(&tx);
+        set_schema_version(&tx, 2);
+        tx.commit()
+            .expect("Could not commit migration to version 2");
+    }
+
+    if version < 3 {
+        log::info!("Migrating database to schema version 3: transfer receipts");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_receipts(&tx);
+        set_schema_version(&tx, 3);
+        tx.commit()
+            .expect("Could not commit migration to version 3");
+    }
+
+    if version < 4 {
+        log::info!("Migrating database to schema version 4: archives and admins");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_archive(&tx);
+        set_schema_version(&tx, 4);
+        tx.commit()
+            .expect("Could not commit migration to version 4");
+    }
+
+    // Rows from before batches existed don't belong to one,
+    // so batch_id, added above, stays NULL.
+    if version < 5 {
+        log::info!("Migrating database to schema version 5: batch sends");
+        set_schema_version(conn, 5);
+    }
+
+    if version < 6 {
+        log::info!("Migrating database to schema version 6: treasury");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_treasury(&tx);
+        set_schema_version(&tx, 6);
+        tx.commit()
+            .expect("Could not commit migration to version 6");
+    }
+
+    if version < 7 {
+        log::info!("Migrating database to schema version 7: reversal links");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_reversals(&tx);
+        set_schema_version(&tx, 7);
+        tx.commit()
+            .expect("Could not commit migration to version 7");
+    }
+
+    if version < 8 {
+        log::info!("Migrating database to schema version 8: user ids in the ledger");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_user_ids(&tx);
+        set_schema_version(&tx, 8);
+        tx.commit()
+            .expect("Could not commit migration to version 8");
+    }
+
+    if version < 9 {
+        log::info!("Migrating database to schema version 9: account deactivation");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_deactivation(&tx);
+        set_schema_version(&tx, 9);
+        tx.commit()
+            .expect("Could not commit migration to version 9");
+    }
+
+    if version < 10 {
+        log::info!("Migrating database to schema version 10: account freezes");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_freezes(&tx);
+        set_schema_version(&tx, 10);
+        tx.commit()
+            .expect("Could not commit migration to version 10");
+    }
+
+    if version < 11 {
+        log::info!("Migrating database to schema version 11: rich list privacy");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_privacy(&tx);
+        set_schema_version(&tx, 11);
+        tx.commit()
+            .expect("Could not commit migration to version 11");
+    }
+
+    // Rows so far were hashed without their user ids or
+    // links, and hash_version, added above, says so.
+    if version < 12 {
+        log::info!("Migrating database to schema version 12: ids and links in the hash chain");
+        set_schema_version(conn, 12);
+    }
+
+    if version < 13 {
+        log::info!("Migrating database to schema version 13: pending transfer expiry");
+        let tx = conn.transaction().expect("Could not begin migration");
+        migrate_pending_expiry(&tx);
+        set_schema_version(&tx, 13);
+        tx.commit()
+            .expect("Could not commit migration to version 13");
+    }
+}
+
+// Columns added to the ledger since version 1 rebuilt it.
+// Later steps write rows with ledger::append() and read
+// them with query::LEDGER_COLUMNS, which expect every one
+// of these, so migrate() adds them all before the first
+// of those steps runs. The step that introduced each
+// column only fills it in.
+const LEDGER_ADDED: [(&str, &str); 5] = [
+    ("batch_id", "INTEGER"),
+    ("reverses", "INTEGER"),
+    ("source_id", "INTEGER"),
+    ("destination_id", "INTEGER"),
+    ("hash_version", "INTEGER NOT NULL DEFAULT 1"),
+];
+
+fn add_ledger_columns(conn: &Connection) {
+    for (column, decl) in LEDGER_ADDED.iter() {
+        if has_column(conn, "ledger", column) {
+            continue;
+        }
+        conn.execute_batch(&format!(
+            "ALTER TABLE ledger ADD COLUMN {} {}",
+            column, decl
+        ))
+        .expect("Could not add ledger columns");
+    }
+}
+
+fn set_schema_version(conn: &Connection, version: i64) {
+    conn.execute_batch(&format!("PRAGMA user_version = {}", version))
+        .expect("Could not set schema version");
+}
+
+// Version 1: amounts used to be REAL columns holding tcoin.
+// Rebuild the ledger and users tables with INTEGER columns
+// holding milli-tcoin instead.
+fn migrate_fixed_point(conn: &Connection) {
+    let stmt = format!(
+        "ALTER TABLE ledger RENAME TO ledger_real;
+        ALTER TABLE users RENAME TO users_real;
+
+        CREATE TABLE ledger (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            type            TEXT NOT NULL,
+            timestamp       TEXT NOT NULL,
+            source          TEXT NOT NULL,
+            destination     TEXT NOT NULL,
+            amount          INTEGER NOT NULL,
+            ledger_hash     TEXT NOT NULL,
+            receipt_id      INTEGER NOT NULL,
+            receipt_hash    TEXT NOT NULL
+        );
+        CREATE TABLE users (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            name        TEXT NOT NULL,
+            pass        TEXT NOT NULL,
+            pubkey      TEXT NOT NULL,
+            balance     INTEGER NOT NULL,
+            messages    TEXT,
+            created     TEXT NOT NULL,
+            last_login  TEXT NOT NULL
+        );
+
+        INSERT INTO ledger
+            SELECT id, type, timestamp, source, destination,
+                CAST(ROUND(amount * {scale}) AS INTEGER),
+                ledger_hash, receipt_id, receipt_hash
+            FROM ledger_real;
+        INSERT INTO users
+            SELECT id, name, pass, pubkey,
+                CAST(ROUND(balance * {scale}) AS INTEGER),
+                messages, created, last_login
+            FROM users_real;
+
+        DROP TABLE ledger_real;
+        DROP TABLE users_real;",
+        scale = amount::SCALE
+    );
+
+    conn.execute_batch(&stmt)
+        .expect("Could not convert amounts to fixed-point");
+}
+
+// Version 2: rows written before the hash chain existed
+// have an empty ledger_hash. Chain them up in id order.
+fn migrate_hash_chain(conn: &Connection) {
+    let entries = {
+        let stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM ledger ORDER BY id",
+                query::LEDGER_COLUMNS
+            ))
+            .expect("Could not read ledger for hash chain migration");
+        query::to_ledger_entry(stmt).expect("Could not read ledger for hash chain migration")
+    };
+
+    let mut prev = ledger::GENESIS_HASH.to_string();
+    for entry in entries {
+        let hash = ledger::chain_hash(&prev, &entry);
+        conn.execute_named(
+            "UPDATE ledger SET ledger_hash = :hash WHERE id = :id",
+            &[(":hash", &hash), (":id", &entry.id)],
+        )
+        .expect("Could not write ledger hash during migration");
+        prev = hash;
+    }
+}
+
+// Version 3: rows written before receipts existed have
+// a receipt_id of zero. Issue them one now.
+fn migrate_receipts(conn: &Connection) {
+    let entries = {
+        let stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM ledger WHERE receipt_id = 0 ORDER BY id",
+                query::LEDGER_COLUMNS
+            ))
+            .expect("Could not read ledger for receipt migration");
+        query::to_ledger_entry(stmt).expect("Could not read ledger for receipt migration")
+    };
+
+    for entry in entries {
+        if let Err(resp) = receipt::issue(conn, i64::from(entry.id), &entry.ledger_hash) {
+            err::log_then_panic("Could not issue receipt during migration", resp);
+        }
+    }
+}
+
+// Version 4: archive rows record which ledger ids they
+// hold, and users can be admins. Nothing has been
+// archived yet and nobody is an admin, so the defaults
+// are right for every existing row. The archive table
+// may have only just been created by
+// startup_check_tables(), columns and all.
+fn migrate_archive(conn: &Connection) {
+    let columns = [
+        ("archive", "first_id"),
+        ("archive", "last_id"),
+        ("users", "admin"),
+    ];
+    for (table, column) in columns.iter() {
+        if has_column(conn, table, column) {
+            continue;
+        }
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} INTEGER NOT NULL DEFAULT 0",
+            table, column
+        ))
+        .expect("Could not add archive and admin columns");
+    }
+}
+
+// Version 6: every account used to be handed its grant
+// without a ledger row to show for it. Mint what was
+// handed out into the treasury and pay each user's grant
+// from there, so the ledger accounts for every tcoin.
+// Balances already include the grants and aren't touched.
+//
+// The new rows are stamped with the time of the migration,
+// not when each grant was really handed out. Rows have to
+// keep their ids in time order, which balance lookups as
+// of a past time rely on, and these come after everything
+// already on the ledger. So a lookup as of any time before
+// the migration leaves the grants out.
+fn migrate_treasury(conn: &Connection) {
+    let names = {
+        let mut stmt = conn
+            .prepare("SELECT name FROM users ORDER BY id")
+            .expect("Could not read users for treasury migration");
+        stmt.query_map(NO_PARAMS, |row| row.get::<usize, String>(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+            .expect("Could not read users for treasury migration")
+    };
+    if names.is_empty() {
+        return;
+    }
+
+    let total = treasury::DEFAULT_GRANT
+        .milli()
+        .checked_mul(names.len() as i64)
+        .map(Amount::from_milli)
+        .expect("Grants to existing users overflow");
+    if let Err(resp) = treasury::issue(conn, total) {
+        err::log_then_panic("Could not mint grants during migration", resp);
+    }
+    for name in &names {
+        if let Err(resp) = ledger::append(
+            conn,
+            "grant",
+            treasury::ACCOUNT,
+            name,
+            treasury::DEFAULT_GRANT,
+        ) {
+            err::log_then_panic("Could not record grant during migration", resp);
+        }
+    }
+}
+
+// Version 7: reversal rows point at the row they reverse,
+// and no row can be reversed twice. Until now the only
+// reversals came from upheld disputes, which recorded
+// the link themselves, so it's copied from there.
+fn migrate_reversals(conn: &Connection) {
+    conn.execute_batch(
+        "UPDATE ledger SET reverses =
+            (SELECT ledger_id FROM disputes WHERE reversal_id = ledger.id)
+            WHERE type = 'reversal';
+        CREATE UNIQUE INDEX IF NOT EXISTS ledger_reverses ON ledger (reverses);",
+    )
+    .expect("Could not link reversals");
+}
+
+// Version 8: ledger rows refer to users by id as well as
+// by name, so a rename doesn't cut anyone off from their
+// history. Rows are matched on the name they were written
+// under. Renames before now didn't leave an alias behind,
+// so rows under a name nobody has any more stay NULL and
+// the audit goes on reporting them.
+fn migrate_user_ids(conn: &Connection) {
+    conn.execute_batch(
+        "UPDATE ledger SET
+            source_id = (SELECT id FROM users WHERE name = ledger.source),
+            destination_id = (SELECT id FROM users WHERE name = ledger.destination)",
+    )
+    .expect("Could not fill in ledger user ids");
+}
+
+// Version 9: accounts can be deactivated. Every account
+// so far is still in use.
+fn migrate_deactivation(conn: &Connection) {
+    if has_column(conn, "users", "active") {
+        return;
+    }
+    conn.execute_batch("ALTER TABLE users ADD COLUMN active INTEGER NOT NULL DEFAULT 1")
+        .expect("Could not add active column");
+}
+
+// Version 10: admins can freeze accounts. Nobody is
+// frozen yet.
+fn migrate_freezes(conn: &Connection) {
+    for column in &["frozen", "frozen_incoming"] {
+        if has_column(conn, "users", column) {
+            continue;
+        }
+        conn.execute_batch(&format!(
+            "ALTER TABLE users ADD COLUMN {} INTEGER NOT NULL DEFAULT 0",
+            column
+        ))
+        .expect("Could not add freeze columns");
+    }
+}
+
+// Version 11: users can keep themselves off the public
+// rich list. Nobody has asked to yet.
+fn migrate_privacy(conn: &Connection) {
+    if has_column(conn, "users", "private") {
+        return;
+    }
+    conn.execute_batch("ALTER TABLE users ADD COLUMN private INTEGER NOT NULL DEFAULT 0")
+        .expect("Could not add privacy column");
+}
+
+// Version 13: pending transfers expire. Ones already
+// waiting get a week from when they were proposed.
+fn migrate_pending_expiry(conn: &Connection) {
+    if has_column(conn, "pending", "expires") {
+        return;
+    }
+    conn.execute_batch(
+        "ALTER TABLE pending ADD COLUMN expires TEXT NOT NULL DEFAULT '';
+        UPDATE pending SET expires = strftime('%Y-%m-%dT%H:%M:%SZ', created, '+7 days')",
+    )
+    .expect("Could not add pending expiry");
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+    let count = conn
+        .query_row_named(
+            "SELECT COUNT(*) FROM pragma_table_info(:table) WHERE name = :column",
+            &[(":table", &table), (":column", &column)],
+            |row| row.get::<usize, i64>(0),
+        )
+        .expect("Could not read table layout");
+    count > 0
+}
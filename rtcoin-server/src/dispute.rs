@@ -0,0 +1,558 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use chrono::prelude::*;
+use rusqlite::OptionalExtension;
+
+use crate::{db, err, ledger, query, reversal, user};
+
+// Number of seconds, not counting the contestant's own,
+// that upholds a dispute without waiting on an admin,
+// unless an admin sets another.
+pub const DEFAULT_QUORUM: i64 = 3;
+
+// How many days old an account has to be before it can
+// second a dispute, unless an admin sets another. Fresh
+// accounts are free to make, so without this anyone could
+// register a quorum of their own.
+pub const DEFAULT_SECOND_AGE: i64 = 30;
+
+// Contests a ledger entry. Accepts the args
+//     vec![user, password, ledger_id, reason...]
+// Only the sender or recipient of a transfer can
+// contest it, and each entry can only have one
+// dispute that hasn't been dismissed.
+pub fn contest(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, ledger_id, reason",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let ledger_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid ledger id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let reason = args[3..].join(" ");
+
+    match open(conn, &args[0], ledger_id, &reason) {
+        Ok(id) => {
+            log::info!(
+                "Dispute {}: {} contests ledger entry {}",
+                id,
+                args[0],
+                ledger_id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Opened dispute {} over ledger entry {}",
+                id, ledger_id
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Contest of ledger entry {} by {} failed: {}",
+                ledger_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Records a new dispute and returns its id.
+pub fn open(
+    conn: &rusqlite::Connection,
+    name: &str,
+    ledger_id: u32,
+    reason: &str,
+) -> Result<u32, err::Resp> {
+    let entry = live_entry(conn, ledger_id)?;
+    if entry.transaction_type == "reversal" {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Reversals can't be contested",
+        ));
+    }
+    if let Some(id) = reversal::reversed_by(conn, ledger_id)? {
+        let details = format!(
+            "Ledger entry {} was already reversed in ledger entry {}",
+            ledger_id, id
+        );
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    let (source, destination) = ledger::parties(conn, ledger_id)?;
+    let contestant = user::id_of(conn, name)?;
+    if contestant.is_none() || (contestant != source && contestant != destination) {
+        return Err(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Only the sender or recipient can contest a transfer",
+        ));
+    }
+
+    let disputed = conn.query_row_named(
+        "SELECT COUNT(*) FROM disputes WHERE ledger_id = :ledger_id AND state != 'dismissed'",
+        &[(":ledger_id", &ledger_id)],
+        |row| row.get::<usize, i64>(0),
+    )?;
+    if disputed > 0 {
+        let details = format!("Ledger entry {} is already disputed", ledger_id);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    conn.execute_named(
+        "INSERT INTO disputes (ledger_id, contestant, reason, state, opened)
+            VALUES (:ledger_id, :contestant, :reason, 'open', :opened)",
+        &[
+            (":ledger_id", &ledger_id),
+            (":contestant", &name),
+            (":reason", &reason),
+            (":opened", &ledger::now()),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid() as u32)
+}
+
+// Seconds someone else's dispute. Accepts the args
+//     vec![user, password, dispute_id]
+// The second that brings a dispute to quorum also
+// upholds it. Neither side of the contested transfer can
+// second it, and neither can accounts younger than the
+// minimum age.
+pub fn second(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, dispute_id",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let dispute_id = match parse_id(&comm, &args[2]) {
+        Some(val) => val,
+        None => return,
+    };
+
+    match second_tx(conn, &args[0], dispute_id) {
+        Ok((count, None)) => {
+            let needed = quorum(conn).unwrap_or(DEFAULT_QUORUM);
+            comm.reply(db::Reply::Info(format!(
+                "Seconded dispute {} ({} of {})",
+                dispute_id, count, needed
+            )));
+        }
+        Ok((_, Some(reversal))) => {
+            log::info!(
+                "Dispute {} upheld by quorum, reversed in ledger entry {}",
+                dispute_id,
+                reversal.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Seconded dispute {}. Quorum reached, reversed in ledger entry {}",
+                dispute_id, reversal.id
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Second of dispute {} by {} failed: {}",
+                dispute_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn second_tx(
+    conn: &mut rusqlite::Connection,
+    name: &str,
+    dispute_id: u32,
+) -> Result<(i64, Option<db::LedgerEntry>), err::Resp> {
+    let tx = conn.transaction()?;
+    let outcome = add_second(&tx, name, dispute_id)?;
+    tx.commit()?;
+    Ok(outcome)
+}
+
+// Returns how many seconds the dispute has now, and
+// the reversal if that was enough to uphold it. Expects
+// to be handed a transaction.
+pub fn add_second(
+    conn: &rusqlite::Connection,
+    name: &str,
+    dispute_id: u32,
+) -> Result<(i64, Option<db::LedgerEntry>), err::Resp> {
+    let dispute = open_dispute(conn, dispute_id)?;
+    if dispute.contestant == name {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Can't second your own dispute",
+        ));
+    }
+    let (source, destination) = ledger::parties(conn, dispute.ledger_id)?;
+    let seconder = user::id_of(conn, name)?;
+    if seconder.is_some() && (seconder == source || seconder == destination) {
+        return Err(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Neither side of a transfer can second a dispute over it",
+        ));
+    }
+    let min_age = second_age(conn)?;
+    if !old_enough(conn, name, min_age)? {
+        let details = format!(
+            "Accounts have to be {} days old to second a dispute",
+            min_age
+        );
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+
+    let seconded = conn.query_row_named(
+        "SELECT COUNT(*) FROM dispute_seconds WHERE dispute_id = :dispute_id AND name = :name",
+        &[(":dispute_id", &dispute_id), (":name", &name)],
+        |row| row.get::<usize, i64>(0),
+    )?;
+    if seconded > 0 {
+        let details = format!("{} has already seconded dispute {}", name, dispute_id);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    conn.execute_named(
+        "INSERT INTO dispute_seconds (dispute_id, name, timestamp)
+            VALUES (:dispute_id, :name, :timestamp)",
+        &[
+            (":dispute_id", &dispute_id),
+            (":name", &name),
+            (":timestamp", &ledger::now()),
+        ],
+    )?;
+    let count = conn.query_row_named(
+        "SELECT COUNT(*) FROM dispute_seconds WHERE dispute_id = :dispute_id",
+        &[(":dispute_id", &dispute_id)],
+        |row| row.get::<usize, i64>(0),
+    )?;
+
+    if count < quorum(conn)? {
+        return Ok((count, None));
+    }
+    let reversal = uphold(conn, &dispute, "quorum")?;
+    Ok((count, Some(reversal)))
+}
+
+// Whether an account was opened at least the given number
+// of days ago. An account whose creation time can't be
+// read doesn't count as old enough.
+fn old_enough(conn: &rusqlite::Connection, name: &str, days: i64) -> Result<bool, err::Resp> {
+    let created = conn
+        .query_row_named(
+            "SELECT created FROM users WHERE name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, String>(0),
+        )
+        .optional()?;
+    let created = match created.and_then(|val| DateTime::parse_from_rfc2822(&val).ok()) {
+        Some(val) => val,
+        None => return Ok(false),
+    };
+    Ok(Utc::now().signed_duration_since(created) >= chrono::Duration::days(days))
+}
+
+// How many seconds uphold a dispute.
+pub fn quorum(conn: &rusqlite::Connection) -> Result<i64, err::Resp> {
+    setting(conn, "dispute_quorum", DEFAULT_QUORUM)
+}
+
+// How many days old a seconder's account has to be.
+pub fn second_age(conn: &rusqlite::Connection) -> Result<i64, err::Resp> {
+    setting(conn, "dispute_second_age", DEFAULT_SECOND_AGE)
+}
+
+fn setting(conn: &rusqlite::Connection, name: &str, default: i64) -> Result<i64, err::Resp> {
+    let value = conn
+        .query_row_named(
+            "SELECT value FROM settings WHERE name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, i64>(0),
+        )
+        .optional()?;
+    Ok(value.unwrap_or(default))
+}
+
+// Sets how disputes reach quorum. Accepts the args
+//     vec![admin, password, seconds, days]
+// where seconds is how many it takes to uphold a dispute
+// and days is how old a seconder's account has to be.
+// Admin only.
+pub fn set_quorum(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, seconds, days",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let (count, days) = match (args[2].parse::<i64>(), args[3].parse::<i64>()) {
+        (Ok(count), Ok(days)) if count > 0 && days >= 0 => (count, days),
+        _ => {
+            comm.reply_error(err::Resp::new(
+                3,
+                "Invalid Request",
+                "Seconds must be at least 1 and days can't be negative",
+            ));
+            return;
+        }
+    };
+
+    let set = conn.execute_named(
+        "INSERT OR REPLACE INTO settings (name, value)
+            VALUES ('dispute_quorum', :count), ('dispute_second_age', :days)",
+        &[(":count", &count), (":days", &days)],
+    );
+    match set {
+        Ok(_) => {
+            log::info!(
+                "Dispute quorum set to {} seconds from accounts {} days old by {}",
+                count,
+                days,
+                args[0]
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Disputes are upheld by {} seconds from accounts at least {} days old",
+                count, days
+            )));
+        }
+        Err(error) => {
+            let resp: err::Resp = error.into();
+            log::error!("Setting the dispute quorum failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Settles a dispute. Accepts the args
+//     vec![admin, password, dispute_id, uphold|dismiss]
+// Admin only.
+pub fn resolve(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, dispute_id, uphold|dismiss",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let dispute_id = match parse_id(&comm, &args[2]) {
+        Some(val) => val,
+        None => return,
+    };
+    let upheld = match &args[3][..] {
+        "uphold" => true,
+        "dismiss" => false,
+        other => {
+            let details = format!("Expected uphold or dismiss, got {}", other);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match settle_tx(conn, &args[0], dispute_id, upheld) {
+        Ok(Some(reversal)) => {
+            log::info!(
+                "Dispute {} upheld by {}, reversed in ledger entry {}",
+                dispute_id,
+                args[0],
+                reversal.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Upheld dispute {}, reversed in ledger entry {}",
+                dispute_id, reversal.id
+            )));
+        }
+        Ok(None) => {
+            log::info!("Dispute {} dismissed by {}", dispute_id, args[0]);
+            comm.reply(db::Reply::Info(format!("Dismissed dispute {}", dispute_id)));
+        }
+        Err(resp) => {
+            log::error!(
+                "Resolution of dispute {} by {} failed: {}",
+                dispute_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn settle_tx(
+    conn: &mut rusqlite::Connection,
+    resolver: &str,
+    dispute_id: u32,
+    upheld: bool,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let tx = conn.transaction()?;
+    let outcome = settle(&tx, resolver, dispute_id, upheld)?;
+    tx.commit()?;
+    Ok(outcome)
+}
+
+// Upholds or dismisses an open dispute on behalf of
+// the given admin. Returns the reversal if upheld.
+// Expects to be handed a transaction.
+pub fn settle(
+    conn: &rusqlite::Connection,
+    resolver: &str,
+    dispute_id: u32,
+    upheld: bool,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let dispute = open_dispute(conn, dispute_id)?;
+    if upheld {
+        return Ok(Some(uphold(conn, &dispute, resolver)?));
+    }
+    close(conn, dispute_id, "dismissed", resolver, None)?;
+    Ok(None)
+}
+
+// Sends the contested amount back where it came from
+// and marks the dispute upheld. If the recipient can't
+// cover it, or the entry was reversed some other way in
+// the meantime, the whole thing fails and the dispute
+// stays open.
+fn uphold(
+    conn: &rusqlite::Connection,
+    dispute: &db::DisputeEntry,
+    resolver: &str,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let entry = live_entry(conn, dispute.ledger_id)?;
+    let reversal = reversal::apply(conn, &entry)?;
+    close(conn, dispute.id, "upheld", resolver, Some(reversal.id))?;
+    Ok(reversal)
+}
+
+fn close(
+    conn: &rusqlite::Connection,
+    dispute_id: u32,
+    state: &str,
+    resolver: &str,
+    reversal_id: Option<u32>,
+) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "UPDATE disputes SET state = :state, resolver = :resolver, resolved = :resolved, reversal_id = :reversal_id
+            WHERE id = :id",
+        &[
+            (":state", &state),
+            (":resolver", &resolver),
+            (":resolved", &ledger::now()),
+            (":reversal_id", &reversal_id),
+            (":id", &dispute_id),
+        ],
+    )?;
+    Ok(())
+}
+
+fn open_dispute(
+    conn: &rusqlite::Connection,
+    dispute_id: u32,
+) -> Result<db::DisputeEntry, err::Resp> {
+    let dispute = conn
+        .query_row_named(
+            "SELECT id, ledger_id, contestant, reason, state, opened, resolved, resolver, reversal_id
+                FROM disputes WHERE id = :id",
+            &[(":id", &dispute_id)],
+            dispute_row,
+        )
+        .optional()?;
+
+    match dispute {
+        Some(val) if val.state == "open" => Ok(val),
+        Some(val) => {
+            let details = format!("Dispute {} has already been {}", dispute_id, val.state);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+        None => {
+            let details = format!("No such dispute: {}", dispute_id);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+    }
+}
+
+fn dispute_row(row: &rusqlite::Row) -> rusqlite::Result<db::DisputeEntry> {
+    Ok(db::DisputeEntry {
+        id: row.get(0)?,
+        ledger_id: row.get(1)?,
+        contestant: row.get(2)?,
+        reason: row.get(3)?,
+        state: row.get(4)?,
+        opened: row.get(5)?,
+        resolved: row.get(6)?,
+        resolver: row.get(7)?,
+        reversal_id: row.get(8)?,
+    })
+}
+
+// Archived entries are out of reach, since reversing
+// them would mean rewriting a closed period.
+pub fn live_entry(
+    conn: &rusqlite::Connection,
+    ledger_id: u32,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let entry = conn
+        .query_row_named(
+            &format!(
+                "SELECT {} FROM ledger WHERE id = :id",
+                query::LEDGER_COLUMNS
+            ),
+            &[(":id", &ledger_id)],
+            query::ledger_row,
+        )
+        .optional()?;
+
+    match entry {
+        Some(val) => Ok(val),
+        None => {
+            let details = format!("No live ledger entry {}", ledger_id);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+    }
+}
+
+fn parse_id(comm: &db::Comm, arg: &str) -> Option<u32> {
+    match arg.parse::<u32>() {
+        Ok(val) => Some(val),
+        Err(_) => {
+            let details = format!("Invalid dispute id: {}", arg);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            None
+        }
+    }
+}
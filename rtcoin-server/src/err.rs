@@ -13,6 +13,18 @@ use std::fmt;
 //      03: Invalid request
 //      04: Query Error
 //      05: Channel Send Error
+//      06: Authentication failure
+//      07: Insufficient funds
+//      08: Unknown user
+//      09: Stored balance doesn't match the ledger
+//      10: Ledger hash chain is broken
+//      11: Receipt doesn't match the ledger
+//      12: Permission denied
+//      13: Archive file doesn't match its Merkle root
+//      14: Invalid signature
+//      15: Account has been deactivated
+//      16: Account is frozen
+//      17: Spending limit exceeded
 #[derive(Debug)]
 pub struct Resp {
     code: u32,
@@ -58,6 +70,14 @@ impl Resp {
     }
 }
 
+// Lets the ? operator turn database errors into
+// a Query Error response.
+impl From<rusqlite::Error> for Resp {
+    fn from(err: rusqlite::Error) -> Self {
+        Resp::new(4, "Query Error", &format!("{}", err))
+    }
+}
+
 // I found myself writing this same construction
 // a few times repeatedly.
 pub fn log_then_panic<T>(context: &str, err: T)
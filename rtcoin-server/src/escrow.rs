@@ -0,0 +1,411 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{sync::mpsc, thread, time::Duration};
+
+use rusqlite::{OptionalExtension, NO_PARAMS};
+
+use crate::{amount::Amount, db, err, ledger, order, user};
+
+// The ledger account held funds sit in. It isn't a user,
+// so nobody can log in as it or register under its name.
+pub const ACCOUNT: &str = "escrow";
+
+// How often expired escrows are looked for.
+const TICK: u64 = 60;
+
+// Moves tcoin out of the sender's balance and holds it
+// until it's released to the recipient or refunded.
+// Accepts the args
+//     vec![sender, password, recipient, amount, expiry, (arbiter)]
+// The expiry is how long it's held for at most, written
+// like a standing order's interval: 12h, 3d, 1w. The
+// sender or the arbiter can release it. The recipient
+// or the arbiter can refund it early; otherwise it's
+// refunded when it expires.
+pub fn hold(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 5 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: sender, password, recipient, amount, expiry, (arbiter)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let amount = match args[3].parse::<Amount>() {
+        Ok(val) => val,
+        Err(details) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let expiry = match order::parse_interval(&args[4]) {
+        Some(val) => val,
+        None => {
+            let details = format!("Invalid expiry: {}", args[4]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let arbiter = args.get(5).map(|name| &name[..]);
+
+    match hold_tx(conn, &args[0], &args[2], amount, expiry, arbiter) {
+        Ok((id, entry)) => {
+            log::info!(
+                "Escrow {}: {} tcoin from {} to {} held in ledger entry {}",
+                id,
+                amount,
+                args[0],
+                args[2],
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Escrow {}: holding {} tcoin for {}. Receipt {}: {}",
+                id, amount, args[2], entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Escrow from {} to {} failed: {}",
+                args[0],
+                args[2],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn hold_tx(
+    conn: &mut rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    expiry: i64,
+    arbiter: Option<&str>,
+) -> Result<(u32, db::LedgerEntry), err::Resp> {
+    let tx = conn.transaction()?;
+    let held = open(&tx, source, destination, amount, expiry, arbiter)?;
+    tx.commit()?;
+    Ok(held)
+}
+
+// Takes the funds into escrow and records who can let
+// them go, under the names they have now. Returns the
+// escrow id and the ledger row that moved the funds.
+// Expects to be handed a transaction.
+pub fn open(
+    conn: &rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    expiry: i64,
+    arbiter: Option<&str>,
+) -> Result<(u32, db::LedgerEntry), err::Resp> {
+    let destination = &user::resolve(conn, destination)?[..];
+    let arbiter = match arbiter {
+        Some(name) => Some(user::resolve(conn, name)?),
+        None => None,
+    };
+    let arbiter = arbiter.as_deref();
+    if source == destination {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Source and destination are the same account",
+        ));
+    }
+    if let Some(arbiter) = arbiter {
+        if arbiter == source || arbiter == destination {
+            let details = format!("{} can't arbitrate their own escrow", arbiter);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    }
+
+    let entry = ledger::debit(conn, "escrow", source, ACCOUNT, amount)?;
+    let expires = ledger::seconds_from_now(expiry);
+    conn.execute_named(
+        "INSERT INTO escrow (source, destination, arbiter, amount, created, expires, state, hold_id)
+            VALUES (:source, :destination, :arbiter, :amount, :created, :expires, 'held', :hold_id)",
+        &[
+            (":source", &source),
+            (":destination", &destination),
+            (":arbiter", &arbiter),
+            (":amount", &amount),
+            (":created", &entry.timestamp),
+            (":expires", &expires),
+            (":hold_id", &entry.id),
+        ],
+    )?;
+    Ok((conn.last_insert_rowid() as u32, entry))
+}
+
+// Pays held funds out to the recipient. Accepts the args
+//     vec![user, password, escrow_id]
+// where the user is the sender or the arbiter.
+pub fn release(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    finish(comm, conn, true);
+}
+
+// Sends held funds back to the sender. Accepts the args
+//     vec![user, password, escrow_id]
+// where the user is the recipient or the arbiter.
+pub fn refund(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    finish(comm, conn, false);
+}
+
+fn finish(mut comm: db::Comm, conn: &mut rusqlite::Connection, released: bool) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, escrow_id",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let escrow_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid escrow id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match settle_tx(conn, &args[0], escrow_id, released) {
+        Ok(entry) => {
+            let outcome = if released { "released" } else { "refunded" };
+            log::info!(
+                "Escrow {} {} by {} in ledger entry {}",
+                escrow_id,
+                outcome,
+                args[0],
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Escrow {} {}: {} tcoin to {}. Receipt {}: {}",
+                escrow_id,
+                outcome,
+                entry.amount,
+                entry.destination,
+                entry.receipt_id,
+                entry.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Settling escrow {} for {} failed: {}",
+                escrow_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn settle_tx(
+    conn: &mut rusqlite::Connection,
+    name: &str,
+    escrow_id: u32,
+    released: bool,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let tx = conn.transaction()?;
+    let entry = settle(&tx, name, escrow_id, released)?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+// Releases or refunds on someone's say-so, once they've
+// been checked as allowed to. Once an escrow expires,
+// the only way out for it is a refund. Expects to be
+// handed a transaction.
+pub fn settle(
+    conn: &rusqlite::Connection,
+    name: &str,
+    escrow_id: u32,
+    released: bool,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let escrow = held(conn, escrow_id)?;
+    let party = if released {
+        &escrow.source
+    } else {
+        &escrow.destination
+    };
+    if name != party && escrow.arbiter.as_deref() != Some(name) {
+        let action = if released { "release" } else { "refund" };
+        let details = format!("{} can't {} escrow {}", name, action, escrow_id);
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+    if released && escrow.expires <= ledger::now() {
+        let details = format!("Escrow {} has expired", escrow_id);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    close(conn, &escrow, released)
+}
+
+// Internal request from the expiry thread. Refunds
+// every escrow past its expiry and replies with a row
+// per refund:
+//     escrow_id  ledger_id
+pub fn expire(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    match refund_expired(conn) {
+        Ok(refunds) => {
+            for (id, ledger_id) in &refunds {
+                log::info!(
+                    "Escrow {} expired, refunded in ledger entry {}",
+                    id,
+                    ledger_id
+                );
+            }
+            comm.reply(db::Reply::Rows(
+                refunds
+                    .iter()
+                    .map(|(id, ledger_id)| format!("{}\t{}", id, ledger_id))
+                    .collect(),
+            ));
+        }
+        Err(resp) => {
+            log::error!("Refunding expired escrows failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Each escrow is refunded in its own transaction. A
+// refund can't fail for want of funds, but it can if the
+// source has been frozen outright or deactivated since.
+// That escrow stays held and is tried again next tick,
+// without holding up the rest.
+fn refund_expired(conn: &mut rusqlite::Connection) -> Result<Vec<(u32, u32)>, err::Resp> {
+    let ids = {
+        let mut stmt = conn.prepare(
+            "SELECT id FROM escrow WHERE state = 'held' AND expires <= :now ORDER BY id",
+        )?;
+        let rows =
+            stmt.query_map_named(&[(":now", &ledger::now())], |row| row.get::<usize, u32>(0))?;
+        rows.collect::<rusqlite::Result<Vec<u32>>>()?
+    };
+
+    let mut refunds = Vec::new();
+    for id in ids {
+        match refund_tx(conn, id) {
+            Ok(entry) => refunds.push((id, entry.id)),
+            Err(resp) => log::warn!(
+                "Escrow {} expired but couldn't be refunded: {}",
+                id,
+                resp.details()
+            ),
+        }
+    }
+    Ok(refunds)
+}
+
+fn refund_tx(
+    conn: &mut rusqlite::Connection,
+    escrow_id: u32,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let tx = conn.transaction()?;
+    let escrow = held(&tx, escrow_id)?;
+    let entry = close(&tx, &escrow, false)?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+// Asks the ledger worker to refund expired escrows
+// every TICK seconds.
+pub fn expiry(pipe: mpsc::Sender<db::Comm>) {
+    loop {
+        thread::sleep(Duration::from_secs(TICK));
+
+        let (tx, rx) = mpsc::channel::<db::Reply>();
+        let comm = db::Comm::new(Some(db::Kind::Query), Some(vec!["expire".into()]), Some(tx));
+        if pipe.send(comm).is_err() {
+            log::warn!("Ledger worker is gone, stopping escrow expiry");
+            return;
+        }
+        if let Err(err) = rx.recv() {
+            log::warn!("No reply to escrow expiry: {:?}", err);
+        }
+    }
+}
+
+// Total tcoin still held in escrow.
+pub fn total_held(conn: &rusqlite::Connection) -> Result<Amount, err::Resp> {
+    Ok(conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM escrow WHERE state = 'held'",
+        NO_PARAMS,
+        |row| row.get::<usize, Amount>(0),
+    )?)
+}
+
+fn close(
+    conn: &rusqlite::Connection,
+    escrow: &db::EscrowEntry,
+    released: bool,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let (kind, state, to) = if released {
+        ("release", "released", &escrow.destination)
+    } else {
+        ("refund", "refunded", &escrow.source)
+    };
+    let entry = ledger::credit(conn, kind, ACCOUNT, to, escrow.amount)?;
+    conn.execute_named(
+        "UPDATE escrow SET state = :state, settled_id = :settled_id WHERE id = :id",
+        &[
+            (":state", &state),
+            (":settled_id", &entry.id),
+            (":id", &escrow.id),
+        ],
+    )?;
+    Ok(entry)
+}
+
+// Looks up an escrow that's still holding funds.
+fn held(conn: &rusqlite::Connection, escrow_id: u32) -> Result<db::EscrowEntry, err::Resp> {
+    let escrow = conn
+        .query_row_named(
+            "SELECT id, source, destination, arbiter, amount, created, expires, state, hold_id, settled_id
+                FROM escrow WHERE id = :id",
+            &[(":id", &escrow_id)],
+            escrow_row,
+        )
+        .optional()?;
+    match escrow {
+        Some(val) if val.state == "held" => Ok(val),
+        Some(val) => {
+            let details = format!("Escrow {} has been {}", escrow_id, val.state);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+        None => {
+            let details = format!("No such escrow: {}", escrow_id);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+    }
+}
+
+fn escrow_row(row: &rusqlite::Row) -> rusqlite::Result<db::EscrowEntry> {
+    Ok(db::EscrowEntry {
+        id: row.get(0)?,
+        source: row.get(1)?,
+        destination: row.get(2)?,
+        arbiter: row.get(3)?,
+        amount: row.get(4)?,
+        created: row.get(5)?,
+        expires: row.get(6)?,
+        state: row.get(7)?,
+        hold_id: row.get(8)?,
+        settled_id: row.get(9)?,
+    })
+}
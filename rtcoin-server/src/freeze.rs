@@ -0,0 +1,199 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use rusqlite::OptionalExtension;
+
+use crate::{db, err, ledger, user};
+
+// Stops an account from sending. Accepts the args
+//     vec![admin, password, account, send|all, reason...]
+// "all" stops it receiving as well. Freezing a frozen
+// account changes which of the two applies. Admin only,
+// and every freeze goes in the freeze log with its
+// reason.
+pub fn freeze(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 5 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, account, send|all, reason...",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let incoming = match args[3].as_str() {
+        "send" => false,
+        "all" => true,
+        other => {
+            let details = format!("Expected send or all, got {}", other);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let reason = args[4..].join(" ");
+
+    match log_tx(conn, &args[0], &args[2], Some(incoming), &reason) {
+        Ok(name) => {
+            log::info!("{} frozen by {}: {}", name, args[0], reason);
+            let what = if incoming {
+                "sending or receiving"
+            } else {
+                "sending"
+            };
+            comm.reply(db::Reply::Info(format!(
+                "Froze {}. They can't go on {} tcoin",
+                name, what
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Freeze of {} by {} failed: {}",
+                args[2],
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Lifts a freeze. Accepts the args
+//     vec![admin, password, account, reason...]
+// Admin only, and logged like a freeze.
+pub fn unfreeze(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, account, reason...",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let reason = args[3..].join(" ");
+
+    match log_tx(conn, &args[0], &args[2], None, &reason) {
+        Ok(name) => {
+            log::info!("{} unfrozen by {}: {}", name, args[0], reason);
+            comm.reply(db::Reply::Info(format!("Unfroze {}", name)));
+        }
+        Err(resp) => {
+            log::error!(
+                "Unfreeze of {} by {} failed: {}",
+                args[2],
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Sets or lifts the freeze and logs it together, so there's
+// never one without the other. None lifts it. Returns the
+// account's current name.
+fn log_tx(
+    conn: &mut rusqlite::Connection,
+    admin: &str,
+    account: &str,
+    incoming: Option<bool>,
+    reason: &str,
+) -> Result<String, err::Resp> {
+    let tx = conn.transaction()?;
+    let id = match user::id_of(&tx, account)? {
+        Some(val) => val,
+        None => {
+            let details = format!("No such user: {}", account);
+            return Err(err::Resp::new(8, "Unknown User", &details));
+        }
+    };
+    let name = user::name_of(&tx, id)?;
+    set(&tx, id, incoming)?;
+
+    let action = match incoming {
+        Some(true) => "freeze all",
+        Some(false) => "freeze send",
+        None => "unfreeze",
+    };
+    tx.execute_named(
+        "INSERT INTO freeze_log (user_id, action, reason, admin, timestamp)
+            VALUES (:user_id, :action, :reason, :admin, :timestamp)",
+        &[
+            (":user_id", &id),
+            (":action", &action),
+            (":reason", &reason),
+            (":admin", &admin),
+            (":timestamp", &ledger::now()),
+        ],
+    )?;
+    tx.commit()?;
+    Ok(name)
+}
+
+fn set(conn: &rusqlite::Connection, id: u32, incoming: Option<bool>) -> Result<(), err::Resp> {
+    let frozen = conn.query_row_named(
+        "SELECT frozen FROM users WHERE id = :id",
+        &[(":id", &id)],
+        |row| row.get::<usize, bool>(0),
+    )?;
+    if !frozen && incoming.is_none() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "That account isn't frozen",
+        ));
+    }
+    conn.execute_named(
+        "UPDATE users SET frozen = :frozen, frozen_incoming = :incoming WHERE id = :id",
+        &[
+            (":frozen", &incoming.is_some()),
+            (":incoming", &incoming.unwrap_or(false)),
+            (":id", &id),
+        ],
+    )?;
+    Ok(())
+}
+
+// Refuses to move tcoin out of a frozen account. Every
+// transfer checks this from inside its own transaction,
+// so a freeze takes effect on the very next one. Names
+// that aren't users pass.
+pub fn ensure_can_send(conn: &rusqlite::Connection, name: &str) -> Result<(), err::Resp> {
+    match flags(conn, name)? {
+        Some((true, _)) => {
+            let details = format!("{} is frozen and can't send tcoin", name);
+            Err(err::Resp::new(16, "Frozen Account", &details))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Same for moving tcoin in, which only a freeze on
+// everything stops.
+pub fn ensure_can_receive(conn: &rusqlite::Connection, name: &str) -> Result<(), err::Resp> {
+    match flags(conn, name)? {
+        Some((_, true)) => {
+            let details = format!("{} is frozen and can't receive tcoin", name);
+            Err(err::Resp::new(16, "Frozen Account", &details))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn flags(conn: &rusqlite::Connection, name: &str) -> Result<Option<(bool, bool)>, err::Resp> {
+    Ok(conn
+        .query_row_named(
+            "SELECT frozen, frozen_incoming FROM users WHERE name = :name",
+            &[(":name", &name)],
+            |row| Ok((row.get::<usize, bool>(0)?, row.get::<usize, bool>(1)?)),
+        )
+        .optional()?)
+}
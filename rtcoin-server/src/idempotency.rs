@@ -0,0 +1,177 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use ring::digest;
+use rusqlite::OptionalExtension;
+
+use crate::{db, err, ledger, user};
+
+// How long a key is remembered, in seconds. A client
+// retrying after a dropped connection does so long
+// before this runs out.
+const KEY_TTL: i64 = 60 * 60 * 24;
+
+// Stands in for the reply of a request that has been
+// claimed but hasn't finished.
+const PENDING: &str = "pending";
+
+// Looks for an earlier reply to the same user's request
+// under the same key. The args have to match what was
+// sent the first time, so a key can't be reused for a
+// different request by mistake.
+pub fn recall(
+    conn: &rusqlite::Connection,
+    kind: &db::Kind,
+    args: &[String],
+    key: &str,
+) -> Result<Option<db::Reply>, err::Resp> {
+    let earlier = conn
+        .query_row_named(
+            "SELECT fingerprint, reply_type, reply FROM idempotency
+                WHERE key = :key AND kind = :kind AND name = :name AND created > :cutoff",
+            &[
+                (":key", &key),
+                (":kind", &format!("{:?}", kind)),
+                (":name", &name(conn, args)?),
+                (":cutoff", &cutoff()),
+            ],
+            |row| {
+                Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, String>(1)?,
+                    row.get::<usize, String>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let (fingerprint, reply_type, reply) = match earlier {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+    if fingerprint != fingerprint_of(args) {
+        let details = format!("Key {} was already used for a different request", key);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    Ok(Some(match &reply_type[..] {
+        PENDING => {
+            let details = format!(
+                "The request under key {} was cut off before it finished. Check whether it went through before sending it again under a new key",
+                key
+            );
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        "data" => db::Reply::Data(reply),
+        "info" => db::Reply::Info(reply),
+        _ => db::Reply::Rows(reply.lines().map(String::from).collect()),
+    }))
+}
+
+// Claims a key before its request runs, so that if the
+// worker dies between the request committing and its
+// reply being stored, a retry finds the claim and is
+// turned away rather than run a second time. Keys past
+// KEY_TTL are cleared out along the way.
+pub fn reserve(
+    conn: &rusqlite::Connection,
+    kind: &db::Kind,
+    args: &[String],
+    key: &str,
+) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "DELETE FROM idempotency WHERE created <= :cutoff",
+        &[(":cutoff", &cutoff())],
+    )?;
+    conn.execute_named(
+        "INSERT INTO idempotency (key, kind, name, fingerprint, reply_type, reply, created)
+            VALUES (:key, :kind, :name, :fingerprint, :reply_type, '', :created)",
+        &[
+            (":key", &key),
+            (":kind", &format!("{:?}", kind)),
+            (":name", &name(conn, args)?),
+            (":fingerprint", &fingerprint_of(args)),
+            (":reply_type", &PENDING),
+            (":created", &ledger::now()),
+        ],
+    )?;
+    Ok(())
+}
+
+// Stores a reply under the key reserve() claimed for
+// it. Errors aren't kept: a request that failed didn't
+// change anything, so the claim is dropped and running
+// it again is safe.
+pub fn remember(
+    conn: &rusqlite::Connection,
+    kind: &db::Kind,
+    args: &[String],
+    key: &str,
+    reply: &db::Reply,
+) -> Result<(), err::Resp> {
+    let (reply_type, body) = match reply {
+        db::Reply::Data(body) => ("data", body.clone()),
+        db::Reply::Info(body) => ("info", body.clone()),
+        db::Reply::Rows(rows) => ("rows", rows.join("\n")),
+        db::Reply::Error(_) => return forget(conn, kind, args, key),
+    };
+
+    conn.execute_named(
+        "UPDATE idempotency SET reply_type = :reply_type, reply = :reply
+            WHERE key = :key AND kind = :kind AND name = :name",
+        &[
+            (":key", &key),
+            (":kind", &format!("{:?}", kind)),
+            (":name", &name(conn, args)?),
+            (":reply_type", &reply_type),
+            (":reply", &body),
+        ],
+    )?;
+    Ok(())
+}
+
+// Drops a claim, for a request that never got as far
+// as a reply.
+pub fn forget(
+    conn: &rusqlite::Connection,
+    kind: &db::Kind,
+    args: &[String],
+    key: &str,
+) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "DELETE FROM idempotency WHERE key = :key AND kind = :kind AND name = :name",
+        &[
+            (":key", &key),
+            (":kind", &format!("{:?}", kind)),
+            (":name", &name(conn, args)?),
+        ],
+    )?;
+    Ok(())
+}
+
+// Keys belong to the user named in the first arg, so
+// two clients can't trip over each other's keys. Renames
+// carry keys along, so they're filed under the account's
+// current name.
+fn name(conn: &rusqlite::Connection, args: &[String]) -> Result<String, err::Resp> {
+    user::current_name(conn, args.first().map_or("", |arg| &arg[..]))
+}
+
+// Hash of every arg but the password, which DB::once()
+// moves second before a request is keyed. The password
+// is checked again on a replay rather than kept here.
+fn fingerprint_of(args: &[String]) -> String {
+    let kept = args
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .map(|(_, arg)| &arg[..])
+        .collect::<Vec<&str>>();
+    ledger::to_hex(digest::digest(&digest::SHA256, kept.join("\t").as_bytes()).as_ref())
+}
+
+fn cutoff() -> String {
+    ledger::seconds_from_now(-KEY_TTL)
+}
@@ -0,0 +1,324 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use rusqlite::OptionalExtension;
+
+use crate::{amount::Amount, db, err, ledger, message, order, user};
+
+// Asks another user to pay up. Accepts the args
+//     vec![payee, password, payer, amount, expiry, (memo...)]
+// The expiry is written like a standing order's
+// interval: 12h, 3d, 1w. Nothing moves until the payer
+// approves it.
+pub fn create(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 5 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: payee, password, payer, amount, expiry, (memo...)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let invoice = parse_invoice(&args).and_then(|(amount, expiry, memo)| {
+        open(conn, &args[0], &args[2], amount, expiry, memo.as_deref())
+    });
+    match invoice {
+        Ok(id) => {
+            log::info!(
+                "Invoice {}: {} asks {} for {} tcoin",
+                id,
+                args[0],
+                args[2],
+                args[3]
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Invoice {}: asked {} for {} tcoin",
+                id, args[2], args[3]
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Invoice from {} to {} failed: {}",
+                args[0],
+                args[2],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn parse_invoice(args: &[String]) -> Result<(Amount, i64, Option<String>), err::Resp> {
+    let amount = args[3]
+        .parse::<Amount>()
+        .map_err(|details| err::Resp::new(3, "Invalid Request", &details))?;
+    let expiry = match order::parse_interval(&args[4]) {
+        Some(val) => val,
+        None => {
+            let details = format!("Invalid expiry: {}", args[4]);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+    let memo = message::from_args(&args[5..])?;
+    Ok((amount, expiry, memo))
+}
+
+// Records an invoice. The payer's funds aren't looked
+// at until they approve it.
+pub fn open(
+    conn: &rusqlite::Connection,
+    payee: &str,
+    payer: &str,
+    amount: Amount,
+    expiry: i64,
+    memo: Option<&str>,
+) -> Result<u32, err::Resp> {
+    let payer = &user::resolve(conn, payer)?[..];
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Transfer amount must be greater than zero",
+        ));
+    }
+    if payee == payer {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Source and destination are the same account",
+        ));
+    }
+    let expires = ledger::seconds_from_now(expiry);
+    conn.execute_named(
+        "INSERT INTO invoices (payee, payer, amount, memo, created, expires, state)
+            VALUES (:payee, :payer, :amount, :memo, :created, :expires, 'open')",
+        &[
+            (":payee", &payee),
+            (":payer", &payer),
+            (":amount", &amount),
+            (":memo", &memo),
+            (":created", &ledger::now()),
+            (":expires", &expires),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid() as u32)
+}
+
+// Lists the invoices waiting on a user to pay them,
+// oldest first. Accepts the args
+//     vec![payer, password]
+// Each row is
+//     id  payee  amount  expires  memo
+// Expired invoices are left out.
+pub fn list(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: payer, password",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    match waiting_on(conn, &args[0]) {
+        Ok(invoices) => {
+            let rows = invoices
+                .iter()
+                .map(|invoice| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}",
+                        invoice.id,
+                        invoice.payee,
+                        invoice.amount,
+                        invoice.expires,
+                        invoice.memo.as_deref().unwrap_or("")
+                    )
+                })
+                .collect();
+            comm.reply(db::Reply::Rows(rows));
+        }
+        Err(resp) => {
+            log::error!("Invoice lookup for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn waiting_on(
+    conn: &rusqlite::Connection,
+    payer: &str,
+) -> Result<Vec<db::InvoiceEntry>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT id, payee, payer, amount, memo, created, expires, state, ledger_id
+            FROM invoices WHERE payer = :payer AND state = 'open' AND expires > :now
+            ORDER BY id",
+    )?;
+    let rows =
+        stmt.query_map_named(&[(":payer", &payer), (":now", &ledger::now())], invoice_row)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<db::InvoiceEntry>>>()?)
+}
+
+// Pays an invoice. Accepts the args
+//     vec![payer, password, invoice_id]
+// The payment is a normal send, memo and all.
+pub fn approve(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    answer(comm, conn, true);
+}
+
+// Turns an invoice down. Accepts the args
+//     vec![payer, password, invoice_id]
+pub fn decline(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    answer(comm, conn, false);
+}
+
+fn answer(mut comm: db::Comm, conn: &mut rusqlite::Connection, approved: bool) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: payer, password, invoice_id",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let invoice_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid invoice id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match answer_tx(conn, &args[0], invoice_id, approved) {
+        Ok(Some(entry)) => {
+            log::info!("Invoice {} paid in ledger entry {}", invoice_id, entry.id);
+            comm.reply(db::Reply::Info(format!(
+                "Paid invoice {}: {} tcoin to {}. Receipt {}: {}",
+                invoice_id, entry.amount, entry.destination, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Ok(None) => {
+            log::info!("Invoice {} declined by {}", invoice_id, args[0]);
+            comm.reply(db::Reply::Info(format!("Declined invoice {}", invoice_id)));
+        }
+        Err(resp) => {
+            log::error!(
+                "Answering invoice {} for {} failed: {}",
+                invoice_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn answer_tx(
+    conn: &mut rusqlite::Connection,
+    payer: &str,
+    invoice_id: u32,
+    approved: bool,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let tx = conn.transaction()?;
+    let entry = settle(&tx, payer, invoice_id, approved)?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+// Pays or declines an open invoice on the payer's say-so.
+// Returns the payment's ledger row, or None if it was
+// declined. Expects to be handed a transaction.
+pub fn settle(
+    conn: &rusqlite::Connection,
+    payer: &str,
+    invoice_id: u32,
+    approved: bool,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let invoice = conn
+        .query_row_named(
+            "SELECT id, payee, payer, amount, memo, created, expires, state, ledger_id
+                FROM invoices WHERE id = :id",
+            &[(":id", &invoice_id)],
+            invoice_row,
+        )
+        .optional()?;
+    let invoice = match invoice {
+        Some(val) => val,
+        None => {
+            let details = format!("No such invoice: {}", invoice_id);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+    if invoice.payer != payer {
+        let details = format!("Invoice {} isn't addressed to {}", invoice_id, payer);
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+    if invoice.state != "open" {
+        let details = format!("Invoice {} is {}", invoice_id, invoice.state);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    if invoice.expires <= ledger::now() {
+        let details = format!("Invoice {} has expired", invoice_id);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    if !approved {
+        set_state(conn, invoice_id, "declined", None)?;
+        return Ok(None);
+    }
+    let entry = user::transfer_with_message(
+        conn,
+        &invoice.payer,
+        &invoice.payee,
+        invoice.amount,
+        invoice.memo.as_deref(),
+    )?;
+    set_state(conn, invoice_id, "paid", Some(entry.id))?;
+    Ok(Some(entry))
+}
+
+fn set_state(
+    conn: &rusqlite::Connection,
+    invoice_id: u32,
+    state: &str,
+    ledger_id: Option<u32>,
+) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "UPDATE invoices SET state = :state, ledger_id = :ledger_id WHERE id = :id",
+        &[
+            (":state", &state),
+            (":ledger_id", &ledger_id),
+            (":id", &invoice_id),
+        ],
+    )?;
+    Ok(())
+}
+
+fn invoice_row(row: &rusqlite::Row) -> rusqlite::Result<db::InvoiceEntry> {
+    Ok(db::InvoiceEntry {
+        id: row.get(0)?,
+        payee: row.get(1)?,
+        payer: row.get(2)?,
+        amount: row.get(3)?,
+        memo: row.get(4)?,
+        created: row.get(5)?,
+        expires: row.get(6)?,
+        state: row.get(7)?,
+        ledger_id: row.get(8)?,
+    })
+}
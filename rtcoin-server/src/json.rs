@@ -31,6 +31,38 @@ pub fn to_comm(json: &Value, tx: mpsc::Sender<db::Reply>) -> Option<db::Comm> {
         "audit" => Kind::Audit,
         "resolve" => Kind::Resolve,
         "second" => Kind::Second,
+        "receipts" => Kind::Receipts,
+        "archive" => Kind::Archive,
+        "proof" => Kind::Proof,
+        "propose" => Kind::Propose,
+        "signers" => Kind::Signers,
+        "history" => Kind::History,
+        "messages" => Kind::Messages,
+        "order" => Kind::Order,
+        "orders" => Kind::Orders,
+        "cancel" => Kind::Cancel,
+        "escrow" => Kind::Escrow,
+        "release" => Kind::Release,
+        "refund" => Kind::Refund,
+        "batch" => Kind::Batch,
+        "invoice" => Kind::Invoice,
+        "invoices" => Kind::Invoices,
+        "approve" => Kind::Approve,
+        "decline" => Kind::Decline,
+        "mint" => Kind::Mint,
+        "burn" => Kind::Burn,
+        "grant" => Kind::Grant,
+        "reverse" => Kind::Reverse,
+        "deactivate" => Kind::Deactivate,
+        "freeze" => Kind::Freeze,
+        "unfreeze" => Kind::Unfreeze,
+        "limit" => Kind::Limit,
+        "limits" => Kind::Limits,
+        "asof" => Kind::AsOf,
+        "stats" => Kind::Stats,
+        "report" => Kind::Report,
+        "privacy" => Kind::Privacy,
+        "quorum" => Kind::Quorum,
         "query" => Kind::Query,           // Query and Disconnect are internal
         "disconnect" => Kind::Disconnect, // values for miscellaneous database
         &_ => return None,                // queries and shutting down the DB.
@@ -42,7 +74,12 @@ pub fn to_comm(json: &Value, tx: mpsc::Sender<db::Reply>) -> Option<db::Comm> {
         .map(|s| s.to_string())
         .collect::<Vec<String>>();
 
-    Some(db::Comm::new(Some(kind), Some(args), Some(tx)))
+    // Clients can tag state-changing requests with a key
+    // of their own choosing, so a retry after a dropped
+    // connection doesn't run the request twice.
+    let key = json["key"].as_str().map(String::from);
+
+    Some(db::Comm::new(Some(kind), Some(args), Some(tx)).with_key(key))
 }
 
 // Takes a string, outputs JSON.
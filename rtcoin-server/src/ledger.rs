@@ -0,0 +1,617 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use chrono::prelude::*;
+use ring::digest;
+use rusqlite::{OptionalExtension, NO_PARAMS};
+
+use crate::{amount::Amount, archive, db, err, freeze, limit, pending, query, receipt, user};
+
+// Stands in for the previous row's hash when
+// hashing the very first ledger row.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// What new rows are hashed with. Version 1 covered the
+// names on a row but not the user ids that make it
+// count toward an account, so rows from before version 2
+// only commit to the names. Version 3 added the links
+// below.
+pub const HASH_VERSION: u32 = 3;
+
+// What a row can point to besides its parties: the batch
+// it was paid in, and the row it reverses. They're
+// written with the row and hashed along with it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Links {
+    pub batch_id: Option<u32>,
+    pub reverses: Option<u32>,
+}
+
+// Result of walking the hash chain. Intact holds the
+// number of rows checked, Broken holds the id of the
+// first row whose hash doesn't follow from the rest.
+#[derive(Debug, PartialEq)]
+pub enum Chain {
+    Intact(u32),
+    Broken(u32),
+}
+
+// Moves tcoin from one account to another and appends the
+// matching ledger row. This doesn't open a transaction on
+// its own. Callers hand it a rusqlite::Transaction (which
+// derefs to a Connection) so that any failure here gets
+// rolled back along with whatever else they've done.
+// A recipient named by an old name gets it under their
+// current one. Deactivated accounts can't receive
+// anything, frozen ones are held to their freeze, and
+// the source has to stay within its spending limits and
+// its signing policy.
+// Returns the new ledger row.
+pub fn transfer(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+) -> Result<db::LedgerEntry, err::Resp> {
+    transfer_linked(conn, kind, source, destination, amount, Links::default())
+}
+
+// Same, for a row that belongs to a batch or reverses
+// another.
+pub fn transfer_linked(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    links: Links,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let destination = &user::current_name(conn, destination)?[..];
+    user::ensure_active(conn, destination)?;
+    freeze::ensure_can_send(conn, source)?;
+    freeze::ensure_can_receive(conn, destination)?;
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Transfer amount must be greater than zero",
+        ));
+    }
+    if source == destination {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Source and destination are the same account",
+        ));
+    }
+    limit::check(conn, kind, source, destination, amount)?;
+    pending::check(conn, kind, source, destination)?;
+
+    let available = balance_of(conn, source)?;
+    let received = balance_of(conn, destination)?;
+
+    if available < amount {
+        let details = format!(
+            "{} has {} tcoin available, tried to send {}",
+            source, available, amount
+        );
+        return Err(err::Resp::new(7, "Insufficient Funds", &details));
+    }
+
+    let (source_balance, destination_balance) =
+        match (available.checked_sub(amount), received.checked_add(amount)) {
+            (Some(src), Some(dest)) => (src, dest),
+            _ => {
+                let details = format!("Transfer of {} tcoin overflows a balance", amount);
+                return Err(err::Resp::new(3, "Invalid Request", &details));
+            }
+        };
+
+    set_balance(conn, source, source_balance)?;
+    set_balance(conn, destination, destination_balance)?;
+
+    append_linked(conn, kind, source, destination, amount, links)
+}
+
+// Moves tcoin out of a user's balance into an account
+// the ledger keeps itself, such as escrow, and appends
+// the matching row. Like transfer(), this expects to be
+// handed a transaction.
+pub fn debit(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    account: &str,
+    amount: Amount,
+) -> Result<db::LedgerEntry, err::Resp> {
+    freeze::ensure_can_send(conn, source)?;
+    pending::check(conn, kind, source, account)?;
+    reclaim(conn, kind, source, account, amount)
+}
+
+// Same as debit(), but goes ahead even if the source is
+// frozen. Only for an admin taking tcoin back into one of
+// the ledger's accounts, which a freeze exists to allow.
+pub fn reclaim(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    account: &str,
+    amount: Amount,
+) -> Result<db::LedgerEntry, err::Resp> {
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Transfer amount must be greater than zero",
+        ));
+    }
+    limit::check(conn, kind, source, account, amount)?;
+    let available = balance_of(conn, source)?;
+    let balance = match available.checked_sub(amount) {
+        Some(val) if available >= amount => val,
+        _ => {
+            let details = format!(
+                "{} has {} tcoin available, tried to send {}",
+                source, available, amount
+            );
+            return Err(err::Resp::new(7, "Insufficient Funds", &details));
+        }
+    };
+    set_balance(conn, source, balance)?;
+    append(conn, kind, source, account, amount)
+}
+
+// The other way around: pays tcoin held in one of the
+// ledger's own accounts out to a user.
+pub fn credit(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    account: &str,
+    destination: &str,
+    amount: Amount,
+) -> Result<db::LedgerEntry, err::Resp> {
+    user::ensure_active(conn, destination)?;
+    freeze::ensure_can_receive(conn, destination)?;
+    let received = balance_of(conn, destination)?;
+    let balance = match received.checked_add(amount) {
+        Some(val) => val,
+        None => {
+            let details = format!("Transfer of {} tcoin overflows a balance", amount);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+    set_balance(conn, destination, balance)?;
+    append(conn, kind, account, destination, amount)
+}
+
+// Appends a row to the ledger, links it into the hash
+// chain and issues its receipt. Doesn't touch any
+// balances.
+pub fn append(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+) -> Result<db::LedgerEntry, err::Resp> {
+    append_linked(conn, kind, source, destination, amount, Links::default())
+}
+
+pub fn append_linked(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    links: Links,
+) -> Result<db::LedgerEntry, err::Resp> {
+    // The row is tied to the accounts by id as well, so it
+    // stays theirs if they're renamed. The ledger's own
+    // accounts have no id and are left NULL.
+    let now = now();
+    conn.execute_named(
+        "INSERT INTO ledger (type, timestamp, source, destination, amount, ledger_hash, receipt_id, receipt_hash,
+                source_id, destination_id, hash_version, batch_id, reverses)
+            VALUES (:type, :timestamp, :source, :destination, :amount, '', 0, '',
+                (SELECT id FROM users WHERE name = :source),
+                (SELECT id FROM users WHERE name = :destination),
+                :hash_version, :batch_id, :reverses)",
+        &[
+            (":type", &kind),
+            (":timestamp", &now),
+            (":source", &source),
+            (":destination", &destination),
+            (":amount", &amount),
+            (":hash_version", &HASH_VERSION),
+            (":batch_id", &links.batch_id),
+            (":reverses", &links.reverses),
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    // The id is part of what gets hashed, so the hash
+    // can only be filled in once the row exists.
+    let mut entry = conn.query_row_named(
+        &format!(
+            "SELECT {} FROM ledger WHERE id = :id",
+            query::LEDGER_COLUMNS
+        ),
+        &[(":id", &id)],
+        query::ledger_row,
+    )?;
+    entry.ledger_hash = chain_hash(&previous_hash(conn, id)?, &entry);
+    conn.execute_named(
+        "UPDATE ledger SET ledger_hash = :hash WHERE id = :id",
+        &[(":hash", &entry.ledger_hash), (":id", &id)],
+    )?;
+
+    let (receipt_id, receipt_hash) = receipt::issue(conn, id, &entry.ledger_hash)?;
+    entry.receipt_id = receipt_id;
+    entry.receipt_hash = receipt_hash;
+
+    Ok(entry)
+}
+
+// Hash of the row just before the given id. The first
+// live row chains from the archive anchor instead.
+pub fn previous_hash(conn: &rusqlite::Connection, id: i64) -> Result<String, err::Resp> {
+    let prev = conn
+        .query_row_named(
+            "SELECT ledger_hash FROM ledger WHERE id < :id ORDER BY id DESC LIMIT 1",
+            &[(":id", &id)],
+            |row| row.get::<usize, String>(0),
+        )
+        .optional()?;
+
+    match prev {
+        Some(val) => Ok(val),
+        None => archive::anchor(conn),
+    }
+}
+
+// A row's hash commits to the previous row's hash and
+// to every field that describes the transfer itself,
+// as the row's hash version defines them. Fields are
+// tab-separated; none of them can contain whitespace
+// since request args are split on it. A missing id is
+// left empty.
+pub fn chain_hash(prev: &str, entry: &db::LedgerEntry) -> String {
+    let mut preimage = format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        prev,
+        entry.id,
+        entry.transaction_type,
+        entry.timestamp,
+        entry.source,
+        entry.destination,
+        entry.amount.milli()
+    );
+    let id = |val: Option<u32>| val.map(|id| id.to_string()).unwrap_or_default();
+    if entry.hash_version >= 2 {
+        preimage.push_str(&format!(
+            "\t{}\t{}",
+            id(entry.source_id),
+            id(entry.destination_id)
+        ));
+    }
+    if entry.hash_version >= 3 {
+        preimage.push_str(&format!("\t{}\t{}", id(entry.batch_id), id(entry.reverses)));
+    }
+    to_hex(digest::digest(&digest::SHA256, preimage.as_bytes()).as_ref())
+}
+
+// Every timestamp the server stores is RFC3339 in UTC to
+// the second, so they all compare correctly as strings.
+pub fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn now() -> String {
+    timestamp(Utc::now())
+}
+
+// The time a number of seconds from now, or ago if it's
+// negative.
+pub fn seconds_from_now(seconds: i64) -> String {
+    timestamp(Utc::now() + chrono::Duration::seconds(seconds))
+}
+
+// Reads an RFC3339 timestamp from a client and puts it
+// in the same form the ledger uses.
+pub fn parse_timestamp(input: &str) -> Option<String> {
+    DateTime::parse_from_rfc3339(input)
+        .ok()
+        .map(|val| timestamp(val.with_timezone(&Utc)))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Walks the live ledger in order, recomputing each row's
+// hash from the one before it, starting from the hash
+// of the last archived row.
+pub fn verify_chain(conn: &rusqlite::Connection) -> Result<Chain, err::Resp> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM ledger ORDER BY id",
+        query::LEDGER_COLUMNS
+    ))?;
+    let rows = stmt.query_map(NO_PARAMS, query::ledger_row)?;
+
+    let mut prev = archive::anchor(conn)?;
+    let mut count = 0;
+    for row in rows {
+        let entry = row?;
+        if chain_hash(&prev, &entry) != entry.ledger_hash {
+            return Ok(Chain::Broken(entry.id));
+        }
+        prev = entry.ledger_hash;
+        count += 1;
+    }
+
+    Ok(Chain::Intact(count))
+}
+
+// Answers a verify request. With no args, checks the whole
+// chain. With the args
+//     vec![receipt_id, receipt_hash]
+// checks that receipt against its ledger row, or its
+// batch's rows, instead.
+// Neither reveals more than the caller already knows, so
+// there's no authentication.
+pub fn verify(comm: db::Comm, conn: &rusqlite::Connection) {
+    let args = comm.args();
+    if !args.is_empty() {
+        verify_receipt(&comm, conn, &args);
+        return;
+    }
+
+    match verify_chain(conn) {
+        Ok(Chain::Intact(count)) => {
+            let msg = format!("Ledger hash chain intact: {} entries checked", count);
+            comm.reply(db::Reply::Info(msg));
+        }
+        Ok(Chain::Broken(id)) => {
+            let details = format!("First broken link at ledger entry {}", id);
+            log::error!("Ledger hash chain verification failed: {}", details);
+            comm.reply_error(err::Resp::new(10, "Chain Broken", &details));
+        }
+        Err(resp) => {
+            log::error!("Ledger hash chain verification failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Stored balance for a user. Unknown users are an error
+// rather than a zero balance, so a typo in a recipient's
+// name can't swallow a transfer.
+pub fn balance_of(conn: &rusqlite::Connection, name: &str) -> Result<Amount, err::Resp> {
+    let balance = conn
+        .query_row_named(
+            "SELECT balance FROM users WHERE name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, Amount>(0),
+        )
+        .optional()?;
+
+    match balance {
+        Some(val) => Ok(val),
+        None => {
+            let details = format!("No such user: {}", name);
+            Err(err::Resp::new(8, "Unknown User", &details))
+        }
+    }
+}
+
+fn set_balance(conn: &rusqlite::Connection, name: &str, balance: Amount) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "UPDATE users SET balance = :balance WHERE name = :name",
+        &[(":balance", &balance), (":name", &name)],
+    )?;
+    Ok(())
+}
+
+// Rebuilds a user's balance from scratch. Their grant
+// is on the ledger like everything else, so this is just
+// their net movement over the whole ledger.
+pub fn ledger_balance(conn: &rusqlite::Connection, name: &str) -> Result<Amount, err::Resp> {
+    net(conn, name)
+}
+
+// Everything an account has received, minus everything
+// it has sent, counting archived periods. This works for
+// accounts the ledger keeps itself, like escrow, as well
+// as for users. A user's rows are found by id, so rows
+// from before a rename still count.
+pub fn net(conn: &rusqlite::Connection, name: &str) -> Result<Amount, err::Resp> {
+    let carried = conn.query_row_named(
+        "SELECT COALESCE(SUM(net), 0) FROM archive_balances WHERE name = :name",
+        &[(":name", &name)],
+        |row| row.get::<usize, Amount>(0),
+    )?;
+    live_net(conn, name, carried, u32::MAX)
+}
+
+// What an account held as of a moment in time. Like
+// closing an archive, this counts every row up to the
+// newest one at or before the timestamp, so the answer
+// always matches some prefix of the ledger. Periods
+// archived since come out of archive_balances whole, or
+// out of the archive file if the moment falls inside
+// one. Returns the balance and the id of that newest row.
+pub fn net_as_of(
+    conn: &rusqlite::Connection,
+    name: &str,
+    timestamp: &str,
+) -> Result<(Amount, u32), err::Resp> {
+    let live = conn.query_row_named(
+        "SELECT MAX(id) FROM ledger WHERE timestamp <= :timestamp",
+        &[(":timestamp", &timestamp)],
+        |row| row.get::<usize, Option<u32>>(0),
+    )?;
+    let last_id = match live {
+        Some(val) => val,
+        None => match archive::last_id_at(conn, timestamp)? {
+            Some(val) => val,
+            None => {
+                let details = format!("No ledger entries at or before {}", timestamp);
+                return Err(err::Resp::new(3, "Invalid Request", &details));
+            }
+        },
+    };
+
+    let current = user::current_name(conn, name)?;
+    let mut carried = archive::carried_through(conn, &current, last_id)?;
+    if let Some(entry) = archive::holding(conn, last_id)? {
+        if entry.last_id > last_id {
+            let names = match user::id_of(conn, name)? {
+                Some(id) => user::names_of(conn, id)?,
+                None => vec![name.to_string()],
+            };
+            carried = carried
+                .checked_add(archive::net_through(&entry, &names, last_id)?)
+                .ok_or_else(|| {
+                    let details = format!("Ledger history for {} overflows a balance", name);
+                    err::Resp::new(9, "Balance Mismatch", &details)
+                })?;
+        }
+    }
+    Ok((live_net(conn, name, carried, last_id)?, last_id))
+}
+
+// Adds an account's live ledger rows, up to and
+// including last_id, to what it carried in.
+fn live_net(
+    conn: &rusqlite::Connection,
+    name: &str,
+    carried: Amount,
+    last_id: u32,
+) -> Result<Amount, err::Resp> {
+    let totals =
+        |row: &rusqlite::Row| Ok((row.get::<usize, Amount>(0)?, row.get::<usize, Amount>(1)?));
+    let (credits, debits) = match user::id_of(conn, name)? {
+        Some(id) => conn.query_row_named(
+            "SELECT
+                COALESCE(SUM(CASE WHEN destination_id = :id THEN amount END), 0),
+                COALESCE(SUM(CASE WHEN source_id = :id THEN amount END), 0)
+            FROM ledger WHERE (source_id = :id OR destination_id = :id) AND id <= :last_id",
+            &[(":id", &id), (":last_id", &last_id)],
+            totals,
+        )?,
+        None => conn.query_row_named(
+            "SELECT
+                COALESCE(SUM(CASE WHEN destination = :name THEN amount END), 0),
+                COALESCE(SUM(CASE WHEN source = :name THEN amount END), 0)
+            FROM ledger WHERE (source = :name OR destination = :name) AND id <= :last_id",
+            &[(":name", &name), (":last_id", &last_id)],
+            totals,
+        )?,
+    };
+
+    carried
+        .checked_add(credits)
+        .and_then(|val| val.checked_sub(debits))
+        .ok_or_else(|| {
+            let details = format!("Ledger history for {} overflows a balance", name);
+            err::Resp::new(9, "Balance Mismatch", &details)
+        })
+}
+
+// The ids of the accounts on either side of a ledger row.
+// Either is None for the ledger's own accounts, or for a
+// name that never belonged to anyone.
+pub fn parties(
+    conn: &rusqlite::Connection,
+    ledger_id: u32,
+) -> Result<(Option<u32>, Option<u32>), err::Resp> {
+    Ok(conn.query_row_named(
+        "SELECT source_id, destination_id FROM ledger WHERE id = :id",
+        &[(":id", &ledger_id)],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?)
+}
+
+// Returns the stored balance alongside the one rebuilt
+// from the ledger, so the caller can decide what to do
+// if they don't match.
+pub fn reconcile(conn: &rusqlite::Connection, name: &str) -> Result<(Amount, Amount), err::Resp> {
+    let stored = balance_of(conn, name)?;
+    let computed = ledger_balance(conn, name)?;
+    Ok((stored, computed))
+}
+
+fn verify_receipt(comm: &db::Comm, conn: &rusqlite::Connection, args: &[String]) {
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: receipt_id, receipt_hash",
+        ));
+        return;
+    }
+    let receipt_id = match args[0].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid receipt id: {}", args[0]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match receipt::check_batch(conn, receipt_id, &args[1]) {
+        Ok(Some((batch, _))) => {
+            comm.reply(db::Reply::Info(format!(
+                "Receipt {} matches batch {}: {} tcoin from {} in {} payments at {}",
+                receipt_id, batch.id, batch.total, batch.source, batch.count, batch.created
+            )));
+            return;
+        }
+        Ok(None) => {}
+        Err(resp) => {
+            log::warn!(
+                "Receipt {} failed verification: {}",
+                receipt_id,
+                resp.details()
+            );
+            comm.reply_error(resp);
+            return;
+        }
+    }
+
+    match receipt::check(conn, receipt_id, &args[1]) {
+        Ok(entry) => {
+            comm.reply(db::Reply::Info(format!(
+                "Receipt {} matches ledger entry {}: {} tcoin from {} to {} at {}",
+                receipt_id,
+                entry.id,
+                entry.amount,
+                entry.source,
+                entry.destination,
+                entry.timestamp
+            )));
+        }
+        Err(resp) => {
+            log::warn!(
+                "Receipt {} failed verification: {}",
+                receipt_id,
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use rusqlite::OptionalExtension;
+
+use crate::{amount::Amount, archive, db, err, ledger, treasury, user};
+
+// How far back the daily limit looks, in seconds. It's
+// a rolling window, not a calendar day.
+const WINDOW: i64 = 24 * 60 * 60;
+
+// The two limits there are: on everything sent in the
+// last WINDOW, and on any one transfer.
+const KINDS: [&str; 2] = ["daily", "transfer"];
+
+// Sets a spending limit. Accepts the args
+//     vec![admin, password, account|*, daily|transfer, amount|none]
+// "*" sets the server-wide default, which applies to
+// every account that doesn't have a limit of its own.
+// "none" takes the limit away, so an account falls back
+// to the default and the default to no limit at all.
+// Admin only.
+pub fn set(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 5 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, account|*, daily|transfer, amount|none",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    if !KINDS.contains(&args[3].as_str()) {
+        let details = format!("Expected daily or transfer, got {}", args[3]);
+        comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+        return;
+    }
+    let amount = match args[4].as_str() {
+        "none" => None,
+        val => match val.parse::<Amount>() {
+            Ok(val) if val.is_positive() => Some(val),
+            Ok(_) => {
+                comm.reply_error(err::Resp::new(
+                    3,
+                    "Invalid Request",
+                    "Limits must be greater than zero",
+                ));
+                return;
+            }
+            Err(details) => {
+                comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+                return;
+            }
+        },
+    };
+
+    let account = if args[2] == "*" {
+        None
+    } else {
+        Some(args[2].as_str())
+    };
+    match store(conn, account, &args[3], amount) {
+        Ok(()) => {
+            let whose = account.unwrap_or("the default");
+            let what = match amount {
+                Some(val) => format!("{} tcoin", val),
+                None => "lifted".to_string(),
+            };
+            log::info!("{} limit for {} {} by {}", args[3], whose, what, args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "The {} limit for {} is now {}",
+                args[3], whose, what
+            )));
+        }
+        Err(resp) => {
+            log::error!("Setting a limit failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn store(
+    conn: &rusqlite::Connection,
+    account: Option<&str>,
+    kind: &str,
+    amount: Option<Amount>,
+) -> Result<(), err::Resp> {
+    let id = match account {
+        Some(name) => match user::id_of(conn, name)? {
+            Some(val) => Some(val),
+            None => {
+                let details = format!("No such user: {}", name);
+                return Err(err::Resp::new(8, "Unknown User", &details));
+            }
+        },
+        None => None,
+    };
+    let setting = format!("{}_limit", kind);
+    match (id, amount) {
+        (Some(id), Some(amount)) => conn.execute_named(
+            "INSERT OR REPLACE INTO limits (user_id, kind, value) VALUES (:user_id, :kind, :value)",
+            &[(":user_id", &id), (":kind", &kind), (":value", &amount)],
+        )?,
+        (Some(id), None) => conn.execute_named(
+            "DELETE FROM limits WHERE user_id = :user_id AND kind = :kind",
+            &[(":user_id", &id), (":kind", &kind)],
+        )?,
+        (None, Some(amount)) => conn.execute_named(
+            "INSERT OR REPLACE INTO settings (name, value) VALUES (:name, :value)",
+            &[(":name", &setting), (":value", &amount)],
+        )?,
+        (None, None) => conn.execute_named(
+            "DELETE FROM settings WHERE name = :name",
+            &[(":name", &setting)],
+        )?,
+    };
+    Ok(())
+}
+
+// Shows a user the limits they're held to. Accepts the
+// args
+//     vec![user, password]
+// Replies with the rows
+//     daily  limit|none  sent in the last 24 hours
+//     transfer  limit|none
+pub fn show(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    match describe(conn, &args[0]) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::error!("Limit lookup for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn describe(conn: &rusqlite::Connection, name: &str) -> Result<Vec<String>, err::Resp> {
+    let id = match user::id_of(conn, name)? {
+        Some(val) => val,
+        None => {
+            let details = format!("No such user: {}", name);
+            return Err(err::Resp::new(8, "Unknown User", &details));
+        }
+    };
+    let show = |limit: Option<Amount>| match limit {
+        Some(val) => val.to_string(),
+        None => "none".to_string(),
+    };
+    Ok(vec![
+        format!(
+            "daily\t{}\t{}",
+            show(limit(conn, id, "daily")?),
+            spent(conn, id)?
+        ),
+        format!("transfer\t{}", show(limit(conn, id, "transfer")?)),
+    ])
+}
+
+// Refuses a transfer that would take the source over
+// either of its limits. The transfer path calls this
+// from inside its own transaction, and the daily total
+// comes from the ledger itself, so there's no counter to
+// fall out of step. Tcoin on its way back to where it
+// came from, by reversal or by a sweep into the
+// treasury, isn't spending and isn't counted.
+pub fn check(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+) -> Result<(), err::Resp> {
+    if kind == "reversal" || destination == treasury::ACCOUNT {
+        return Ok(());
+    }
+    let id = match user::id_of(conn, source)? {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    if let Some(max) = limit(conn, id, "transfer")? {
+        if amount > max {
+            let details = format!(
+                "{} can send at most {} tcoin at a time, tried to send {}",
+                source, max, amount
+            );
+            return Err(err::Resp::new(17, "Limit Exceeded", &details));
+        }
+    }
+    if let Some(max) = limit(conn, id, "daily")? {
+        let sent = spent(conn, id)?;
+        match sent.checked_add(amount) {
+            Some(total) if total <= max => {}
+            _ => {
+                let details = format!(
+                    "{} has sent {} tcoin in the last 24 hours, tried to send {} more against a limit of {}",
+                    source, sent, amount, max
+                );
+                return Err(err::Resp::new(17, "Limit Exceeded", &details));
+            }
+        }
+    }
+    Ok(())
+}
+
+// An account's own limit if it has one, otherwise the
+// server-wide default, if there is one.
+fn limit(conn: &rusqlite::Connection, id: u32, kind: &str) -> Result<Option<Amount>, err::Resp> {
+    let own = conn
+        .query_row_named(
+            "SELECT value FROM limits WHERE user_id = :user_id AND kind = :kind",
+            &[(":user_id", &id), (":kind", &kind)],
+            |row| row.get::<usize, Amount>(0),
+        )
+        .optional()?;
+    if own.is_some() {
+        return Ok(own);
+    }
+    Ok(conn
+        .query_row_named(
+            "SELECT value FROM settings WHERE name = :name",
+            &[(":name", &format!("{}_limit", kind))],
+            |row| row.get::<usize, Amount>(0),
+        )
+        .optional()?)
+}
+
+// What an account has sent in the last WINDOW, counted
+// the same way check() counts it. Rows archived inside
+// the window still count, so archiving can't reset it.
+fn spent(conn: &rusqlite::Connection, id: u32) -> Result<Amount, err::Resp> {
+    let since = ledger::seconds_from_now(-WINDOW);
+    let live = conn.query_row_named(
+        "SELECT COALESCE(SUM(amount), 0) FROM ledger
+            WHERE source_id = :id AND timestamp > :since
+            AND type != 'reversal' AND destination != :treasury",
+        &[
+            (":id", &id),
+            (":since", &since),
+            (":treasury", &treasury::ACCOUNT),
+        ],
+        |row| row.get::<usize, Amount>(0),
+    )?;
+
+    let mut total = live;
+    for row in archive::sent_since(conn, id, &since)? {
+        if row.transaction_type == "reversal" || row.destination == treasury::ACCOUNT {
+            continue;
+        }
+        total = total.checked_add(row.amount).ok_or_else(|| {
+            err::Resp::new(
+                17,
+                "Limit Exceeded",
+                "Spending in the last 24 hours overflows",
+            )
+        })?;
+    }
+    Ok(total)
+}
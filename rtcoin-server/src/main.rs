@@ -10,12 +10,30 @@ use std::{error::Error, fs, os::unix::net::UnixListener, path::Path, process, sy
 use threadpool::ThreadPool;
 use zeroize::Zeroize;
 
+mod amount;
+mod archive;
+mod audit;
+mod batch;
 mod conn;
 mod db;
+mod dispute;
 mod err;
+mod escrow;
+mod freeze;
+mod idempotency;
+mod invoice;
 mod json;
+mod ledger;
+mod limit;
 mod logging;
+mod message;
+mod order;
+mod pending;
 mod query;
+mod receipt;
+mod reversal;
+mod stats;
+mod treasury;
 mod user;
 
 #[cfg(test)]
@@ -45,6 +63,36 @@ fn main() -> Result<(), Box<dyn Error>> {
     let (tx, rx) = mpsc::channel::<db::Comm>();
     thread::spawn(move || spawn_ledger_worker(db_key, rx));
 
+    // Queue up an audit of the whole ledger every night.
+    let audit_tx = tx.clone();
+    thread::Builder::new()
+        .name("Nightly Audit".into())
+        .spawn(move || audit::nightly(audit_tx))
+        .unwrap_or_else(|error| {
+            err::log_then_panic("Nightly audit failed to spawn", error);
+            panic!();
+        });
+
+    // Pay out standing orders as they come due.
+    let order_tx = tx.clone();
+    thread::Builder::new()
+        .name("Standing Orders".into())
+        .spawn(move || order::scheduler(order_tx))
+        .unwrap_or_else(|error| {
+            err::log_then_panic("Standing order scheduler failed to spawn", error);
+            panic!();
+        });
+
+    // Refund escrows nobody released in time.
+    let escrow_tx = tx.clone();
+    thread::Builder::new()
+        .name("Escrow Expiry".into())
+        .spawn(move || escrow::expiry(escrow_tx))
+        .unwrap_or_else(|error| {
+            err::log_then_panic("Escrow expiry failed to spawn", error);
+            panic!();
+        });
+
     // If the socket exists already, remove it.
     let sock = Path::new(conn::SOCK);
     if fs::metadata(sock).is_ok() {
@@ -98,7 +146,7 @@ fn spawn_ledger_worker(mut db_key: String, rx: mpsc::Receiver<db::Comm>) {
     // This next call opens the actual database connection.
     // It also creates the tables if they don't yet exist.
     log::info!("Connecting to database: {}", db::PATH);
-    let ledger = DB::connect(db::PATH, db_key.clone(), rx);
+    let mut ledger = DB::connect(db::PATH, db_key.clone(), rx);
     db_key.zeroize();
 
     // Naming the thread helps with debugging. It will
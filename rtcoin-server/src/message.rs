@@ -0,0 +1,103 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use crate::{amount::Amount, db, err, query, user};
+
+// Longest note a transfer can carry, in characters.
+pub const MAX_LEN: usize = 256;
+
+// Request args arrive split on whitespace, so the words
+// of a note are put back together with single spaces.
+// An empty note is None.
+pub fn from_args(words: &[String]) -> Result<Option<String>, err::Resp> {
+    let text = words.join(" ");
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text.chars().count() > MAX_LEN {
+        let details = format!("Messages are limited to {} characters", MAX_LEN);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    Ok(Some(text))
+}
+
+// Stores the note that goes with a ledger row. Expects
+// to be handed the same transaction as the transfer, so
+// the two stand or fall together.
+pub fn attach(conn: &rusqlite::Connection, ledger_id: u32, text: &str) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "INSERT INTO messages (ledger_id, message) VALUES (:ledger_id, :message)",
+        &[(":ledger_id", &ledger_id), (":message", &text)],
+    )?;
+    Ok(())
+}
+
+// Lists the notes on transfers a user sent or received,
+// newest first. Accepts the args
+//     vec![user, password, (count)]
+// A count of zero, or none at all, lists every message.
+// Each row is
+//     id  timestamp  source  destination  amount  message
+// Transfers that have been archived drop out of the list.
+pub fn list(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, (count)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let count = match args.get(2).map(|n| n.parse::<i64>()) {
+        None => 0,
+        Some(Ok(n)) if n >= 0 => n,
+        Some(_) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", "Invalid count"));
+            return;
+        }
+    };
+
+    match messages_for(conn, &args[0], count) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::error!("Message lookup for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn messages_for(
+    conn: &rusqlite::Connection,
+    name: &str,
+    count: i64,
+) -> Result<Vec<String>, err::Resp> {
+    let limit = query::limit_of(count);
+    let mut stmt = conn.prepare(
+        "SELECT ledger.id, ledger.timestamp, ledger.source, ledger.destination,
+                ledger.amount, messages.message
+            FROM messages JOIN ledger ON ledger.id = messages.ledger_id
+            WHERE ledger.source_id = :id OR ledger.destination_id = :id
+            ORDER BY ledger.id DESC LIMIT :limit",
+    )?;
+    let id = user::id_of(conn, name)?;
+    let rows = stmt.query_map_named(&[(":id", &id), (":limit", &limit)], |row| {
+        Ok(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            row.get::<usize, u32>(0)?,
+            row.get::<usize, String>(1)?,
+            row.get::<usize, String>(2)?,
+            row.get::<usize, String>(3)?,
+            row.get::<usize, Amount>(4)?,
+            row.get::<usize, String>(5)?
+        ))
+    })?;
+
+    Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+}
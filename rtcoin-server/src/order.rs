@@ -0,0 +1,527 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{sync::mpsc, thread, time::Duration};
+
+use chrono::prelude::*;
+use rusqlite::OptionalExtension;
+
+use crate::{amount::Amount, db, err, ledger, message, user};
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = HOUR * 24;
+const WEEK: i64 = DAY * 7;
+
+// How often the scheduler looks for orders that
+// have come due.
+const TICK: u64 = 60;
+
+// Sets up a standing order: the same transfer, repeated
+// every interval until the end date. Accepts the args
+//     vec![owner, password, recipient, amount, interval, end|never, (message...)]
+// The interval is a count of hours, days or weeks, such
+// as 12h, 1d or 2w. The end is an RFC3339 timestamp. The
+// first payment goes out on the scheduler's next pass.
+pub fn create(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 6 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: owner, password, recipient, amount, interval, end|never, (message...)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let order = parse_order(&args).and_then(|(amount, interval, ends, message)| {
+        let id = open(
+            conn,
+            &args[0],
+            &args[2],
+            amount,
+            interval,
+            ends.as_deref(),
+            message.as_deref(),
+        )?;
+        Ok((id, amount))
+    });
+    match order {
+        Ok((id, amount)) => {
+            log::info!(
+                "Standing order {}: {} tcoin from {} to {} every {}",
+                id,
+                amount,
+                args[0],
+                args[2],
+                args[4]
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Standing order {}: {} tcoin to {} every {}",
+                id, amount, args[2], args[4]
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Standing order from {} to {} failed: {}",
+                args[0],
+                args[2],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn parse_order(
+    args: &[String],
+) -> Result<(Amount, i64, Option<String>, Option<String>), err::Resp> {
+    let amount = args[3]
+        .parse::<Amount>()
+        .map_err(|details| err::Resp::new(3, "Invalid Request", &details))?;
+    let interval = match parse_interval(&args[4]) {
+        Some(val) => val,
+        None => {
+            let details = format!("Invalid interval: {}", args[4]);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+    let ends = match &args[5][..] {
+        "never" => None,
+        end => match ledger::parse_timestamp(end) {
+            Some(val) => Some(val),
+            None => {
+                let details = format!("Invalid end date: {}", end);
+                return Err(err::Resp::new(3, "Invalid Request", &details));
+            }
+        },
+    };
+    let message = message::from_args(&args[6..])?;
+    Ok((amount, interval, ends, message))
+}
+
+// Records a standing order. Funds aren't checked here;
+// each payment is checked when it goes out.
+pub fn open(
+    conn: &rusqlite::Connection,
+    owner: &str,
+    destination: &str,
+    amount: Amount,
+    interval: i64,
+    ends: Option<&str>,
+    message: Option<&str>,
+) -> Result<u32, err::Resp> {
+    let destination = &user::resolve(conn, destination)?[..];
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Transfer amount must be greater than zero",
+        ));
+    }
+    if owner == destination {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Source and destination are the same account",
+        ));
+    }
+    let created = ledger::now();
+    match ends {
+        Some(end) if end <= &created[..] => {
+            return Err(err::Resp::new(
+                3,
+                "Invalid Request",
+                "End date has already passed",
+            ));
+        }
+        _ => {}
+    }
+    conn.execute_named(
+        "INSERT INTO orders (owner, destination, amount, interval, next_due, ends, message, state, created)
+            VALUES (:owner, :destination, :amount, :interval, :created, :ends, :message, 'active', :created)",
+        &[
+            (":owner", &owner),
+            (":destination", &destination),
+            (":amount", &amount),
+            (":interval", &interval),
+            (":created", &created),
+            (":ends", &ends),
+            (":message", &message),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid() as u32)
+}
+
+// Lists a user's standing orders, newest first. Accepts
+// the args
+//     vec![owner, password]
+// Each row is
+//     id  recipient  amount  interval  next_due  end|never  state
+pub fn list(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: owner, password",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    match orders_for(conn, &args[0]) {
+        Ok(orders) => {
+            let rows = orders
+                .iter()
+                .map(|order| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        order.id,
+                        order.destination,
+                        order.amount,
+                        show_interval(order.interval),
+                        order.next_due,
+                        order.ends.as_deref().unwrap_or("never"),
+                        order.state
+                    )
+                })
+                .collect();
+            comm.reply(db::Reply::Rows(rows));
+        }
+        Err(resp) => {
+            log::error!("Order lookup for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn orders_for(conn: &rusqlite::Connection, owner: &str) -> Result<Vec<db::OrderEntry>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT id, owner, destination, amount, interval, next_due, ends, message, state
+            FROM orders WHERE owner = :owner ORDER BY id DESC",
+    )?;
+    let rows = stmt.query_map_named(&[(":owner", &owner)], order_row)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<db::OrderEntry>>>()?)
+}
+
+// Stops a standing order. Accepts the args
+//     vec![owner, password, order_id]
+pub fn cancel(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: owner, password, order_id",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let order_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid order id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match stop(conn, &args[0], order_id) {
+        Ok(()) => {
+            log::info!("Standing order {} cancelled by {}", order_id, args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "Cancelled standing order {}",
+                order_id
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Cancelling order {} for {} failed: {}",
+                order_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+pub fn stop(conn: &rusqlite::Connection, owner: &str, order_id: u32) -> Result<(), err::Resp> {
+    let order = find(conn, order_id)?;
+    if order.owner != owner {
+        let details = format!("Standing order {} belongs to someone else", order_id);
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+    if order.state != "active" {
+        let details = format!("Standing order {} is {}", order_id, order.state);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    set_state(conn, order_id, "cancelled")
+}
+
+// Internal request from the scheduler. Replies with a
+// row for every active order that has come due:
+//     id  owner  recipient  amount  next_due  (message)
+// Each is then paid by a send request of its own.
+pub fn due(comm: db::Comm, conn: &rusqlite::Connection) {
+    let rows = conn
+        .prepare(
+            "SELECT id, owner, destination, amount, next_due, message FROM orders
+                WHERE state = 'active' AND next_due <= :now ORDER BY next_due",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map_named(&[(":now", &ledger::now())], |row| {
+                Ok(format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    row.get::<usize, u32>(0)?,
+                    row.get::<usize, String>(1)?,
+                    row.get::<usize, String>(2)?,
+                    row.get::<usize, Amount>(3)?,
+                    row.get::<usize, String>(4)?,
+                    row.get::<usize, Option<String>>(5)?.unwrap_or_default()
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<String>>>()
+        });
+
+    match rows {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(err) => {
+            log::error!("Could not look up due orders: {}", err);
+            comm.reply_error(err::Resp::from(err));
+        }
+    }
+}
+
+// Internal request from the scheduler, carrying the args
+//     vec!["advance", order_id, next_due]
+// Moves an order on once the payment due at next_due has
+// gone through or been refused. A refused payment is
+// skipped rather than retried, so a broke owner doesn't
+// get tried every tick. If the order has moved on
+// already, it's left alone.
+pub fn advance(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let args = comm.args();
+    let order_id = match args.get(1).map(|id| id.parse::<u32>()) {
+        Some(Ok(val)) => val,
+        _ => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", "Invalid order id"));
+            return;
+        }
+    };
+    let next_due = args.get(2).cloned().unwrap_or_default();
+
+    match advance_tx(conn, order_id, &next_due) {
+        Ok(true) => comm.reply(db::Reply::Info(format!(
+            "Standing order {} moved on from {}",
+            order_id, next_due
+        ))),
+        Ok(false) => comm.reply(db::Reply::Info(format!(
+            "Standing order {} isn't due at {}",
+            order_id, next_due
+        ))),
+        Err(resp) => {
+            log::error!(
+                "Could not reschedule order {}: {}",
+                order_id,
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn advance_tx(
+    conn: &mut rusqlite::Connection,
+    order_id: u32,
+    next_due: &str,
+) -> Result<bool, err::Resp> {
+    let tx = conn.transaction()?;
+    let order = find(&tx, order_id)?;
+    if order.state != "active" || order.next_due != next_due {
+        return Ok(false);
+    }
+    reschedule(&tx, &order)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+// Moves an order on to its next payment, past now. If the
+// server was down for a few intervals, the ones it missed
+// aren't paid out back to back. Past the end date, the
+// order is finished.
+fn reschedule(conn: &rusqlite::Connection, order: &db::OrderEntry) -> Result<(), err::Resp> {
+    let now = Utc::now();
+    let mut next = DateTime::parse_from_rfc3339(&order.next_due)
+        .map(|val| val.with_timezone(&Utc))
+        .unwrap_or(now);
+    while next <= now {
+        next += chrono::Duration::seconds(order.interval);
+    }
+    let next = ledger::timestamp(next);
+
+    match &order.ends {
+        Some(end) if next > *end => return set_state(conn, order.id, "finished"),
+        _ => {}
+    }
+    conn.execute_named(
+        "UPDATE orders SET next_due = :next_due WHERE id = :id",
+        &[(":next_due", &next), (":id", &order.id)],
+    )?;
+    Ok(())
+}
+
+// Checks for due orders every TICK seconds and pays
+// them on the ledger worker, behind whatever clients have
+// already sent.
+pub fn scheduler(pipe: mpsc::Sender<db::Comm>) {
+    loop {
+        thread::sleep(Duration::from_secs(TICK));
+        if !tick(&pipe) {
+            log::warn!("Ledger worker is gone, stopping standing orders");
+            return;
+        }
+    }
+}
+
+// One pass of the scheduler. Each payment is an ordinary
+// send request from the order's owner, so it's held to
+// everything a send is. The server doesn't have the
+// owner's password, so the request is marked internal
+// instead. It's keyed by the order and its due time: if
+// the server stops between the payment and moving the
+// order on, the next pass replays the payment's reply
+// rather than paying twice. Returns false once the
+// ledger worker is gone.
+pub fn tick(pipe: &mpsc::Sender<db::Comm>) -> bool {
+    let rows = match ask(pipe, db::Kind::Query, vec!["orders".into()], None) {
+        Some(db::Reply::Rows(rows)) => rows,
+        Some(other) => {
+            log::warn!("Unexpected reply to due orders: {:?}", other);
+            return true;
+        }
+        None => return false,
+    };
+
+    for row in rows {
+        let cols = row.splitn(6, '\t').collect::<Vec<&str>>();
+        if cols.len() < 6 {
+            log::warn!("Unexpected due order: {}", row);
+            continue;
+        }
+        let (id, next_due) = (cols[0], cols[4]);
+        let mut args = vec![
+            cols[1].into(),
+            String::new(),
+            cols[2].into(),
+            cols[3].into(),
+        ];
+        args.extend(cols[5].split_whitespace().map(String::from));
+        let key = format!("order-{}-{}", id, next_due);
+
+        match ask(pipe, db::Kind::Send, args, Some(key)) {
+            Some(db::Reply::Error(err)) => {
+                log::error!("Standing order {} payment failed: {}", id, err)
+            }
+            Some(_) => log::info!("Standing order {} paid", id),
+            None => return false,
+        }
+        let args = vec!["advance".into(), id.into(), next_due.into()];
+        if ask(pipe, db::Kind::Query, args, None).is_none() {
+            return false;
+        }
+    }
+    true
+}
+
+fn ask(
+    pipe: &mpsc::Sender<db::Comm>,
+    kind: db::Kind,
+    args: Vec<String>,
+    key: Option<String>,
+) -> Option<db::Reply> {
+    let (tx, rx) = mpsc::channel::<db::Reply>();
+    let comm = db::Comm::new(Some(kind), Some(args), Some(tx))
+        .with_key(key)
+        .internal();
+    pipe.send(comm).ok()?;
+    rx.recv().ok()
+}
+
+// Reads an interval such as 12h, 1d or 2w into
+// seconds. Anything under an hour is refused.
+pub fn parse_interval(input: &str) -> Option<i64> {
+    if input.len() < 2 {
+        return None;
+    }
+    let (count, unit) = input.split_at(input.len() - 1);
+    let unit = match unit {
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => WEEK,
+        _ => return None,
+    };
+    match count.parse::<i64>() {
+        Ok(n) if n > 0 => n.checked_mul(unit),
+        _ => None,
+    }
+}
+
+fn show_interval(seconds: i64) -> String {
+    if seconds % WEEK == 0 {
+        format!("{}w", seconds / WEEK)
+    } else if seconds % DAY == 0 {
+        format!("{}d", seconds / DAY)
+    } else {
+        format!("{}h", seconds / HOUR)
+    }
+}
+
+fn find(conn: &rusqlite::Connection, order_id: u32) -> Result<db::OrderEntry, err::Resp> {
+    let order = conn
+        .query_row_named(
+            "SELECT id, owner, destination, amount, interval, next_due, ends, message, state
+                FROM orders WHERE id = :id",
+            &[(":id", &order_id)],
+            order_row,
+        )
+        .optional()?;
+    match order {
+        Some(val) => Ok(val),
+        None => {
+            let details = format!("No such standing order: {}", order_id);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+    }
+}
+
+fn set_state(conn: &rusqlite::Connection, order_id: u32, state: &str) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "UPDATE orders SET state = :state WHERE id = :id",
+        &[(":state", &state), (":id", &order_id)],
+    )?;
+    Ok(())
+}
+
+fn order_row(row: &rusqlite::Row) -> rusqlite::Result<db::OrderEntry> {
+    Ok(db::OrderEntry {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        destination: row.get(2)?,
+        amount: row.get(3)?,
+        interval: row.get(4)?,
+        next_due: row.get(5)?,
+        ends: row.get(6)?,
+        message: row.get(7)?,
+        state: row.get(8)?,
+    })
+}
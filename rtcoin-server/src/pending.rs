@@ -0,0 +1,552 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use ring::signature;
+use rusqlite::OptionalExtension;
+
+use crate::{amount::Amount, db, err, ledger, order, treasury, user};
+
+// Where a signature leaves a pending transfer.
+#[derive(Debug)]
+pub enum Signed {
+    Waiting(i64),
+    Settled(db::LedgerEntry),
+    Declined,
+    Expired,
+}
+
+// Proposes a transfer that only settles once others
+// have signed off on it. Accepts the args
+//     vec![sender, password, recipient, amount, expiry]
+// The sender's signing policy says who has to sign.
+// Without one, the recipient does. The expiry is an
+// interval such as 12h, 3d or 2w, after which the
+// transfer can no longer be signed.
+pub fn propose(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 5 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: sender, password, recipient, amount, expiry",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let amount = match args[3].parse::<Amount>() {
+        Ok(val) => val,
+        Err(details) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let expiry = match order::parse_interval(&args[4]) {
+        Some(val) => val,
+        None => {
+            let details = format!("Invalid expiry: {}", args[4]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match propose_tx(conn, &args[0], &args[2], amount, expiry) {
+        Ok((id, signers)) => {
+            log::info!(
+                "Pending transfer {}: {} tcoin from {} to {}",
+                id,
+                amount,
+                args[0],
+                args[2]
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Pending transfer {}: {} tcoin to {}, awaiting signatures from {}",
+                id,
+                amount,
+                args[2],
+                signers.join(", ")
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Proposal from {} to {} failed: {}",
+                args[0],
+                args[2],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn propose_tx(
+    conn: &mut rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    expiry: i64,
+) -> Result<(u32, Vec<String>), err::Resp> {
+    let tx = conn.transaction()?;
+    let mut signers = policy(&tx, source)?;
+    if signers.is_empty() {
+        signers.push(user::resolve(&tx, destination)?);
+    }
+    let id = open(&tx, source, destination, amount, &signers, expiry)?;
+    tx.commit()?;
+    Ok((id, signers))
+}
+
+// Records a pending transfer and who has to sign it,
+// open for expiry seconds. Everyone is recorded under
+// the name they have now, however they were named. Funds aren't checked here;
+// that happens when the last signature comes in and the
+// transfer settles. Expects to be handed a transaction,
+// so a transfer is never left with only some of its
+// signers.
+pub fn open(
+    conn: &rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    signers: &[String],
+    expiry: i64,
+) -> Result<u32, err::Resp> {
+    let destination = &user::resolve(conn, destination)?[..];
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Transfer amount must be greater than zero",
+        ));
+    }
+    if source == destination {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Source and destination are the same account",
+        ));
+    }
+    let mut names: Vec<String> = Vec::new();
+    for signer in signers {
+        let name = user::resolve(conn, signer)?;
+        if name == source || names.contains(&name) {
+            let details = format!("{} can't sign this transfer", signer);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        names.push(name);
+    }
+
+    conn.execute_named(
+        "INSERT INTO pending (source, destination, amount, created, expires, state)
+            VALUES (:source, :destination, :amount, :created, :expires, 'pending')",
+        &[
+            (":source", &source),
+            (":destination", &destination),
+            (":amount", &amount),
+            (":created", &ledger::now()),
+            (":expires", &ledger::seconds_from_now(expiry)),
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    for signer in &names {
+        conn.execute_named(
+            "INSERT INTO pending_signers (pending_id, name) VALUES (:pending_id, :name)",
+            &[(":pending_id", &id), (":name", signer)],
+        )?;
+    }
+    Ok(id as u32)
+}
+
+// Sets who has to sign off on every transfer out of an
+// account. Accepts the args
+//     vec![user, password, account, signer...|none]
+// The holder can give their own account a policy, but
+// once it has one only an admin can change or lift it;
+// otherwise whoever holds the password could drop the
+// co-signers it exists to require. "none" lifts it.
+pub fn set_signers(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, account, signer...|none",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let signers = if args[3..] == ["none"] {
+        Vec::new()
+    } else {
+        args[3..].to_vec()
+    };
+
+    match set_signers_tx(conn, &args[0], &args[2], &signers) {
+        Ok(()) if signers.is_empty() => {
+            log::info!("Signing policy for {} lifted by {}", args[2], args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "Transfers from {} no longer need signatures",
+                args[2]
+            )));
+        }
+        Ok(()) => {
+            log::info!("Signing policy for {} set by {}", args[2], args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "Transfers from {} now need signatures from {}",
+                args[2],
+                signers.join(", ")
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Setting signers for {} by {} failed: {}",
+                args[2],
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn set_signers_tx(
+    conn: &mut rusqlite::Connection,
+    caller: &str,
+    account: &str,
+    signers: &[String],
+) -> Result<(), err::Resp> {
+    let tx = conn.transaction()?;
+    let admin = user::is_admin(&tx, caller)?;
+    if !admin && user::current_name(&tx, account)? != caller {
+        let details = format!("{} can't set signers for {}", caller, account);
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+    if !admin && !policy(&tx, account)?.is_empty() {
+        let details = format!(
+            "{} already has a signing policy, which only an admin can change",
+            account
+        );
+        return Err(err::Resp::new(12, "Permission Denied", &details));
+    }
+    store(&tx, account, signers)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Replaces an account's signers. Stored by id, so the
+// policy holds through renames on either side.
+fn store(conn: &rusqlite::Connection, account: &str, signers: &[String]) -> Result<(), err::Resp> {
+    let unknown = |name: &str| {
+        let details = format!("No such user: {}", name);
+        err::Resp::new(8, "Unknown User", &details)
+    };
+    let id = user::id_of(conn, account)?.ok_or_else(|| unknown(account))?;
+    let mut ids = Vec::new();
+    for signer in signers {
+        let signer_id = user::id_of(conn, signer)?.ok_or_else(|| unknown(signer))?;
+        if signer_id == id || ids.contains(&signer_id) {
+            let details = format!("{} can't sign for {}", signer, account);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        ids.push(signer_id);
+    }
+
+    conn.execute_named(
+        "DELETE FROM signers WHERE user_id = :user_id",
+        &[(":user_id", &id)],
+    )?;
+    for signer_id in ids {
+        conn.execute_named(
+            "INSERT INTO signers (user_id, signer_id) VALUES (:user_id, :signer_id)",
+            &[(":user_id", &id), (":signer_id", &signer_id)],
+        )?;
+    }
+    Ok(())
+}
+
+// The current names of everyone who has to sign for an
+// account, in the order they were named. Empty if it
+// has no policy.
+pub fn policy(conn: &rusqlite::Connection, name: &str) -> Result<Vec<String>, err::Resp> {
+    let id = match user::id_of(conn, name)? {
+        Some(val) => val,
+        None => return Ok(Vec::new()),
+    };
+    let mut stmt =
+        conn.prepare("SELECT signer_id FROM signers WHERE user_id = :user_id ORDER BY rowid")?;
+    let ids = stmt
+        .query_map_named(&[(":user_id", &id)], |row| row.get::<usize, u32>(0))?
+        .collect::<rusqlite::Result<Vec<u32>>>()?;
+    ids.into_iter().map(|id| user::name_of(conn, id)).collect()
+}
+
+// Refuses to move tcoin out of an account with a signing
+// policy any way but through a signed pending transfer.
+// ledger::transfer() and debit() both check it, the same
+// as freezes and limits. Reversals and sweeps
+// into the treasury aren't the holder spending, so they
+// aren't held to it.
+pub fn check(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    source: &str,
+    destination: &str,
+) -> Result<(), err::Resp> {
+    if kind == "signed" || kind == "reversal" || destination == treasury::ACCOUNT {
+        return Ok(());
+    }
+    let signers = policy(conn, source)?;
+    if signers.is_empty() {
+        return Ok(());
+    }
+    let details = format!(
+        "Transfers from {} need signatures from {} and have to be proposed",
+        source,
+        signers.join(", ")
+    );
+    Err(err::Resp::new(12, "Permission Denied", &details))
+}
+
+// Signs, or declines, a pending transfer. Accepts the args
+//     vec![signer, password, pending_id, signature|decline]
+// The signature is hex-encoded Ed25519 over message(),
+// checked against the signer's registered public key.
+pub fn sign(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: signer, password, pending_id, signature|decline",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let pending_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid pending transfer id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match sign_tx(conn, &args[0], pending_id, &args[3]) {
+        Ok(Signed::Waiting(remaining)) => {
+            comm.reply(db::Reply::Info(format!(
+                "Signed pending transfer {}, {} signatures to go",
+                pending_id, remaining
+            )));
+        }
+        Ok(Signed::Settled(entry)) => {
+            log::info!(
+                "Pending transfer {} settled in ledger entry {}",
+                pending_id,
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Signed pending transfer {}, settled in ledger entry {}. Receipt {}: {}",
+                pending_id, entry.id, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Ok(Signed::Declined) => {
+            log::info!("Pending transfer {} declined by {}", pending_id, args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "Declined pending transfer {}",
+                pending_id
+            )));
+        }
+        Ok(Signed::Expired) => {
+            log::info!("Pending transfer {} expired", pending_id);
+            let details = format!("Pending transfer {} has expired", pending_id);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+        }
+        Err(resp) => {
+            log::error!(
+                "Signature on pending transfer {} by {} failed: {}",
+                pending_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn sign_tx(
+    conn: &mut rusqlite::Connection,
+    signer: &str,
+    pending_id: u32,
+    signature: &str,
+) -> Result<Signed, err::Resp> {
+    let tx = conn.transaction()?;
+    let outcome = add_signature(&tx, signer, pending_id, signature)?;
+    tx.commit()?;
+    Ok(outcome)
+}
+
+// Records one signer's answer. The last signature
+// settles the transfer through ledger::transfer(), so if
+// the sender can no longer cover it, the signature is
+// refused along with it and can be sent again later.
+// Nothing is held while a transfer waits, so there's
+// nothing to give back when it expires; it's marked
+// expired the next time someone tries to sign it.
+// Expects to be handed a transaction.
+pub fn add_signature(
+    conn: &rusqlite::Connection,
+    signer: &str,
+    pending_id: u32,
+    signature: &str,
+) -> Result<Signed, err::Resp> {
+    let pending = conn
+        .query_row_named(
+            "SELECT id, source, destination, amount, created, expires, state, ledger_id
+                FROM pending WHERE id = :id",
+            &[(":id", &pending_id)],
+            pending_row,
+        )
+        .optional()?;
+    let pending = match pending {
+        Some(val) if val.state == "pending" => val,
+        Some(val) => {
+            let details = format!("Pending transfer {} is {}", pending_id, val.state);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        None => {
+            let details = format!("No such pending transfer: {}", pending_id);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+    };
+
+    let existing = conn
+        .query_row_named(
+            "SELECT signature FROM pending_signers WHERE pending_id = :pending_id AND name = :name",
+            &[(":pending_id", &pending_id), (":name", &signer)],
+            |row| row.get::<usize, Option<String>>(0),
+        )
+        .optional()?;
+    match existing {
+        None => {
+            let details = format!("{} isn't asked to sign transfer {}", signer, pending_id);
+            return Err(err::Resp::new(12, "Permission Denied", &details));
+        }
+        Some(Some(_)) => {
+            let details = format!("{} has already signed transfer {}", signer, pending_id);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        Some(None) => {}
+    }
+
+    if pending.expires <= ledger::now() {
+        set_state(conn, pending_id, "expired", None)?;
+        return Ok(Signed::Expired);
+    }
+
+    if signature == "decline" {
+        set_state(conn, pending_id, "declined", None)?;
+        return Ok(Signed::Declined);
+    }
+
+    let pubkey = conn.query_row_named(
+        "SELECT pubkey FROM users WHERE name = :name",
+        &[(":name", &signer)],
+        |row| row.get::<usize, String>(0),
+    )?;
+    if !verify(&pubkey, &message(&pending), signature) {
+        let details = format!("Bad signature from {} on transfer {}", signer, pending_id);
+        return Err(err::Resp::new(14, "Invalid Signature", &details));
+    }
+
+    conn.execute_named(
+        "UPDATE pending_signers SET signature = :signature, signed = :signed
+            WHERE pending_id = :pending_id AND name = :name",
+        &[
+            (":signature", &signature),
+            (":signed", &ledger::now()),
+            (":pending_id", &pending_id),
+            (":name", &signer),
+        ],
+    )?;
+
+    let remaining = conn.query_row_named(
+        "SELECT COUNT(*) FROM pending_signers WHERE pending_id = :pending_id AND signature IS NULL",
+        &[(":pending_id", &pending_id)],
+        |row| row.get::<usize, i64>(0),
+    )?;
+    if remaining > 0 {
+        return Ok(Signed::Waiting(remaining));
+    }
+
+    let entry = ledger::transfer(
+        conn,
+        "signed",
+        &pending.source,
+        &pending.destination,
+        pending.amount,
+    )?;
+    set_state(conn, pending_id, "settled", Some(entry.id))?;
+    Ok(Signed::Settled(entry))
+}
+
+// What each signer signs. The id keeps a signature
+// from being replayed onto another pending transfer.
+pub fn message(pending: &db::PendingEntry) -> String {
+    format!(
+        "pending\t{}\t{}\t{}\t{}",
+        pending.id,
+        pending.source,
+        pending.destination,
+        pending.amount.milli()
+    )
+}
+
+// Public keys and signatures travel as hex, since
+// request args can't hold raw bytes.
+fn verify(pubkey: &str, message: &str, sig: &str) -> bool {
+    match (ledger::from_hex(pubkey), ledger::from_hex(sig)) {
+        (Some(pubkey), Some(sig)) => signature::UnparsedPublicKey::new(&signature::ED25519, pubkey)
+            .verify(message.as_bytes(), &sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn set_state(
+    conn: &rusqlite::Connection,
+    pending_id: u32,
+    state: &str,
+    ledger_id: Option<u32>,
+) -> Result<(), err::Resp> {
+    conn.execute_named(
+        "UPDATE pending SET state = :state, ledger_id = :ledger_id WHERE id = :id",
+        &[
+            (":state", &state),
+            (":ledger_id", &ledger_id),
+            (":id", &pending_id),
+        ],
+    )?;
+    Ok(())
+}
+
+fn pending_row(row: &rusqlite::Row) -> rusqlite::Result<db::PendingEntry> {
+    Ok(db::PendingEntry {
+        id: row.get(0)?,
+        source: row.get(1)?,
+        destination: row.get(2)?,
+        amount: row.get(3)?,
+        created: row.get(4)?,
+        expires: row.get(5)?,
+        state: row.get(6)?,
+        ledger_id: row.get(7)?,
+    })
+}
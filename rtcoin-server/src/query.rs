@@ -3,10 +3,16 @@
 // See LICENSE file for detailed license information.
 //
 
-use rusqlite::NO_PARAMS;
+use rusqlite::{types::ToSql, NO_PARAMS};
 
+use crate::amount::Amount;
+use crate::audit;
 use crate::db;
 use crate::err;
+use crate::escrow;
+use crate::ledger;
+use crate::order;
+use crate::user;
 
 // Accepts the comm of kind Whoami and arg of
 //     vec["user", (username)]
@@ -56,24 +62,295 @@ pub fn whoami(comm: db::Comm, conn: &rusqlite::Connection) {
     }
 }
 
+// Columns of the ledger table in the order ledger_row()
+// expects them. Select these rather than * so columns
+// added by later migrations don't shift anything.
+pub const LEDGER_COLUMNS: &str =
+    "id, type, timestamp, source, destination, amount, ledger_hash, receipt_id, receipt_hash,
+    source_id, destination_id, hash_version, batch_id, reverses";
+
+// Packs a single row, selected with LEDGER_COLUMNS,
+// into a db::LedgerEntry.
+pub fn ledger_row(row: &rusqlite::Row) -> rusqlite::Result<db::LedgerEntry> {
+    Ok(db::LedgerEntry {
+        id: row.get(0)?,
+        transaction_type: row.get(1)?,
+        timestamp: row.get(2)?,
+        source: row.get(3)?,
+        destination: row.get(4)?,
+        amount: row.get(5)?,
+        ledger_hash: row.get(6)?,
+        receipt_id: row.get(7)?,
+        receipt_hash: row.get(8)?,
+        source_id: row.get(9)?,
+        destination_id: row.get(10)?,
+        hash_version: row.get(11)?,
+        batch_id: row.get(12)?,
+        reverses: row.get(13)?,
+    })
+}
+
 // Takes the rows returned from a query and packs them into
 // a Vec of the db::LedgerEntry struct.
 pub fn to_ledger_entry(mut stmt: rusqlite::Statement) -> rusqlite::Result<Vec<db::LedgerEntry>> {
-    let rows = stmt.query_map(NO_PARAMS, |row| {
-        Ok(db::LedgerEntry {
-            id: row.get(0)?,
-            transaction_type: row.get(1)?,
-            timestamp: row.get(2)?,
-            source: row.get(3)?,
-            destination: row.get(4)?,
-            amount: row.get(5)?,
-            ledger_hash: row.get(6)?,
-            receipt_id: row.get(7)?,
-            receipt_hash: row.get(8)?,
-        })
-    })?;
+    let rows = stmt.query_map(NO_PARAMS, ledger_row)?;
 
     Ok(rows
         .map(|row| row.unwrap())
         .collect::<Vec<db::LedgerEntry>>())
 }
+
+// Handles requests generated inside the server itself.
+// The first arg names the job. Clients can't get here,
+// since conn::route() turns away Query requests.
+pub fn internal(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let job = comm.args().first().cloned().unwrap_or_default();
+    match &job[..] {
+        "audit" => audit::scheduled(comm, conn),
+        "orders" => order::due(comm, conn),
+        "advance" => order::advance(comm, conn),
+        "expire" => escrow::expire(comm, conn),
+        _ => {
+            log::warn!("Unknown internal request: {:?}", comm.args());
+            let details = format!("Unknown internal request: {}", job);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+        }
+    }
+}
+
+// Turns a client's row count into a LIMIT, where a count
+// of zero means no limit. SQLite treats a negative LIMIT
+// as no limit at all.
+pub fn limit_of(count: i64) -> i64 {
+    if count == 0 {
+        -1
+    } else {
+        count
+    }
+}
+
+// Rows per history page, unless the client asks
+// for something else, and the most it can ask for.
+const HISTORY_PAGE: i64 = 50;
+pub const HISTORY_MAX: i64 = 500;
+
+#[derive(Debug, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+    Both,
+}
+
+// Filters for a history request.
+#[derive(Debug)]
+pub struct History {
+    pub with: Option<String>,
+    pub direction: Direction,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+    pub cursor: Option<u32>,
+    pub limit: i64,
+}
+
+impl History {
+    // Reads the filters from key=value args. Anything
+    // left out doesn't filter at all.
+    pub fn parse(args: &[String]) -> Result<History, err::Resp> {
+        let mut history = History {
+            with: None,
+            direction: Direction::Both,
+            since: None,
+            until: None,
+            min: None,
+            max: None,
+            cursor: None,
+            limit: HISTORY_PAGE,
+        };
+
+        for arg in args {
+            let invalid = || {
+                let details = format!("Invalid filter: {}", arg);
+                err::Resp::new(3, "Invalid Request", &details)
+            };
+            let mut pair = arg.splitn(2, '=');
+            let (key, val) = match (pair.next(), pair.next()) {
+                (Some(key), Some(val)) if !val.is_empty() => (key, val),
+                _ => return Err(invalid()),
+            };
+
+            match key {
+                "with" => history.with = Some(val.to_string()),
+                "direction" => {
+                    history.direction = match val {
+                        "in" => Direction::In,
+                        "out" => Direction::Out,
+                        "both" => Direction::Both,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "since" => history.since = Some(ledger::parse_timestamp(val).ok_or_else(invalid)?),
+                "until" => history.until = Some(ledger::parse_timestamp(val).ok_or_else(invalid)?),
+                "min" => history.min = Some(val.parse::<Amount>().map_err(|_| invalid())?),
+                "max" => history.max = Some(val.parse::<Amount>().map_err(|_| invalid())?),
+                "cursor" => history.cursor = Some(val.parse::<u32>().map_err(|_| invalid())?),
+                "limit" => match val.parse::<i64>() {
+                    Ok(n) if n > 0 && n <= HISTORY_MAX => history.limit = n,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(history)
+    }
+
+    // Runs the filters against the given user's rows and
+    // returns one page, plus the cursor for the next one
+    // if there's more. The statement is only ever built
+    // from the fixed clauses below; every value the
+    // client sent goes in as a named parameter. Users are
+    // matched by id, so rows from before a rename turn up,
+    // and with= takes old names too.
+    pub fn query(
+        &self,
+        conn: &rusqlite::Connection,
+        name: &str,
+    ) -> Result<(Vec<db::LedgerEntry>, Option<u32>), err::Resp> {
+        let id = match user::id_of(conn, name)? {
+            Some(val) => val,
+            None => {
+                let details = format!("No such user: {}", name);
+                return Err(err::Resp::new(8, "Unknown User", &details));
+            }
+        };
+        let fetch = self.limit + 1;
+        let mut clauses = vec![match self.direction {
+            Direction::In => "destination_id = :id",
+            Direction::Out => "source_id = :id",
+            Direction::Both => "(source_id = :id OR destination_id = :id)",
+        }];
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":id", &id), (":limit", &fetch)];
+
+        // Counterparties that aren't users, like escrow,
+        // only have a name to go on.
+        let with_id = match &self.with {
+            Some(with) => user::id_of(conn, with)?,
+            None => None,
+        };
+        if let Some(with_id) = &with_id {
+            clauses.push(match self.direction {
+                Direction::In => "source_id = :with_id",
+                Direction::Out => "destination_id = :with_id",
+                Direction::Both => "(source_id = :with_id OR destination_id = :with_id)",
+            });
+            params.push((":with_id", with_id));
+        } else if let Some(with) = &self.with {
+            clauses.push(match self.direction {
+                Direction::In => "source = :with",
+                Direction::Out => "destination = :with",
+                Direction::Both => "(source = :with OR destination = :with)",
+            });
+            params.push((":with", with));
+        }
+        if let Some(since) = &self.since {
+            clauses.push("timestamp >= :since");
+            params.push((":since", since));
+        }
+        if let Some(until) = &self.until {
+            clauses.push("timestamp < :until");
+            params.push((":until", until));
+        }
+        if let Some(min) = &self.min {
+            clauses.push("amount >= :min");
+            params.push((":min", min));
+        }
+        if let Some(max) = &self.max {
+            clauses.push("amount <= :max");
+            params.push((":max", max));
+        }
+        if let Some(cursor) = &self.cursor {
+            clauses.push("id < :cursor");
+            params.push((":cursor", cursor));
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM ledger WHERE {} ORDER BY id DESC LIMIT :limit",
+            LEDGER_COLUMNS,
+            clauses.join(" AND ")
+        ))?;
+        let mut entries = stmt
+            .query_map_named(&params, ledger_row)?
+            .collect::<rusqlite::Result<Vec<db::LedgerEntry>>>()?;
+
+        // One more row than asked for means
+        // there's another page after this one.
+        let mut next = None;
+        if entries.len() as i64 > self.limit {
+            entries.truncate(self.limit as usize);
+            next = entries.last().map(|entry| entry.id);
+        }
+        Ok((entries, next))
+    }
+}
+
+// Lists a user's own ledger rows, newest first. Accepts
+// the args
+//     vec![user, password, (key=value...)]
+// where the keys are
+//     with=<user>             only transfers with them
+//     direction=in|out|both
+//     since=<RFC3339>         at or after this time
+//     until=<RFC3339>         before this time
+//     min=<amount>            at least this much
+//     max=<amount>            at most this much
+//     limit=<rows>            page size, up to HISTORY_MAX
+//     cursor=<id>             carry on from an earlier page
+// Each row is
+//     id  timestamp  type  source  destination  amount
+// If there's another page, the last row is instead
+//     cursor  <id>
+// which goes back in as cursor=<id>. Archived rows
+// aren't included.
+pub fn history(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, (key=value...)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let page = History::parse(&args[2..]).and_then(|history| history.query(conn, &args[0]));
+    match page {
+        Ok((entries, next)) => {
+            let mut rows = entries
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        entry.id,
+                        entry.timestamp,
+                        entry.transaction_type,
+                        entry.source,
+                        entry.destination,
+                        entry.amount
+                    )
+                })
+                .collect::<Vec<String>>();
+            if let Some(id) = next {
+                rows.push(format!("cursor\t{}", id));
+            }
+            comm.reply(db::Reply::Rows(rows));
+        }
+        Err(resp) => {
+            log::error!("History for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
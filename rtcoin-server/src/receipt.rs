@@ -0,0 +1,261 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use rusqlite::OptionalExtension;
+
+use crate::{archive, db, err, ledger, query, user};
+
+// Gives a ledger row its receipt: a random id, plus a hash
+// binding that id to the row's ledger_hash. Whoever holds
+// both can later have the server confirm that the row
+// still exists exactly as it did when the receipt was
+// issued. Returns the receipt id and hash.
+pub fn issue(
+    conn: &rusqlite::Connection,
+    id: i64,
+    ledger_hash: &str,
+) -> Result<(u32, String), err::Resp> {
+    let receipt_id = new_id(conn)?;
+    let hash = receipt_hash(receipt_id, ledger_hash);
+
+    conn.execute_named(
+        "UPDATE ledger SET receipt_id = :receipt_id, receipt_hash = :receipt_hash WHERE id = :id",
+        &[
+            (":receipt_id", &receipt_id),
+            (":receipt_hash", &hash),
+            (":id", &id),
+        ],
+    )?;
+
+    Ok((receipt_id, hash))
+}
+
+pub fn receipt_hash(receipt_id: u32, ledger_hash: &str) -> String {
+    let preimage = format!("{}\t{}", receipt_id, ledger_hash);
+    ledger::to_hex(digest::digest(&digest::SHA256, preimage.as_bytes()).as_ref())
+}
+
+// Same, for a batch: one receipt id bound to the
+// ledger_hash of every row in it, in ledger order.
+pub fn batch_hash(receipt_id: u32, ledger_hashes: &[String]) -> String {
+    let preimage = format!("{}\t{}", receipt_id, ledger_hashes.join("\t"));
+    ledger::to_hex(digest::digest(&digest::SHA256, preimage.as_bytes()).as_ref())
+}
+
+// Receipt ids are random so they can't be guessed from
+// the ledger id. Zero marks a row without a receipt, so
+// it's never handed out. Batch receipts come from the
+// same pool, so an id only ever means one thing.
+pub fn new_id(conn: &rusqlite::Connection) -> Result<u32, err::Resp> {
+    let rng = SystemRandom::new();
+    let mut buf = [0u8; 4];
+
+    loop {
+        rng.fill(&mut buf)
+            .map_err(|_| err::Resp::new(1, "Worker Error", "Could not generate a receipt id"))?;
+        let receipt_id = u32::from_be_bytes(buf);
+        if receipt_id == 0 {
+            continue;
+        }
+
+        let taken = conn.query_row_named(
+            "SELECT (SELECT COUNT(*) FROM ledger WHERE receipt_id = :receipt_id)
+                + (SELECT COUNT(*) FROM batches WHERE receipt_id = :receipt_id)",
+            &[(":receipt_id", &receipt_id)],
+            |row| row.get::<usize, i64>(0),
+        )?;
+        if taken == 0 {
+            return Ok(receipt_id);
+        }
+    }
+}
+
+// Confirms that a receipt matches the ledger row it was
+// issued for, and that the row itself still hashes to
+// what the chain says it should.
+pub fn check(
+    conn: &rusqlite::Connection,
+    receipt_id: u32,
+    hash: &str,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let entry = conn
+        .query_row_named(
+            &format!(
+                "SELECT {} FROM ledger WHERE receipt_id = :receipt_id",
+                query::LEDGER_COLUMNS
+            ),
+            &[(":receipt_id", &receipt_id)],
+            query::ledger_row,
+        )
+        .optional()?;
+
+    let entry = match entry {
+        Some(val) => val,
+        None => {
+            let details = format!("No ledger entry carries receipt {}", receipt_id);
+            return Err(err::Resp::new(11, "Receipt Mismatch", &details));
+        }
+    };
+
+    if entry.receipt_hash != hash || receipt_hash(receipt_id, &entry.ledger_hash) != hash {
+        let details = format!(
+            "Receipt {} does not match ledger entry {}",
+            receipt_id, entry.id
+        );
+        return Err(err::Resp::new(11, "Receipt Mismatch", &details));
+    }
+
+    let prev = ledger::previous_hash(conn, i64::from(entry.id))?;
+    if ledger::chain_hash(&prev, &entry) != entry.ledger_hash {
+        let details = format!("Ledger entry {} has been altered", entry.id);
+        return Err(err::Resp::new(10, "Chain Broken", &details));
+    }
+
+    Ok(entry)
+}
+
+// Confirms a batch receipt, if the id belongs to one,
+// and returns the batch and its rows. Rows that have
+// been archived are read back from the archive. Live or
+// not, every row has to still hash the way the chain
+// says it should.
+pub fn check_batch(
+    conn: &rusqlite::Connection,
+    receipt_id: u32,
+    hash: &str,
+) -> Result<Option<(db::BatchEntry, Vec<db::LedgerEntry>)>, err::Resp> {
+    let batch = conn
+        .query_row_named(
+            "SELECT id, source, created, total, count, receipt_id, receipt_hash
+                FROM batches WHERE receipt_id = :receipt_id",
+            &[(":receipt_id", &receipt_id)],
+            |row| {
+                Ok(db::BatchEntry {
+                    id: row.get(0)?,
+                    source: row.get(1)?,
+                    created: row.get(2)?,
+                    total: row.get(3)?,
+                    count: row.get(4)?,
+                    receipt_id: row.get(5)?,
+                    receipt_hash: row.get(6)?,
+                })
+            },
+        )
+        .optional()?;
+    let batch = match batch {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM ledger WHERE batch_id = :batch_id ORDER BY id",
+        query::LEDGER_COLUMNS
+    ))?;
+    let live = stmt
+        .query_map_named(&[(":batch_id", &batch.id)], query::ledger_row)?
+        .collect::<rusqlite::Result<Vec<db::LedgerEntry>>>()?;
+    let mut entries = if live.len() < batch.count as usize {
+        archive::batch_rows(conn, batch.id)?
+    } else {
+        Vec::new()
+    };
+    let archived = entries.len();
+    entries.extend(live);
+
+    let hashes = entries
+        .iter()
+        .map(|entry| entry.ledger_hash.clone())
+        .collect::<Vec<String>>();
+    if entries.len() != batch.count as usize
+        || batch.receipt_hash != hash
+        || batch_hash(receipt_id, &hashes) != hash
+    {
+        let details = format!("Receipt {} does not match batch {}", receipt_id, batch.id);
+        return Err(err::Resp::new(11, "Receipt Mismatch", &details));
+    }
+
+    for entry in &entries[archived..] {
+        let prev = ledger::previous_hash(conn, i64::from(entry.id))?;
+        if ledger::chain_hash(&prev, entry) != entry.ledger_hash {
+            let details = format!("Ledger entry {} has been altered", entry.id);
+            return Err(err::Resp::new(10, "Chain Broken", &details));
+        }
+    }
+
+    Ok(Some((batch, entries)))
+}
+
+// Lists the receipts for transfers a user sent or received,
+// newest first. This is how the receiving side of a transfer
+// gets hold of its receipt. Accepts the args
+//     vec![user, password, (count)]
+// A count of zero, or none at all, lists every receipt.
+pub fn list(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, (count)",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+
+    let count = match args.get(2).map(|n| n.parse::<i64>()) {
+        None => 0,
+        Some(Ok(n)) if n >= 0 => n,
+        Some(_) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", "Invalid count"));
+            return;
+        }
+    };
+
+    match receipts_for(conn, &args[0], count) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::error!("Receipt lookup for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn receipts_for(
+    conn: &rusqlite::Connection,
+    name: &str,
+    count: i64,
+) -> Result<Vec<String>, err::Resp> {
+    let limit = query::limit_of(count);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM ledger
+            WHERE source_id = :id OR destination_id = :id
+            ORDER BY id DESC LIMIT :limit",
+        query::LEDGER_COLUMNS
+    ))?;
+    let id = user::id_of(conn, name)?;
+    let rows = stmt.query_map_named(&[(":id", &id), (":limit", &limit)], query::ledger_row)?;
+
+    let mut out = Vec::new();
+    for row in rows {
+        let entry = row?;
+        out.push(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            entry.id,
+            entry.timestamp,
+            entry.source,
+            entry.destination,
+            entry.amount,
+            entry.receipt_id,
+            entry.receipt_hash
+        ));
+    }
+    Ok(out)
+}
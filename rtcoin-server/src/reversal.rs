@@ -0,0 +1,143 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use rusqlite::OptionalExtension;
+
+use crate::{archive, db, dispute, err, ledger, user};
+
+// Undoes a transfer. Accepts the args
+//     vec![user, password, ledger_id]
+// The recipient can always send a transfer back, since
+// it's their tcoin that moves. An admin can reverse any
+// transfer between two users. The original row is left
+// as it is; the reversal is a new row pointing at it.
+pub fn reverse(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, ledger_id",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let ledger_id = match args[2].parse::<u32>() {
+        Ok(val) => val,
+        Err(_) => {
+            let details = format!("Invalid ledger id: {}", args[2]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    match reverse_tx(conn, &args[0], ledger_id) {
+        Ok(entry) => {
+            log::info!(
+                "Ledger entry {} reversed by {} in ledger entry {}",
+                ledger_id,
+                args[0],
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Reversed ledger entry {}: {} tcoin back to {}. Receipt {}: {}",
+                ledger_id, entry.amount, entry.destination, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Reversal of ledger entry {} by {} failed: {}",
+                ledger_id,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn reverse_tx(
+    conn: &mut rusqlite::Connection,
+    name: &str,
+    ledger_id: u32,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let tx = conn.transaction()?;
+    let entry = dispute::live_entry(&tx, ledger_id)?;
+    let (_, recipient) = ledger::parties(&tx, ledger_id)?;
+    if (recipient.is_none() || recipient != user::id_of(&tx, name)?) && !user::is_admin(&tx, name)?
+    {
+        return Err(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Only the recipient or an admin can reverse a transfer",
+        ));
+    }
+    let reversal = apply(&tx, &entry)?;
+    tx.commit()?;
+    Ok(reversal)
+}
+
+// Sends a transfer's amount back where it came from and
+// links the new row to the original. Whoever calls this
+// has already decided it's allowed. Reversals themselves
+// can't be reversed, and no entry is reversed twice. If
+// the recipient can't cover it, nothing happens. Expects
+// to be handed a transaction.
+pub fn apply(
+    conn: &rusqlite::Connection,
+    entry: &db::LedgerEntry,
+) -> Result<db::LedgerEntry, err::Resp> {
+    if entry.transaction_type == "reversal" {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Reversals can't be reversed",
+        ));
+    }
+    if let Some(id) = reversed_by(conn, entry.id)? {
+        let details = format!(
+            "Ledger entry {} was already reversed in ledger entry {}",
+            entry.id, id
+        );
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    // Either side may have been renamed since, so the
+    // money goes back under the names they have now.
+    let (source, destination) = match ledger::parties(conn, entry.id)? {
+        (Some(src), Some(dest)) => (user::name_of(conn, src)?, user::name_of(conn, dest)?),
+        _ => {
+            return Err(err::Resp::new(
+                3,
+                "Invalid Request",
+                "Only transfers between users can be reversed",
+            ))
+        }
+    };
+
+    let links = ledger::Links {
+        batch_id: None,
+        reverses: Some(entry.id),
+    };
+    ledger::transfer_linked(conn, "reversal", &destination, &source, entry.amount, links)
+}
+
+// The ledger id of the row that reversed this one, if
+// any. The unique index on reverses only covers live
+// rows, so the archive is checked as well.
+pub fn reversed_by(conn: &rusqlite::Connection, ledger_id: u32) -> Result<Option<u32>, err::Resp> {
+    let live = conn
+        .query_row_named(
+            "SELECT id FROM ledger WHERE reverses = :ledger_id",
+            &[(":ledger_id", &ledger_id)],
+            |row| row.get::<usize, u32>(0),
+        )
+        .optional()?;
+    match live {
+        Some(id) => Ok(Some(id)),
+        None => archive::reversal_of(conn, ledger_id),
+    }
+}
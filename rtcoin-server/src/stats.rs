@@ -0,0 +1,231 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use chrono::prelude::*;
+use rusqlite::NO_PARAMS;
+
+use crate::{amount::Amount, db, err, escrow, treasury, user};
+
+// How many accounts the rich list shows unless asked
+// for a different number, and the most it will show.
+const TOP: u32 = 10;
+const TOP_MAX: u32 = 100;
+
+// How many days of activity the reports go back.
+const DAYS: i64 = 30;
+
+// Public numbers on the economy, for the community page.
+// Accepts the args
+//     vec![(count)]
+// and replies with the rows
+//     supply    <tcoin issued>
+//     accounts  <active accounts>
+//     gini      <coefficient over active balances>
+//     rich      <rank>  <name>  <balance>
+//     day       <YYYY-MM-DD>  <transfers>  <volume>
+// with count rich rows and a day row for each of the last
+// DAYS days that saw any transfers. Accounts that opted
+// out of the rich list are left off it, but still count
+// toward everything else. Like verify, there's no
+// authentication.
+pub fn stats(comm: db::Comm, conn: &rusqlite::Connection) {
+    let args = comm.args();
+    let top = match top(args.first()) {
+        Ok(val) => val,
+        Err(resp) => {
+            comm.reply_error(resp);
+            return;
+        }
+    };
+    match rows(conn, top, false) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::error!("Stats failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// The same report for admins. Accepts the args
+//     vec![admin, password, (count)]
+// The rich list includes accounts that opted out, and
+// these rows come after the rest:
+//     treasury  <tcoin held>
+//     escrow    <tcoin held>
+//     inactive  <deactivated accounts>
+//     frozen    <frozen accounts>
+pub fn report(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, (count)",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let top = match top(args.get(2)) {
+        Ok(val) => val,
+        Err(resp) => {
+            comm.reply_error(resp);
+            return;
+        }
+    };
+    match rows(conn, top, true) {
+        Ok(rows) => comm.reply(db::Reply::Rows(rows)),
+        Err(resp) => {
+            log::error!("Report for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn top(arg: Option<&String>) -> Result<u32, err::Resp> {
+    match arg.map(|val| val.parse::<u32>()) {
+        None => Ok(TOP),
+        Some(Ok(val)) if val <= TOP_MAX => Ok(val),
+        Some(_) => {
+            let details = format!("Count must be a number from 0 to {}", TOP_MAX);
+            Err(err::Resp::new(3, "Invalid Request", &details))
+        }
+    }
+}
+
+fn rows(conn: &rusqlite::Connection, top: u32, admin: bool) -> Result<Vec<String>, err::Resp> {
+    let balances = {
+        let mut stmt =
+            conn.prepare("SELECT balance FROM users WHERE active = 1 ORDER BY balance")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get::<usize, Amount>(0))?;
+        rows.collect::<rusqlite::Result<Vec<Amount>>>()?
+    };
+
+    let mut rows = vec![
+        format!("supply\t{}", treasury::issued(conn)?),
+        format!("accounts\t{}", balances.len()),
+        format!("gini\t{:.4}", gini(&balances)),
+    ];
+
+    let mut stmt = conn.prepare(
+        "SELECT name, balance FROM users
+            WHERE active = 1 AND (private = 0 OR :admin)
+            ORDER BY balance DESC, id LIMIT :top",
+    )?;
+    let rich = stmt
+        .query_map_named(&[(":admin", &admin), (":top", &top)], |row| {
+            Ok((row.get::<usize, String>(0)?, row.get::<usize, Amount>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(String, Amount)>>>()?;
+    for (rank, (name, balance)) in rich.iter().enumerate() {
+        rows.push(format!("rich\t{}\t{}\t{}", rank + 1, name, balance));
+    }
+
+    // A transfer is anything sent out of a user's
+    // account. Mints, grants and escrow payouts aren't
+    // anyone spending, so they're left out.
+    let since = (Utc::now() - chrono::Duration::days(DAYS))
+        .format("%Y-%m-%d")
+        .to_string();
+    let mut stmt = conn.prepare(
+        "SELECT substr(timestamp, 1, 10) AS day, COUNT(*), SUM(amount) FROM ledger
+            WHERE source_id IS NOT NULL AND timestamp >= :since
+            GROUP BY day ORDER BY day DESC",
+    )?;
+    let days = stmt
+        .query_map_named(&[(":since", &since)], |row| {
+            Ok(format!(
+                "day\t{}\t{}\t{}",
+                row.get::<usize, String>(0)?,
+                row.get::<usize, i64>(1)?,
+                row.get::<usize, Amount>(2)?
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    rows.extend(days);
+
+    if admin {
+        let count = |sql: &str| conn.query_row(sql, NO_PARAMS, |row| row.get::<usize, i64>(0));
+        rows.push(format!("treasury\t{}", treasury::balance(conn)?));
+        rows.push(format!("escrow\t{}", escrow::total_held(conn)?));
+        rows.push(format!(
+            "inactive\t{}",
+            count("SELECT COUNT(*) FROM users WHERE active = 0")?
+        ));
+        rows.push(format!(
+            "frozen\t{}",
+            count("SELECT COUNT(*) FROM users WHERE active = 1 AND frozen = 1")?
+        ));
+    }
+    Ok(rows)
+}
+
+// How unevenly tcoin is spread: 0 when everyone holds the
+// same, approaching 1 when one account holds it all.
+// Expects the balances sorted smallest first. The sums
+// are done in whole milli-tcoin so they can't drift; only
+// the final ratio is a float.
+pub fn gini(balances: &[Amount]) -> f64 {
+    let n = balances.len() as i128;
+    let total: i128 = balances.iter().map(|val| i128::from(val.milli())).sum();
+    if n == 0 || total <= 0 {
+        return 0.0;
+    }
+    let weighted: i128 = balances
+        .iter()
+        .enumerate()
+        .map(|(i, val)| (i as i128 + 1) * i128::from(val.milli()))
+        .sum();
+    (2 * weighted - (n + 1) * total) as f64 / (n * total) as f64
+}
+
+// Takes a user off the public rich list, or puts them
+// back on it. Accepts the args
+//     vec![user, password, private|public]
+pub fn privacy(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, private|public",
+        ));
+        return;
+    }
+    if !user::auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let private = match args[2].as_str() {
+        "private" => true,
+        "public" => false,
+        other => {
+            let details = format!("Expected private or public, got {}", other);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    let set = conn.execute_named(
+        "UPDATE users SET private = :private WHERE name = :name",
+        &[(":private", &private), (":name", &args[0])],
+    );
+    match set {
+        Ok(_) => {
+            log::info!("{} set their rich list privacy to {}", args[0], args[2]);
+            let reply = if private {
+                "You're off the public rich list"
+            } else {
+                "You're on the public rich list"
+            };
+            comm.reply(db::Reply::Info(reply.into()));
+        }
+        Err(error) => {
+            let resp: err::Resp = error.into();
+            log::error!("Setting privacy for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+extern crate test;
+
+use crate::amount::*;
+
+#[test]
+fn parse_and_display() {
+    let cases = vec![
+        ("1000", 1_000_000, "1000"),
+        ("12.5", 12_500, "12.5"),
+        ("0.001", 1, "0.001"),
+        (".25", 250, "0.25"),
+        ("7.", 7_000, "7"),
+        ("-3.75", -3_750, "-3.75"),
+    ];
+
+    for (input, milli, shown) in cases {
+        let amount = input.parse::<Amount>().unwrap();
+        assert_eq!(amount.milli(), milli);
+        assert_eq!(amount.to_string(), shown);
+    }
+}
+
+#[test]
+fn parse_rejects_garbage() {
+    let cases = vec![
+        "",
+        ".",
+        "abc",
+        "1.2345",
+        "1.2.3",
+        "+5",
+        "1e5",
+        "99999999999999999999",
+    ];
+    for input in cases {
+        assert!(input.parse::<Amount>().is_err(), "{} parsed", input);
+    }
+}
+
+#[test]
+fn checked_math() {
+    let one = Amount::from_tcoin(1);
+    let half = "0.5".parse::<Amount>().unwrap();
+
+    assert_eq!(one.checked_sub(half), Some(half));
+    assert_eq!(half.checked_add(half), Some(one));
+    assert_eq!(Amount::from_milli(i64::MAX).checked_add(one), None);
+    assert_eq!(Amount::from_milli(i64::MIN).checked_sub(one), None);
+    assert!(!Amount::ZERO.is_positive());
+}
+
+#[bench]
+fn bench_parse(b: &mut test::Bencher) {
+    b.iter(|| "1234.567".parse::<Amount>())
+}
@@ -0,0 +1,343 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use merkle::MerkleTree;
+use ring::digest;
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::archive::*;
+use crate::db;
+use crate::ledger;
+use crate::tests::{self, db_with_users};
+use crate::user;
+
+#[test]
+fn archive_closes_period() {
+    let path = "/tmp/rtcoinserver-archive-close-test.db";
+    let dir = "/tmp/rtcoinserver-archive-close-test";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    let last = ledger::transfer(&db.conn, "send", "bob", "alice", Amount::from_tcoin(2)).unwrap();
+
+    let entry = close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+    // The mint and both grants go along with the sends
+    assert_eq!((entry.first_id, entry.last_id), (1, 5));
+    assert_eq!(entry.hash, last.ledger_hash);
+    assert_eq!(ledger_rows(&db.conn), 0);
+
+    // The file holds one line per row, and its
+    // Merkle root is the one that was recorded
+    let body = fs::read_to_string(&entry.filename).unwrap();
+    let lines = body.lines().map(String::from).collect::<Vec<String>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[4].starts_with("5\tsend\t"));
+    let tree = MerkleTree::from_vec(&digest::SHA256, lines);
+    assert_eq!(tree.root_hash(), &entry.merkle_hash);
+
+    // Balances still reconcile without the rows, and the
+    // chain carries on from the archive
+    let (stored, computed) = ledger::reconcile(&db.conn, "alice").unwrap();
+    assert_eq!(stored, Amount::from_tcoin(997));
+    assert_eq!(stored, computed);
+
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(1)).unwrap();
+    assert_eq!(anchor(&db.conn).unwrap(), last.ledger_hash);
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Intact(1)
+    );
+
+    // Nothing left before the cutoff
+    assert_eq!(
+        close_before(&db.conn, "2000-01-01T00:00:00Z", dir)
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn archive_requires_admin() {
+    let path = "/tmp/rtcoinserver-archive-admin-test.db";
+    let mut db = db_with_users(path, &["alice", "bob"]);
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+
+    let (comm, reply) = tests::comm(
+        db::Kind::Archive,
+        &["alice", tests::PASS, "9999-01-01T00:00:00Z"],
+    );
+    close(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(ledger_rows(&db.conn), 4);
+
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'alice'", NO_PARAMS)
+        .unwrap();
+    let (comm, reply) = tests::comm(db::Kind::Archive, &["alice", tests::PASS, "yesterday"]);
+    close(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Invalid cutoff")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn proof_checks_against_root() {
+    let path = "/tmp/rtcoinserver-archive-proof-test.db";
+    let dir = "/tmp/rtcoinserver-archive-proof-test";
+    let db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let mut sent = Vec::new();
+    for (src, dest) in &[("alice", "bob"), ("bob", "carol"), ("carol", "alice")] {
+        sent.push(ledger::transfer(&db.conn, "send", src, dest, Amount::from_tcoin(1)).unwrap());
+    }
+    let archived = close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+    let live = ledger::transfer(&db.conn, "send", "alice", "carol", Amount::from_tcoin(1)).unwrap();
+
+    for entry in &sent {
+        let rows = proof(&db.conn, entry.receipt_id, &entry.receipt_hash).unwrap();
+        assert_eq!(
+            rows[0],
+            format!(
+                "archive\t{}\t{}",
+                archived.id,
+                ledger::to_hex(&archived.merkle_hash)
+            )
+        );
+
+        // Fold the leaf up through its siblings the
+        // way a client would
+        let leaf = rows[1].split_once('\t').unwrap().1;
+        assert_eq!(leaf.split('\t').nth(8), Some(&entry.receipt_hash[..]));
+        let mut hash = node(0x00, &[leaf.as_bytes()]);
+        for row in &rows[2..] {
+            let (side, sibling) = row.split_once('\t').unwrap();
+            let sibling = unhex(sibling);
+            hash = match side {
+                "left" => node(0x01, &[&sibling, &hash]),
+                "right" => node(0x01, &[&hash, &sibling]),
+                other => panic!("Unexpected row: {}", other),
+            };
+        }
+        assert_eq!(hash, archived.merkle_hash);
+    }
+
+    let forged = crate::receipt::receipt_hash(sent[0].receipt_id, ledger::GENESIS_HASH);
+    assert_eq!(
+        proof(&db.conn, sent[0].receipt_id, &forged)
+            .unwrap_err()
+            .code(),
+        11
+    );
+    assert_eq!(
+        proof(&db.conn, live.receipt_id, &live.receipt_hash)
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    // Change an amount in the archive file
+    let body = fs::read_to_string(&archived.filename).unwrap();
+    fs::write(&archived.filename, body.replacen("\t1000\t", "\t9000\t", 1)).unwrap();
+    assert_eq!(
+        proof(&db.conn, sent[1].receipt_id, &sent[1].receipt_hash)
+            .unwrap_err()
+            .code(),
+        13
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn archived_rows_rehash() {
+    let path = "/tmp/rtcoinserver-archive-rehash-test.db";
+    let dir = "/tmp/rtcoinserver-archive-rehash-test";
+    let db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    // Rows so far go back to hash version 2, the way an
+    // older server wrote them
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    db.conn
+        .execute("UPDATE ledger SET hash_version = 2", NO_PARAMS)
+        .unwrap();
+    rehash(&db.conn);
+
+    let payments = vec![
+        ("bob".to_string(), Amount::from_tcoin(1)),
+        ("carol".to_string(), Amount::from_tcoin(1)),
+    ];
+    crate::batch::pay(&db.conn, "alice", &payments).unwrap();
+    let sent = ledger::transfer(&db.conn, "send", "carol", "alice", Amount::from_tcoin(3)).unwrap();
+    crate::reversal::apply(&db.conn, &sent).unwrap();
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Intact(9)
+    );
+
+    let archived = close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+    let body = fs::read_to_string(&archived.filename).unwrap();
+    let rows = body
+        .lines()
+        .map(|line| parse(line).unwrap())
+        .collect::<Vec<db::LedgerEntry>>();
+    assert_eq!(rows[0].hash_version, 2);
+    assert_eq!(rows[6].batch_id, Some(1));
+    assert_eq!(rows[8].reverses, Some(sent.id));
+    assert_eq!(rows[8].destination_id, Some(3));
+    assert_eq!(verify_chain(&db.conn).unwrap(), ledger::Chain::Intact(9));
+
+    // The live chain carries on from the archive
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(1)).unwrap();
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Intact(1)
+    );
+
+    // Unlink the reversal and rebuild the Merkle root to
+    // match, and only the re-hash notices
+    let lines = body
+        .lines()
+        .map(|line| match line.rsplit_once('\t') {
+            Some((rest, _)) if line.starts_with("9\t") => format!("{}\t", rest),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>();
+    fs::write(&archived.filename, format!("{}\n", lines.join("\n"))).unwrap();
+    let tree = MerkleTree::from_vec(&digest::SHA256, lines);
+    db.conn
+        .execute_named(
+            "UPDATE archive SET merkle_hash = :merkle_hash",
+            &[(":merkle_hash", tree.root_hash())],
+        )
+        .unwrap();
+    assert_eq!(verify_chain(&db.conn).unwrap(), ledger::Chain::Broken(9));
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn balance_as_of_reaches_into_archives() {
+    let path = "/tmp/rtcoinserver-archive-asof-test.db";
+    let dir = "/tmp/rtcoinserver-archive-asof-test";
+    let db = db_with_users(path, &["alice", "bob", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    for (from, to, tcoin) in &[
+        ("alice", "bob", 10),
+        ("alice", "bob", 20),
+        ("bob", "alice", 5),
+    ] {
+        ledger::transfer(&db.conn, "send", from, to, Amount::from_tcoin(*tcoin)).unwrap();
+    }
+    db.conn
+        .execute(
+            "UPDATE ledger SET timestamp = '2020-01-0' || MAX(id - 3, 1) || 'T00:00:00Z'",
+            NO_PARAMS,
+        )
+        .unwrap();
+    user::change_name(&db.conn, "alice", "alicia").unwrap();
+    ledger::transfer(&db.conn, "send", "alicia", "bob", Amount::from_tcoin(1)).unwrap();
+    close_before(&db.conn, "2020-01-03T12:00:00Z", dir).unwrap();
+
+    // Partway into the archive, the end of it, and past it
+    // into the live rows
+    let as_of = |timestamp: &str| ledger::net_as_of(&db.conn, "alicia", timestamp).unwrap();
+    assert_eq!(as_of("2020-01-01T00:00:00Z"), (Amount::from_tcoin(1000), 4));
+    assert_eq!(as_of("2020-01-02T08:00:00Z"), (Amount::from_tcoin(990), 5));
+    assert_eq!(as_of("2020-01-03T12:00:00Z"), (Amount::from_tcoin(970), 6));
+    assert_eq!(as_of("2020-01-04T00:00:00Z"), (Amount::from_tcoin(975), 7));
+    assert_eq!(as_of("9999-01-01T00:00:00Z"), (Amount::from_tcoin(974), 8));
+    assert_eq!(
+        ledger::net_as_of(&db.conn, "alicia", "2019-12-31T00:00:00Z")
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    let ask = |args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(db::Kind::AsOf, args);
+        user::balance_as_of(comm, &db.conn);
+        reply.recv().unwrap()
+    };
+    match ask(&["bob", tests::PASS, "alicia", "2020-01-02T08:00:00Z"]) {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match ask(&["alicia", tests::PASS, "alice", "2020-01-01T08:00:00+01:00"]) {
+        db::Reply::Data(data) => assert_eq!(data, "1000\t4"),
+        other => panic!("Expected Data, got {:?}", other),
+    }
+    match ask(&["root", tests::PASS, "bob", "2020-01-03T12:00:00Z"]) {
+        db::Reply::Data(data) => assert_eq!(data, "1030\t6"),
+        other => panic!("Expected Data, got {:?}", other),
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+fn node(prefix: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[prefix]);
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.finish().as_ref().to_vec()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// Recomputes every row's hash in order, for tests that
+// change what a row's hash covers.
+fn rehash(conn: &rusqlite::Connection) {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM ledger ORDER BY id",
+            crate::query::LEDGER_COLUMNS
+        ))
+        .unwrap();
+    let rows = stmt
+        .query_map(NO_PARAMS, crate::query::ledger_row)
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<db::LedgerEntry>>>()
+        .unwrap();
+    let mut prev = ledger::GENESIS_HASH.to_string();
+    for row in rows {
+        let hash = ledger::chain_hash(&prev, &row);
+        conn.execute_named(
+            "UPDATE ledger SET ledger_hash = :hash WHERE id = :id",
+            &[(":hash", &hash), (":id", &row.id)],
+        )
+        .unwrap();
+        prev = hash;
+    }
+}
+
+fn ledger_rows(conn: &rusqlite::Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM ledger", NO_PARAMS, |row| row.get(0))
+        .unwrap()
+}
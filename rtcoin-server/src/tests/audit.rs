@@ -0,0 +1,131 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::audit::*;
+use crate::db;
+use crate::ledger;
+use crate::query;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn clean_ledger_passes_audit() {
+    let path = "/tmp/rtcoinserver-audit-clean-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    ledger::transfer(&db.conn, "send", "bob", "root", Amount::from_tcoin(2)).unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Audit, &["root", tests::PASS]);
+    audit(comm, &db.conn);
+    let rows = match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => rows,
+        other => panic!("Expected Rows, got {:?}", other),
+    };
+    assert_eq!(
+        rows,
+        vec![
+            "audit\tok\t0 problems found",
+            "supply\tok\t3000 tcoin held, 3000 tcoin issued",
+            "balance\tok\t3 users match their ledger history",
+            "escrow\tok\t0 tcoin held in escrow, ledger history says 0",
+            "archive\tok\t0 archived entries intact",
+            "chain\tok\t6 entries intact",
+            "users\tok\tEvery ledger entry names known users",
+        ]
+    );
+
+    // The nightly run goes through an internal query
+    let (comm, reply) = tests::comm(db::Kind::Query, &["audit"]);
+    query::internal(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(nightly) => assert_eq!(nightly, rows),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    let (comm, reply) = tests::comm(db::Kind::Audit, &["alice", tests::PASS]);
+    audit(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn audit_finds_problems() {
+    let path = "/tmp/rtcoinserver-audit-problems-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    ledger::append(&db.conn, "send", "alice", "mallory", Amount::from_tcoin(1)).unwrap();
+    db.conn
+        .execute(
+            "UPDATE users SET balance = balance + 7000 WHERE name = 'bob'",
+            NO_PARAMS,
+        )
+        .unwrap();
+    db.conn
+        .execute("UPDATE ledger SET amount = 9000 WHERE id = 4", NO_PARAMS)
+        .unwrap();
+
+    let failed = report(&db.conn)
+        .unwrap()
+        .into_iter()
+        .filter(|finding| !finding.ok)
+        .map(|finding| finding.to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(
+        failed,
+        vec![
+            "supply\tfail\t2007 tcoin held, 2000 tcoin issued",
+            "balance\tfail\talice holds 995 tcoin, ledger history says 990",
+            "balance\tfail\tbob holds 1012 tcoin, ledger history says 1009",
+            "chain\tfail\tFirst broken link at ledger entry 4",
+            "users\tfail\tLedger entry 5 names unknown user mallory",
+        ]
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn audit_catches_moved_ids() {
+    let path = "/tmp/rtcoinserver-audit-ids-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+
+    // Pointing bob's credit at alice changes whose balance
+    // it counts toward without touching any names
+    db.conn
+        .execute(
+            "UPDATE ledger SET destination_id = source_id WHERE id = 4",
+            NO_PARAMS,
+        )
+        .unwrap();
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Broken(4)
+    );
+    let failed = report(&db.conn)
+        .unwrap()
+        .into_iter()
+        .filter(|finding| !finding.ok)
+        .map(|finding| finding.to_string())
+        .collect::<Vec<String>>();
+    assert!(
+        failed.contains(&"users\tfail\tLedger entry 4 names bob but counts toward alice".into())
+    );
+
+    fs::remove_file(path).unwrap();
+}
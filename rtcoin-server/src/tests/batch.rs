@@ -0,0 +1,157 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::batch::*;
+use crate::db;
+use crate::ledger;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn batch_pays_everyone_or_nobody() {
+    let path = "/tmp/rtcoinserver-batch-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let args = ["alice", tests::PASS, "bob=10", "carol=2.5"];
+    let (comm, reply) = tests::comm(db::Kind::Batch, &args);
+    send(comm, &mut db.conn);
+    let receipt = match reply.recv().unwrap() {
+        db::Reply::Info(msg) => {
+            assert!(msg.starts_with("Sent 12.5 tcoin in 2 payments. Receipt "));
+            msg.rsplit("Receipt ").next().unwrap().replace(":", "")
+        }
+        other => panic!("Expected Info, got {:?}", other),
+    };
+    assert_eq!(tcoin(&db.conn), ["987.5", "1010", "1002.5"]);
+
+    let batch_ids: Vec<Option<u32>> = db
+        .conn
+        .prepare("SELECT batch_id FROM ledger ORDER BY id")
+        .unwrap()
+        .query_map(NO_PARAMS, |row| row.get(0))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    // The mint and the grants before it aren't batched
+    assert_eq!(batch_ids, vec![None, None, None, None, Some(1), Some(1)]);
+
+    // One bad payment sinks the lot
+    let failing: [&[&str]; 3] = [
+        &["alice", tests::PASS, "bob=10", "mallory=1"],
+        &["alice", tests::PASS, "bob=900", "carol=200"],
+        &["alice", tests::PASS, "bob=1", "bob=1"],
+    ];
+    for args in failing.iter() {
+        let (comm, reply) = tests::comm(db::Kind::Batch, args);
+        send(comm, &mut db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Error(_) => {}
+            other => panic!("Expected Error for {:?}, got {:?}", args, other),
+        }
+    }
+    assert_eq!(tcoin(&db.conn), ["987.5", "1010", "1002.5"]);
+
+    // The combined receipt checks out against both rows
+    let receipt = receipt.split_whitespace().collect::<Vec<&str>>();
+    let (comm, reply) = tests::comm(db::Kind::Verify, &receipt);
+    ledger::verify(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("matches batch 1: 12.5 tcoin from alice")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    db.conn
+        .execute("UPDATE ledger SET amount = 1000 WHERE id = 6", NO_PARAMS)
+        .unwrap();
+    let (comm, reply) = tests::comm(db::Kind::Verify, &receipt);
+    ledger::verify(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Chain Broken")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn batch_receipt_checks_after_archive() {
+    let path = "/tmp/rtcoinserver-batch-archive-test.db";
+    let dir = "/tmp/rtcoinserver-batch-archive-test";
+    let db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let payments = parse_payments(&db.conn, &["bob=10".into(), "carol=2.5".into()]).unwrap();
+    let batch = pay(&db.conn, "alice", &payments).unwrap();
+    let archived = crate::archive::close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+    ledger::transfer(&db.conn, "send", "bob", "carol", Amount::from_tcoin(1)).unwrap();
+
+    let receipt = [batch.receipt_id.to_string(), batch.receipt_hash.clone()];
+    let receipt = receipt.iter().map(|arg| &arg[..]).collect::<Vec<&str>>();
+    let (comm, reply) = tests::comm(db::Kind::Verify, &receipt);
+    ledger::verify(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("matches batch 1: 12.5 tcoin from alice")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    // Editing the archive file is caught by its root
+    let body = fs::read_to_string(&archived.filename).unwrap();
+    fs::write(&archived.filename, body.replacen("\t2500\t", "\t3500\t", 1)).unwrap();
+    let (comm, reply) = tests::comm(db::Kind::Verify, &receipt);
+    ledger::verify(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Archive Corrupt")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+fn tcoin(conn: &rusqlite::Connection) -> Vec<String> {
+    ["alice", "bob", "carol"]
+        .iter()
+        .map(|name| ledger::balance_of(conn, name).unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn payments_parse() {
+    let path = "/tmp/rtcoinserver-batch-parse-test.db";
+    let db = db_with_users(path, &["bob", "carol"]);
+    let parse = |args: &[&str]| {
+        let args = args
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<String>>();
+        parse_payments(&db.conn, &args)
+    };
+
+    let payments = parse(&["bob=1", "carol=0.5"]).unwrap();
+    assert_eq!(
+        payments,
+        vec![
+            ("bob".to_string(), Amount::from_tcoin(1)),
+            ("carol".to_string(), Amount::from_milli(500)),
+        ]
+    );
+    for bad in &["bob", "=1", "bob=", "bob=lots"] {
+        assert_eq!(parse(&[bad]).unwrap_err().code(), 3);
+    }
+    assert_eq!(parse(&["mallory=1"]).unwrap_err().code(), 8);
+
+    // Old names count as the same recipient
+    crate::user::change_name(&db.conn, "bob", "robert").unwrap();
+    assert_eq!(
+        parse(&["bob=1"]).unwrap(),
+        vec![("robert".to_string(), Amount::from_tcoin(1))]
+    );
+    assert_eq!(parse(&["robert=1", "bob=1"]).unwrap_err().code(), 3);
+
+    fs::remove_file(path).unwrap();
+}
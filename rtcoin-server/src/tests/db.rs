@@ -5,8 +5,14 @@
 
 extern crate test;
 
+use crate::amount::Amount;
+use crate::audit;
 use crate::db::*;
+use crate::ledger;
 use crate::query;
+use crate::treasury;
+
+use rusqlite::NO_PARAMS;
 
 use std::{fs, sync::mpsc, thread};
 
@@ -15,7 +21,7 @@ use std::{fs, sync::mpsc, thread};
 fn worker_thread_spawn_send_recv_query_rows() {
     let path = "/tmp/rtcoinserver-test.db";
     let (worker_tx, pipe) = mpsc::channel::<Comm>();
-    let db = DB::connect(path, "test".into(), pipe);
+    let mut db = DB::connect(path, "test".into(), pipe);
 
     assert!(fs::metadata(path).is_ok());
 
@@ -87,3 +93,92 @@ fn comm_kind() {
 fn comm_kind_bench(bn: &mut test::Bencher) {
     bn.iter(comm_kind)
 }
+
+#[test]
+fn migrate_old_schema() {
+    let path = "/tmp/rtcoinserver-migrate-test.db";
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path).unwrap();
+    }
+
+    // Lay out the tables the way they looked before
+    // amounts were fixed-point, under the same key
+    // DB::connect() will open them with.
+    let old = rusqlite::Connection::open(path).unwrap();
+    old.execute("PRAGMA key = 'test'", NO_PARAMS).unwrap();
+    old.execute_batch(
+        "CREATE TABLE ledger (
+            id INTEGER PRIMARY KEY AUTOINCREMENT, type TEXT NOT NULL,
+            timestamp TEXT NOT NULL, source TEXT NOT NULL,
+            destination TEXT NOT NULL, amount REAL NOT NULL,
+            ledger_hash TEXT NOT NULL, receipt_id INTEGER NOT NULL,
+            receipt_hash TEXT NOT NULL);
+        CREATE TABLE users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL,
+            pass TEXT NOT NULL, pubkey TEXT NOT NULL, balance REAL NOT NULL,
+            messages TEXT, created TEXT NOT NULL, last_login TEXT NOT NULL);
+        CREATE TABLE archive (
+            id INTEGER PRIMARY KEY AUTOINCREMENT, type TEXT NOT NULL,
+            timestamp TEXT NOT NULL, state TEXT NOT NULL,
+            merkle_hash TEXT NOT NULL, hash TEXT NOT NULL, filename TEXT NOT NULL);
+        INSERT INTO users (name, pass, pubkey, balance, created, last_login)
+            VALUES ('alice', '', '', 987.5, '', ''), ('bob', '', '', 1012.5, '', '');
+        INSERT INTO ledger (type, timestamp, source, destination, amount, ledger_hash, receipt_id, receipt_hash)
+            VALUES ('send', '2020-01-01T00:00:00Z', 'alice', 'bob', 12.5, '', 0, '');",
+    )
+    .unwrap();
+    old.close().unwrap();
+
+    let (_, pipe) = mpsc::channel::<Comm>();
+    let db = DB::connect(path, "test".into(), pipe);
+
+    assert_eq!(
+        ledger::balance_of(&db.conn, "alice").unwrap(),
+        "987.5".parse::<Amount>().unwrap()
+    );
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_milli(1_012_500), Amount::from_milli(1_012_500))
+    );
+
+    let amount_type: String = db
+        .conn
+        .query_row("SELECT typeof(amount) FROM ledger", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(amount_type, "integer");
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Intact(4)
+    );
+
+    let receipt_id: u32 = db
+        .conn
+        .query_row("SELECT receipt_id FROM ledger", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    assert_ne!(receipt_id, 0);
+
+    let admin: bool = db
+        .conn
+        .query_row(
+            "SELECT admin FROM users WHERE name = 'alice'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(!admin);
+
+    // The grants everyone was handed show up on the
+    // ledger, paid out of a treasury they were minted into
+    assert_eq!(
+        treasury::issued(&db.conn).unwrap(),
+        Amount::from_tcoin(2000)
+    );
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,171 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::dispute::*;
+use crate::ledger;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn admin_resolves_dispute() {
+    let path = "/tmp/rtcoinserver-dispute-admin-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let entry = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(10)).unwrap();
+
+    // Only the parties to a transfer can contest it
+    assert_eq!(
+        open(&db.conn, "carol", entry.id, "not mine")
+            .unwrap_err()
+            .code(),
+        12
+    );
+    let id = open(&db.conn, "alice", entry.id, "wrong bob").unwrap();
+    assert_eq!(
+        open(&db.conn, "bob", entry.id, "again").unwrap_err().code(),
+        3
+    );
+
+    let (comm, reply) = tests::comm(
+        db::Kind::Resolve,
+        &["carol", tests::PASS, &id.to_string(), "uphold"],
+    );
+    resolve(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // A failed admin check is reported as such, not taken
+    // to mean carol isn't an admin
+    db.conn
+        .execute(
+            "UPDATE users SET admin = 'yes' WHERE name = 'carol'",
+            NO_PARAMS,
+        )
+        .unwrap();
+    let (comm, reply) = tests::comm(
+        db::Kind::Resolve,
+        &["carol", tests::PASS, &id.to_string(), "uphold"],
+    );
+    resolve(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Query Error")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    db.conn
+        .execute("UPDATE users SET admin = 0 WHERE name = 'carol'", NO_PARAMS)
+        .unwrap();
+
+    // A dismissed dispute can be brought again
+    assert!(settle(&db.conn, "root", id, false).unwrap().is_none());
+    let id = open(&db.conn, "alice", entry.id, "really wrong bob").unwrap();
+
+    let (comm, reply) = tests::comm(
+        db::Kind::Resolve,
+        &["root", tests::PASS, &id.to_string(), "uphold"],
+    );
+    resolve(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with(&format!("Upheld dispute {}", id))),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let (kind, source, destination): (String, String, String) = db
+        .conn
+        .query_row(
+            "SELECT type, source, destination FROM ledger
+                WHERE id = (SELECT reversal_id FROM disputes WHERE state = 'upheld')",
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        (kind.as_str(), source.as_str(), destination.as_str()),
+        ("reversal", "bob", "alice")
+    );
+    assert_eq!(
+        ledger::reconcile(&db.conn, "alice").unwrap(),
+        (Amount::from_tcoin(1000), Amount::from_tcoin(1000))
+    );
+    assert_eq!(settle(&db.conn, "root", id, true).unwrap_err().code(), 3);
+    assert_eq!(
+        open(&db.conn, "alice", entry.id, "once more")
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn quorum_upholds_dispute() {
+    let path = "/tmp/rtcoinserver-dispute-quorum-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "dave", "erin", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let entry = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(10)).unwrap();
+    let id = open(&db.conn, "alice", entry.id, "wrong bob").unwrap();
+
+    // Brand new accounts can't second anything
+    assert_eq!(add_second(&db.conn, "carol", id).unwrap_err().code(), 12);
+    db.conn
+        .execute(
+            "UPDATE users SET created = 'Mon, 01 Jan 2018 00:00:00 +0000'",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+    // Nor can either side of the transfer
+    assert_eq!(add_second(&db.conn, "alice", id).unwrap_err().code(), 3);
+    assert_eq!(add_second(&db.conn, "bob", id).unwrap_err().code(), 12);
+
+    let (comm, reply) = tests::comm(db::Kind::Quorum, &["root", tests::PASS, "3", "30"]);
+    set_quorum(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with("Disputes are upheld by 3 seconds")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(quorum(&db.conn).unwrap(), 3);
+    assert_eq!(second_age(&db.conn).unwrap(), 30);
+
+    assert_eq!(add_second(&db.conn, "carol", id).unwrap().0, 1);
+    assert_eq!(add_second(&db.conn, "carol", id).unwrap_err().code(), 3);
+    assert_eq!(add_second(&db.conn, "dave", id).unwrap().0, 2);
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1010)
+    );
+
+    let (comm, reply) = tests::comm(db::Kind::Second, &["erin", tests::PASS, &id.to_string()]);
+    second(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("Quorum reached")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Intact(9)
+    );
+    assert_eq!(add_second(&db.conn, "root", id).unwrap_err().code(), 3);
+
+    fs::remove_file(path).unwrap();
+}
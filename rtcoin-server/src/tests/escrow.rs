@@ -0,0 +1,197 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::audit;
+use crate::db;
+use crate::escrow::*;
+use crate::ledger;
+use crate::query;
+use crate::tests::{self, db_with_users};
+use crate::user;
+
+#[test]
+fn escrow_releases_and_refunds() {
+    let path = "/tmp/rtcoinserver-escrow-settle-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let args = ["alice", tests::PASS, "bob", "10", "1d", "carol"];
+    let (comm, reply) = tests::comm(db::Kind::Escrow, &args);
+    hold(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with("Escrow 1: holding 10 tcoin for bob")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(balances(&db.conn), [990, 1000]);
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    // Only the sender or the arbiter can release
+    assert_eq!(settle(&db.conn, "bob", 1, true).unwrap_err().code(), 12);
+    let (comm, reply) = tests::comm(db::Kind::Release, &["alice", tests::PASS, "1"]);
+    release(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with("Escrow 1 released: 10 tcoin to bob")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(balances(&db.conn), [990, 1010]);
+    assert_eq!(settle(&db.conn, "alice", 1, true).unwrap_err().code(), 3);
+
+    // Only the recipient or the arbiter can refund
+    open(&db.conn, "alice", "bob", Amount::from_tcoin(5), 3600, None).unwrap();
+    open(
+        &db.conn,
+        "alice",
+        "bob",
+        Amount::from_tcoin(5),
+        3600,
+        Some("carol"),
+    )
+    .unwrap();
+    assert_eq!(settle(&db.conn, "alice", 2, false).unwrap_err().code(), 12);
+    settle(&db.conn, "bob", 2, false).unwrap();
+    settle(&db.conn, "carol", 3, false).unwrap();
+    assert_eq!(balances(&db.conn), [990, 1010]);
+
+    assert_eq!(
+        open(
+            &db.conn,
+            "alice",
+            "bob",
+            Amount::from_tcoin(5000),
+            3600,
+            None
+        )
+        .unwrap_err()
+        .code(),
+        7
+    );
+    assert_eq!(
+        open(
+            &db.conn,
+            "alice",
+            "bob",
+            Amount::from_tcoin(5),
+            3600,
+            Some("bob")
+        )
+        .unwrap_err()
+        .code(),
+        3
+    );
+
+    // An arbiter named by an old name still gets to settle
+    user::change_name(&db.conn, "carol", "caroline").unwrap();
+    let (id, _) = open(
+        &db.conn,
+        "alice",
+        "bob",
+        Amount::from_tcoin(5),
+        3600,
+        Some("carol"),
+    )
+    .unwrap();
+    settle(&db.conn, "caroline", id, false).unwrap();
+    assert_eq!(balances(&db.conn), [990, 1010]);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn expired_escrow_is_refunded() {
+    let path = "/tmp/rtcoinserver-escrow-expiry-test.db";
+    let mut db = db_with_users(path, &["alice", "bob"]);
+
+    open(&db.conn, "alice", "bob", Amount::from_tcoin(10), 3600, None).unwrap();
+    open(&db.conn, "alice", "bob", Amount::from_tcoin(20), 3600, None).unwrap();
+    db.conn
+        .execute(
+            "UPDATE escrow SET expires = '2000-01-01T00:00:00Z' WHERE id = 1",
+            NO_PARAMS,
+        )
+        .unwrap();
+    assert_eq!(settle(&db.conn, "alice", 1, true).unwrap_err().code(), 3);
+
+    let (comm, reply) = tests::comm(db::Kind::Query, &["expire"]);
+    query::internal(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows, vec!["1\t6"]),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+    assert_eq!(balances(&db.conn), [980, 1000]);
+    assert_eq!(total_held(&db.conn).unwrap(), Amount::from_tcoin(20));
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    // Nobody gets to be the escrow account
+    let (comm, reply) = tests::comm(db::Kind::Register, &[ACCOUNT, tests::PASS, "key"]);
+    user::register(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("reserved")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn frozen_source_holds_up_only_its_own_refund() {
+    let path = "/tmp/rtcoinserver-escrow-frozen-expiry-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    open(&db.conn, "carol", "bob", Amount::from_tcoin(10), 3600, None).unwrap();
+    open(&db.conn, "alice", "bob", Amount::from_tcoin(20), 3600, None).unwrap();
+    db.conn
+        .execute_batch(
+            "UPDATE escrow SET expires = '2000-01-01T00:00:00Z';
+            UPDATE users SET frozen = 1, frozen_incoming = 1 WHERE name = 'carol'",
+        )
+        .unwrap();
+
+    // Carol can't take her refund while she's frozen, but
+    // alice still gets hers
+    let (comm, reply) = tests::comm(db::Kind::Query, &["expire"]);
+    query::internal(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows, vec!["2\t7"]),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+    assert_eq!(balances(&db.conn), [1000, 1000]);
+    assert_eq!(total_held(&db.conn).unwrap(), Amount::from_tcoin(10));
+
+    // Once the freeze is lifted, the next tick refunds her
+    db.conn
+        .execute(
+            "UPDATE users SET frozen = 0, frozen_incoming = 0 WHERE name = 'carol'",
+            NO_PARAMS,
+        )
+        .unwrap();
+    let (comm, reply) = tests::comm(db::Kind::Query, &["expire"]);
+    query::internal(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows, vec!["1\t8"]),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::balance_of(&db.conn, "carol").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+    assert_eq!(total_held(&db.conn).unwrap(), Amount::from_milli(0));
+
+    fs::remove_file(path).unwrap();
+}
+
+fn balances(conn: &rusqlite::Connection) -> [i64; 2] {
+    let tcoin = |name| ledger::balance_of(conn, name).unwrap().milli() / 1000;
+    [tcoin("alice"), tcoin("bob")]
+}
@@ -0,0 +1,171 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::escrow;
+use crate::freeze::*;
+use crate::ledger;
+use crate::tests::{self, db_with_users};
+use crate::user;
+
+#[test]
+fn frozen_accounts_stay_put() {
+    let path = "/tmp/rtcoinserver-freeze-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let ask = |conn: &mut rusqlite::Connection, kind: db::Kind, args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(kind.clone(), args);
+        match kind {
+            db::Kind::Freeze => freeze(comm, conn),
+            db::Kind::Unfreeze => unfreeze(comm, conn),
+            _ => user::send(comm, conn),
+        }
+        reply.recv().unwrap()
+    };
+
+    // Moderators only, and there has to be a reason
+    match ask(
+        &mut db.conn,
+        db::Kind::Freeze,
+        &["bob", tests::PASS, "alice", "send", "spam"],
+    ) {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match ask(
+        &mut db.conn,
+        db::Kind::Freeze,
+        &["root", tests::PASS, "alice", "send"],
+    ) {
+        db::Reply::Error(err) => assert!(err.contains("Invalid Request")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match ask(
+        &mut db.conn,
+        db::Kind::Freeze,
+        &["root", tests::PASS, "alice", "send", "spamming", "bob"],
+    ) {
+        db::Reply::Info(msg) => assert_eq!(msg, "Froze alice. They can't go on sending tcoin"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    // alice can still be paid, but can't pay anyone, not
+    // even by way of escrow
+    match ask(
+        &mut db.conn,
+        db::Kind::Send,
+        &["alice", tests::PASS, "bob", "5"],
+    ) {
+        db::Reply::Error(err) => assert!(err.contains("Frozen Account")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(
+        escrow::open(&db.conn, "alice", "bob", Amount::from_tcoin(5), 60, None)
+            .unwrap_err()
+            .code(),
+        16
+    );
+    ledger::transfer(&db.conn, "send", "bob", "alice", Amount::from_tcoin(5)).unwrap();
+
+    match ask(
+        &mut db.conn,
+        db::Kind::Freeze,
+        &["root", tests::PASS, "alice", "all", "still", "at", "it"],
+    ) {
+        db::Reply::Info(msg) => assert!(msg.contains("sending or receiving")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::transfer(&db.conn, "send", "bob", "alice", Amount::from_tcoin(5))
+            .unwrap_err()
+            .code(),
+        16
+    );
+
+    match ask(
+        &mut db.conn,
+        db::Kind::Unfreeze,
+        &["root", tests::PASS, "alice", "sorted", "out"],
+    ) {
+        db::Reply::Info(msg) => assert_eq!(msg, "Unfroze alice"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    match ask(
+        &mut db.conn,
+        db::Kind::Unfreeze,
+        &["root", tests::PASS, "alice", "again"],
+    ) {
+        db::Reply::Error(err) => assert!(err.contains("isn't frozen")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match ask(
+        &mut db.conn,
+        db::Kind::Send,
+        &["alice", tests::PASS, "bob", "5"],
+    ) {
+        db::Reply::Info(_) => {}
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let log = {
+        let mut stmt = db
+            .conn
+            .prepare("SELECT action, reason, admin FROM freeze_log ORDER BY id")
+            .unwrap();
+        stmt.query_map(NO_PARAMS, |row| {
+            Ok(format!(
+                "{}\t{}\t{}",
+                row.get::<usize, String>(0)?,
+                row.get::<usize, String>(1)?,
+                row.get::<usize, String>(2)?
+            ))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .unwrap()
+    };
+    assert_eq!(
+        log,
+        vec![
+            "freeze send\tspamming bob\troot",
+            "freeze all\tstill at it\troot",
+            "unfreeze\tsorted out\troot",
+        ]
+    );
+
+    // A frozen account can't be closed to get its tcoin
+    // out, but an admin can close it into the treasury
+    match ask(
+        &mut db.conn,
+        db::Kind::Freeze,
+        &["root", tests::PASS, "bob", "send", "leaving"],
+    ) {
+        db::Reply::Info(_) => {}
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    let deactivate_as = |conn: &mut rusqlite::Connection, args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(db::Kind::Deactivate, args);
+        user::deactivate(comm, conn);
+        reply.recv().unwrap()
+    };
+    match deactivate_as(&mut db.conn, &["bob", tests::PASS, "bob"]) {
+        db::Reply::Error(err) => assert!(err.contains("Frozen Account")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match deactivate_as(&mut db.conn, &["root", tests::PASS, "bob"]) {
+        db::Reply::Info(msg) => assert!(msg.contains("Swept 1000 tcoin to treasury")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,195 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+use std::sync::mpsc;
+
+use ring::digest;
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db::{self, Comm, Kind, Reply, DB};
+use crate::idempotency;
+use crate::ledger;
+use crate::tests;
+use crate::treasury;
+
+#[test]
+fn keyed_send_runs_once() {
+    let path = "/tmp/rtcoinserver-idempotency-test.db";
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path).unwrap();
+    }
+    let (pipe, rx) = mpsc::channel::<Comm>();
+    let mut db = DB::connect(path, "test".into(), rx);
+    treasury::issue(&db.conn, Amount::from_tcoin(2000)).unwrap();
+
+    let requests: [(Kind, &[&str], Option<&str>); 8] = [
+        (Kind::Register, &["alice", tests::PASS, "key"], Some("r1")),
+        (Kind::Register, &["alice", tests::PASS, "key"], Some("r1")),
+        (Kind::Register, &["bob", tests::PASS, "key"], None),
+        (Kind::Send, &["alice", tests::PASS, "bob", "5"], Some("k1")),
+        (Kind::Send, &["alice", tests::PASS, "bob", "5"], Some("k1")),
+        (
+            Kind::Send,
+            &["alice", "wrongpassword", "bob", "5"],
+            Some("k1"),
+        ),
+        (Kind::Send, &["alice", tests::PASS, "bob", "6"], Some("k1")),
+        (Kind::Send, &["alice", tests::PASS, "bob", "5"], Some("k2")),
+    ];
+    let mut replies = Vec::new();
+    for (kind, args, key) in requests.iter() {
+        let (comm, reply) = tests::comm(kind.clone(), args);
+        pipe.send(comm.with_key(key.map(String::from))).unwrap();
+        replies.push(reply);
+    }
+    pipe.send(Comm::new(Some(Kind::Disconnect), None, None))
+        .unwrap();
+    db.worker_thread();
+
+    let replies = replies
+        .iter()
+        .map(|reply| match reply.recv().unwrap() {
+            Reply::Info(msg) | Reply::Error(msg) => msg,
+            other => panic!("Unexpected reply {:?}", other),
+        })
+        .collect::<Vec<String>>();
+
+    // The retried registration doesn't make a second alice
+    assert_eq!(replies[0], replies[1]);
+    let alices: i64 = db
+        .conn
+        .query_row_named(
+            "SELECT COUNT(*) FROM users WHERE name = :name",
+            &[(":name", &"alice")],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(alices, 1);
+
+    // The retried send gets the same receipt back, but a
+    // retry needs the password and the same request
+    assert!(replies[3].starts_with("Sent 5 tcoin to bob"));
+    assert_eq!(replies[3], replies[4]);
+    assert!(replies[5].contains("Authentication Error"));
+    assert!(replies[6].contains("already used"));
+    assert!(replies[7].starts_with("Sent 5 tcoin to bob"));
+    assert_ne!(replies[3], replies[7]);
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1010)
+    );
+
+    // Keys do nothing on requests that only read
+    assert!(!db::Kind::Balance.changes_state());
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn keyed_rename_replays() {
+    let path = "/tmp/rtcoinserver-idempotency-rename-test.db";
+    let (pipe, rx) = mpsc::channel::<Comm>();
+    let mut db = tests::db_with_users(path, &["alice"]);
+    db.pipe = rx;
+
+    let requests: [(&[&str], &str); 4] = [
+        (&["alice", "carol", tests::PASS], "n1"),
+        (&["alice", "carol", tests::PASS], "n1"),
+        (&["alice", "carol", "wrongpassword"], "n1"),
+        (&["alice", "dave", tests::PASS], "n1"),
+    ];
+    let mut replies = Vec::new();
+    for (args, key) in requests.iter() {
+        let (comm, reply) = tests::comm(Kind::Rename, args);
+        pipe.send(comm.with_key(Some(key.to_string()))).unwrap();
+        replies.push(reply);
+    }
+    pipe.send(Comm::new(Some(Kind::Disconnect), None, None))
+        .unwrap();
+    db.worker_thread();
+
+    let replies = replies
+        .iter()
+        .map(|reply| match reply.recv().unwrap() {
+            Reply::Info(msg) | Reply::Error(msg) => msg,
+            other => panic!("Unexpected reply {:?}", other),
+        })
+        .collect::<Vec<String>>();
+
+    // The retry is answered under the new name, needs the
+    // password, and can't change which name was asked for
+    assert_eq!(replies[0], "Username update successful");
+    assert_eq!(replies[0], replies[1]);
+    assert!(replies[2].contains("Authentication Error"));
+    assert!(replies[3].contains("already used"));
+
+    // Only the new name is ever kept, never the password
+    let kept: String = db
+        .conn
+        .query_row("SELECT fingerprint FROM idempotency", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(
+        kept,
+        ledger::to_hex(digest::digest(&digest::SHA256, b"alice\tcarol").as_ref())
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn interrupted_request_isnt_rerun() {
+    let path = "/tmp/rtcoinserver-idempotency-interrupted-test.db";
+    let (pipe, rx) = mpsc::channel::<Comm>();
+    let mut db = tests::db_with_users(path, &["alice", "bob"]);
+    db.pipe = rx;
+
+    // The worker claimed k1 and died before storing the
+    // reply, so there's no telling whether the send went
+    // through
+    let args = ["alice", tests::PASS, "bob", "5"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>();
+    idempotency::reserve(&db.conn, &Kind::Send, &args, "k1").unwrap();
+
+    let requests: [(&[&str], &str); 3] = [
+        (&["alice", tests::PASS, "bob", "5"], "k1"),
+        (&["alice", tests::PASS, "bob", "9000"], "k2"),
+        (&["alice", tests::PASS, "bob", "5"], "k2"),
+    ];
+    let mut replies = Vec::new();
+    for (args, key) in requests.iter() {
+        let (comm, reply) = tests::comm(Kind::Send, args);
+        pipe.send(comm.with_key(Some(key.to_string()))).unwrap();
+        replies.push(reply);
+    }
+    pipe.send(Comm::new(Some(Kind::Disconnect), None, None))
+        .unwrap();
+    db.worker_thread();
+
+    let replies = replies
+        .iter()
+        .map(|reply| match reply.recv().unwrap() {
+            Reply::Info(msg) | Reply::Error(msg) => msg,
+            other => panic!("Unexpected reply {:?}", other),
+        })
+        .collect::<Vec<String>>();
+
+    // The retry is refused rather than run again, while a
+    // key whose request failed is free to use again
+    assert!(replies[0].contains("cut off"));
+    assert!(replies[1].contains("Insufficient Funds"));
+    assert!(replies[2].starts_with("Sent 5 tcoin to bob"));
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1005)
+    );
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,122 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::invoice::*;
+use crate::ledger;
+use crate::message;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn payer_approves_or_declines_invoice() {
+    let path = "/tmp/rtcoinserver-invoice-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let args = ["bob", tests::PASS, "alice", "5", "3d", "you", "owe", "me"];
+    let (comm, reply) = tests::comm(db::Kind::Invoice, &args);
+    create(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert_eq!(msg, "Invoice 1: asked alice for 5 tcoin"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    open(
+        &db.conn,
+        "carol",
+        "alice",
+        Amount::from_tcoin(2),
+        3600,
+        None,
+    )
+    .unwrap();
+    open(
+        &db.conn,
+        "carol",
+        "alice",
+        Amount::from_tcoin(3),
+        3600,
+        None,
+    )
+    .unwrap();
+    db.conn
+        .execute(
+            "UPDATE invoices SET expires = '2000-01-01T00:00:00Z' WHERE id = 3",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Invoices, &["alice", tests::PASS]);
+    list(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => {
+            assert_eq!(rows.len(), 2);
+            assert!(rows[0].starts_with("1\tbob\t5\t"));
+            assert!(rows[0].ends_with("\tyou owe me"));
+            assert!(rows[1].starts_with("2\tcarol\t2\t"));
+        }
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    // Only the payer answers, and only while it's open
+    assert_eq!(settle(&db.conn, "bob", 1, true).unwrap_err().code(), 12);
+    assert_eq!(settle(&db.conn, "alice", 3, true).unwrap_err().code(), 3);
+
+    let (comm, reply) = tests::comm(db::Kind::Approve, &["alice", tests::PASS, "1"]);
+    approve(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with("Paid invoice 1: 5 tcoin to bob")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Decline, &["alice", tests::PASS, "2"]);
+    decline(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert_eq!(msg, "Declined invoice 2"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(settle(&db.conn, "alice", 1, true).unwrap_err().code(), 3);
+    assert_eq!(settle(&db.conn, "alice", 2, true).unwrap_err().code(), 3);
+
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_tcoin(1005), Amount::from_tcoin(1005))
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "carol").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+
+    // The memo travels with the payment
+    let (comm, reply) = tests::comm(db::Kind::Messages, &["bob", tests::PASS]);
+    message::list(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert!(rows[0].ends_with("\talice\tbob\t5\tyou owe me")),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    // An invoice nobody can cover fails without being used up
+    open(
+        &db.conn,
+        "bob",
+        "alice",
+        Amount::from_tcoin(5000),
+        3600,
+        None,
+    )
+    .unwrap();
+    assert_eq!(settle(&db.conn, "alice", 4, true).unwrap_err().code(), 7);
+    assert!(settle(&db.conn, "alice", 4, false).unwrap().is_none());
+
+    // A payer named by an old name gets it under their
+    // current one
+    crate::user::change_name(&db.conn, "alice", "alicia").unwrap();
+    let id = open(&db.conn, "bob", "alice", Amount::from_tcoin(1), 3600, None).unwrap();
+    assert!(settle(&db.conn, "alicia", id, true).unwrap().is_some());
+
+    fs::remove_file(path).unwrap();
+}
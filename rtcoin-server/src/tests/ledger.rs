@@ -0,0 +1,118 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::ledger::*;
+use crate::tests::db_with_users;
+
+#[test]
+fn transfer_moves_funds_and_appends_row() {
+    let path = "/tmp/rtcoinserver-ledger-transfer-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    let id = transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(250))
+        .unwrap()
+        .id;
+    assert_eq!(
+        balance_of(&db.conn, "alice").unwrap(),
+        Amount::from_tcoin(750)
+    );
+    assert_eq!(
+        balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1250)
+    );
+
+    let (source, destination, amount): (String, String, Amount) = db
+        .conn
+        .query_row_named(
+            "SELECT source, destination, amount FROM ledger WHERE id = :id",
+            &[(":id", &id)],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(source, "alice");
+    assert_eq!(destination, "bob");
+    assert_eq!(amount, Amount::from_tcoin(250));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn transfer_rejects_bad_requests() {
+    let path = "/tmp/rtcoinserver-ledger-reject-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    assert_eq!(
+        transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5000))
+            .unwrap_err()
+            .code(),
+        7
+    );
+    assert_eq!(
+        transfer(&db.conn, "send", "alice", "nobody", Amount::from_tcoin(5))
+            .unwrap_err()
+            .code(),
+        8
+    );
+    assert_eq!(
+        transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(-5))
+            .unwrap_err()
+            .code(),
+        3
+    );
+    assert_eq!(
+        transfer(&db.conn, "send", "alice", "alice", Amount::from_tcoin(5))
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    let rows: i64 = db
+        .conn
+        .query_row("SELECT COUNT(*) FROM ledger", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    // Just the mint and the two grants
+    assert_eq!(rows, 3);
+    assert_eq!(
+        balance_of(&db.conn, "alice").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn hash_chain_detects_tampering() {
+    let path = "/tmp/rtcoinserver-ledger-chain-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    assert_eq!(verify_chain(&db.conn).unwrap(), Chain::Intact(3));
+    for _ in 0..3 {
+        transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(1)).unwrap();
+    }
+    assert_eq!(verify_chain(&db.conn).unwrap(), Chain::Intact(6));
+
+    let first: String = db
+        .conn
+        .query_row(
+            "SELECT ledger_hash FROM ledger WHERE id = 1",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(first.len(), 64);
+    assert_ne!(first, GENESIS_HASH);
+
+    db.conn
+        .execute("UPDATE ledger SET amount = 100000 WHERE id = 2", NO_PARAMS)
+        .unwrap();
+    assert_eq!(verify_chain(&db.conn).unwrap(), Chain::Broken(2));
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,100 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::escrow;
+use crate::ledger;
+use crate::limit::*;
+use crate::reversal;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn limits_hold_within_a_day() {
+    let path = "/tmp/rtcoinserver-limit-test.db";
+    let db = db_with_users(path, &["alice", "bob", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let set_as = |args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(db::Kind::Limit, args);
+        set(comm, &db.conn);
+        reply.recv().unwrap()
+    };
+    match set_as(&["alice", tests::PASS, "*", "daily", "100"]) {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match set_as(&["root", tests::PASS, "*", "daily", "100"]) {
+        db::Reply::Info(msg) => assert_eq!(msg, "The daily limit for the default is now 100 tcoin"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    match set_as(&["root", tests::PASS, "alice", "transfer", "30"]) {
+        db::Reply::Info(msg) => assert_eq!(msg, "The transfer limit for alice is now 30 tcoin"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let send = |from: &str, to: &str, tcoin: i64| {
+        ledger::transfer(&db.conn, "send", from, to, Amount::from_tcoin(tcoin))
+    };
+    assert_eq!(send("alice", "bob", 31).unwrap_err().code(), 17);
+    send("alice", "bob", 30).unwrap();
+    let sent = send("alice", "bob", 30).unwrap();
+    escrow::open(&db.conn, "alice", "bob", Amount::from_tcoin(30), 60, None).unwrap();
+    assert_eq!(send("alice", "bob", 20).unwrap_err().code(), 17);
+
+    // bob is only held to the default, and handing back
+    // what alice sent doesn't count against it
+    send("bob", "alice", 100).unwrap();
+    assert_eq!(send("bob", "alice", 1).unwrap_err().code(), 17);
+    reversal::apply(&db.conn, &sent).unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Limits, &["alice", tests::PASS]);
+    show(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows, vec!["daily\t100\t90", "transfer\t30"]),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    // Lifting the default leaves alice's own limit
+    set_as(&["root", tests::PASS, "*", "daily", "none"]);
+    send("alice", "bob", 30).unwrap();
+    assert_eq!(send("alice", "bob", 31).unwrap_err().code(), 17);
+    set_as(&["root", tests::PASS, "alice", "transfer", "none"]);
+    send("alice", "bob", 500).unwrap();
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn archiving_doesnt_reset_the_day() {
+    let path = "/tmp/rtcoinserver-limit-archive-test.db";
+    let dir = "/tmp/rtcoinserver-limit-archive-test";
+    let db = db_with_users(path, &["alice", "bob"]);
+    db.conn
+        .execute(
+            "INSERT INTO settings (name, value) VALUES ('daily_limit', 100000)",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+    let send =
+        |tcoin: i64| ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(tcoin));
+    send(90).unwrap();
+    crate::archive::close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+
+    // What alice sent today is in the archive now, and
+    // still counts
+    assert_eq!(send(20).unwrap_err().code(), 17);
+    send(10).unwrap();
+
+    fs::remove_file(path).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,63 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use crate::db;
+use crate::message::*;
+use crate::tests::{self, db_with_users};
+use crate::user;
+
+#[test]
+fn send_stores_message() {
+    let path = "/tmp/rtcoinserver-message-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let sends: [&[&str]; 3] = [
+        &["alice", tests::PASS, "bob", "5", "for", "lunch"],
+        &["alice", tests::PASS, "bob", "1"],
+        &["bob", tests::PASS, "carol", "2", "thanks!"],
+    ];
+    for args in sends.iter() {
+        let (comm, reply) = tests::comm(db::Kind::Send, args);
+        user::send(comm, &mut db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Info(_) => {}
+            other => panic!("Expected Info, got {:?}", other),
+        }
+    }
+
+    let long = "x".repeat(MAX_LEN + 1);
+    let (comm, reply) = tests::comm(db::Kind::Send, &["alice", tests::PASS, "bob", "1", &long]);
+    user::send(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("limited to")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    let list_for = |args: &[&str]| -> Vec<Vec<String>> {
+        let (comm, reply) = tests::comm(db::Kind::Messages, args);
+        list(comm, &db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Rows(rows) => rows
+                .iter()
+                .map(|row| row.split('\t').map(String::from).collect())
+                .collect(),
+            other => panic!("Expected Rows, got {:?}", other),
+        }
+    };
+
+    let bob = list_for(&["bob", tests::PASS]);
+    assert_eq!(bob.len(), 2);
+    assert_eq!(bob[0][0], "7");
+    assert_eq!(bob[0][5], "thanks!");
+    assert_eq!(&bob[1][2..], ["alice", "bob", "5", "for lunch"]);
+
+    let last = list_for(&["bob", tests::PASS, "1"]);
+    assert_eq!(last, bob[..1].to_vec());
+    assert_eq!(list_for(&["alice", tests::PASS]).len(), 1);
+
+    fs::remove_file(path).unwrap();
+}
@@ -3,9 +3,66 @@
 // See LICENSE file for detailed license information.
 //
 
-mod err;
+use std::{fs, sync::mpsc};
+
+use crate::amount::Amount;
+use crate::db::{Comm, Kind, Reply, DB};
+use crate::user::register;
+
+mod amount;
+mod archive;
+mod audit;
+mod batch;
 mod db;
+mod dispute;
+mod err;
+mod escrow;
+mod freeze;
+mod idempotency;
+mod invoice;
 mod json;
+mod ledger;
+mod limit;
 mod logging;
+mod message;
+mod order;
+mod pending;
 mod query;
-mod user;
\ No newline at end of file
+mod receipt;
+mod reversal;
+mod stats;
+mod treasury;
+mod user;
+
+// Password used for every account the helpers create.
+pub const PASS: &str = "testpassword123";
+
+// Packs up a request along with the receiving end
+// of its reply channel.
+pub fn comm(kind: Kind, args: &[&str]) -> (Comm, mpsc::Receiver<Reply>) {
+    let (tx, rx) = mpsc::channel::<Reply>();
+    let args = args.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    (Comm::new(Some(kind), Some(args), Some(tx)), rx)
+}
+
+// Opens a fresh database at the given path, mints
+// just enough to cover their grants, and registers
+// each of the given users.
+pub fn db_with_users(path: &str, users: &[&str]) -> DB {
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path).unwrap();
+    }
+    let (_, rx) = mpsc::channel::<Comm>();
+    let mut db = DB::connect(path, "test".into(), rx);
+    if !users.is_empty() {
+        let grants = crate::treasury::DEFAULT_GRANT.milli() * users.len() as i64;
+        crate::treasury::issue(&db.conn, Amount::from_milli(grants)).unwrap();
+    }
+
+    for name in users {
+        let (comm, reply) = comm(Kind::Register, &[name, PASS, "testpubkey"]);
+        register(comm, &mut db.conn);
+        reply.recv().unwrap();
+    }
+    db
+}
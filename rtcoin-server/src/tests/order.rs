@@ -0,0 +1,211 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::{fs, sync::mpsc, thread};
+
+use chrono::prelude::*;
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::ledger;
+use crate::order::*;
+use crate::query;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn standing_order_pays_when_due() {
+    let path = "/tmp/rtcoinserver-order-pay-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+    let soon = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+
+    let args = [
+        "alice",
+        tests::PASS,
+        "bob",
+        "5",
+        "1d",
+        "never",
+        "weekly",
+        "stipend",
+    ];
+    let (comm, reply) = tests::comm(db::Kind::Order, &args);
+    create(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.starts_with("Standing order 1:")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    open(
+        &db.conn,
+        "alice",
+        "carol",
+        Amount::from_tcoin(2),
+        3600,
+        Some(&soon),
+        None,
+    )
+    .unwrap();
+    open(
+        &db.conn,
+        "bob",
+        "carol",
+        Amount::from_tcoin(5000),
+        3600,
+        None,
+        None,
+    )
+    .unwrap();
+
+    let due = internal(&mut db.conn, &["orders"]);
+    assert_eq!(due.len(), 3);
+    assert!(due[0].starts_with("1\talice\tbob\t5\t"));
+    assert!(due[0].ends_with("\tweekly stipend"));
+    let first_due = due[0].split('\t').nth(4).unwrap().to_string();
+
+    // Payments go through the worker as send requests
+    let mut db = scheduled(db);
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1005)
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "carol").unwrap(),
+        Amount::from_tcoin(1002)
+    );
+
+    // Nothing's due again until tomorrow, the second order
+    // ran past its end date, and the one bob couldn't cover
+    // waits for its next turn.
+    assert!(internal(&mut db.conn, &["orders"]).is_empty());
+    let advance = ["advance", "1", &first_due];
+    match &internal(&mut db.conn, &advance)[..] {
+        [msg] => assert!(msg.contains("isn't due")),
+        other => panic!("Expected one reply, got {:?}", other),
+    }
+    let message = db
+        .conn
+        .query_row("SELECT message FROM messages", NO_PARAMS, |row| {
+            row.get::<usize, String>(0)
+        })
+        .unwrap();
+    assert_eq!(message, "weekly stipend");
+
+    // As if the server stopped after paying but before the
+    // order moved on: the next pass doesn't pay it again
+    db.conn
+        .execute_named(
+            "UPDATE orders SET next_due = :next_due WHERE id = 1",
+            &[(":next_due", &first_due)],
+        )
+        .unwrap();
+    let mut db = scheduled(db);
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1005)
+    );
+    assert!(internal(&mut db.conn, &["orders"]).is_empty());
+
+    let (comm, reply) = tests::comm(db::Kind::Orders, &["alice", tests::PASS]);
+    list(comm, &db.conn);
+    let rows = match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => rows,
+        other => panic!("Expected Rows, got {:?}", other),
+    };
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].starts_with("2\tcarol\t2\t1h\t"));
+    assert!(rows[0].ends_with("\tfinished"));
+    assert!(rows[1].starts_with("1\tbob\t5\t1d\t"));
+    assert!(rows[1].ends_with("\tnever\tactive"));
+
+    assert_eq!(stop(&db.conn, "bob", 1).unwrap_err().code(), 12);
+    stop(&db.conn, "alice", 1).unwrap();
+    assert_eq!(stop(&db.conn, "alice", 1).unwrap_err().code(), 3);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn order_args_are_checked() {
+    let path = "/tmp/rtcoinserver-order-args-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    assert_eq!(parse_interval("12h"), Some(12 * 60 * 60));
+    assert_eq!(parse_interval("2w"), Some(14 * 24 * 60 * 60));
+    for bad in &["", "d", "0d", "-1d", "30m", "1y", "1.5d"] {
+        assert_eq!(parse_interval(bad), None);
+    }
+
+    let bad_orders: [&[&str]; 4] = [
+        &["alice", tests::PASS, "bob", "5", "fortnightly", "never"],
+        &[
+            "alice",
+            tests::PASS,
+            "bob",
+            "5",
+            "1d",
+            "2000-01-01T00:00:00Z",
+        ],
+        &["alice", tests::PASS, "alice", "5", "1d", "never"],
+        &["alice", tests::PASS, "mallory", "5", "1d", "never"],
+    ];
+    for args in bad_orders.iter() {
+        let (comm, reply) = tests::comm(db::Kind::Order, args);
+        create(comm, &db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Error(_) => {}
+            other => panic!("Expected Error for {:?}, got {:?}", args, other),
+        }
+    }
+
+    // An old name still finds its account
+    crate::user::change_name(&db.conn, "bob", "robert").unwrap();
+    let id = open(
+        &db.conn,
+        "alice",
+        "bob",
+        Amount::from_tcoin(5),
+        3600,
+        None,
+        None,
+    )
+    .unwrap();
+    let destination = db
+        .conn
+        .query_row_named(
+            "SELECT destination FROM orders WHERE id = :id",
+            &[(":id", &id)],
+            |row| row.get::<usize, String>(0),
+        )
+        .unwrap();
+    assert_eq!(destination, "robert");
+
+    fs::remove_file(path).unwrap();
+}
+
+// Runs one pass of the scheduler against a worker, and
+// hands the database back once it's done.
+fn scheduled(mut db: db::DB) -> db::DB {
+    let (pipe, rx) = mpsc::channel::<db::Comm>();
+    db.pipe = rx;
+    let worker = thread::spawn(move || {
+        db.worker_thread();
+        db
+    });
+    assert!(tick(&pipe));
+    pipe.send(db::Comm::new(Some(db::Kind::Disconnect), None, None))
+        .unwrap();
+    worker.join().unwrap()
+}
+
+// Sends an internal request the way the scheduler does.
+fn internal(conn: &mut rusqlite::Connection, args: &[&str]) -> Vec<String> {
+    let (comm, reply) = tests::comm(db::Kind::Query, args);
+    query::internal(comm, conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => rows,
+        db::Reply::Info(msg) | db::Reply::Error(msg) => vec![msg],
+        other => panic!("Unexpected reply {:?}", other),
+    }
+}
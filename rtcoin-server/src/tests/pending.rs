@@ -0,0 +1,292 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use crate::amount::Amount;
+use crate::db;
+use crate::ledger;
+use crate::pending::*;
+use crate::tests::{self, db_with_users};
+
+const DAY: i64 = 24 * 60 * 60;
+
+#[test]
+fn recipient_signature_settles_transfer() {
+    let path = "/tmp/rtcoinserver-pending-settle-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+    let bob = keypair(&db.conn, "bob");
+    let carol = keypair(&db.conn, "carol");
+
+    let (comm, reply) = tests::comm(
+        db::Kind::Propose,
+        &["alice", tests::PASS, "bob", "10", "1d"],
+    );
+    propose(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.ends_with("awaiting signatures from bob")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    let msg = "pending\t1\talice\tbob\t10000";
+
+    // Wrong key, then the wrong signer
+    assert_eq!(
+        add_signature(&db.conn, "bob", 1, &sig(&carol, msg))
+            .unwrap_err()
+            .code(),
+        14
+    );
+    assert_eq!(
+        add_signature(&db.conn, "carol", 1, &sig(&carol, msg))
+            .unwrap_err()
+            .code(),
+        12
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+
+    let (comm, reply) = tests::comm(db::Kind::Sign, &["bob", tests::PASS, "1", &sig(&bob, msg)]);
+    sign(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("settled in ledger entry 5")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_tcoin(1010), Amount::from_tcoin(1010))
+    );
+    assert_eq!(
+        add_signature(&db.conn, "bob", 1, &sig(&bob, msg))
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn cosigned_transfer_waits_for_everyone() {
+    let path = "/tmp/rtcoinserver-pending-cosign-test.db";
+    let db = db_with_users(path, &["alice", "bob", "carol", "dave"]);
+    let bob = keypair(&db.conn, "bob");
+    let dave = keypair(&db.conn, "dave");
+    let signers = vec!["bob".to_string(), "dave".to_string()];
+
+    assert_eq!(
+        open(
+            &db.conn,
+            "alice",
+            "carol",
+            Amount::from_tcoin(5),
+            &["alice".into()],
+            DAY
+        )
+        .unwrap_err()
+        .code(),
+        3
+    );
+
+    let id = open(
+        &db.conn,
+        "alice",
+        "carol",
+        Amount::from_tcoin(5),
+        &signers,
+        DAY,
+    )
+    .unwrap();
+    let msg = format!("pending\t{}\talice\tcarol\t5000", id);
+    match add_signature(&db.conn, "bob", id, &sig(&bob, &msg)).unwrap() {
+        Signed::Waiting(1) => {}
+        other => panic!("Expected one signature to go, got {:?}", other),
+    }
+    match add_signature(&db.conn, "dave", id, &sig(&dave, &msg)).unwrap() {
+        Signed::Settled(entry) => assert_eq!(entry.transaction_type, "signed"),
+        other => panic!("Expected a settled transfer, got {:?}", other),
+    }
+
+    // Declining stops it for good
+    let id = open(
+        &db.conn,
+        "alice",
+        "carol",
+        Amount::from_tcoin(5),
+        &signers,
+        DAY,
+    )
+    .unwrap();
+    match add_signature(&db.conn, "dave", id, "decline").unwrap() {
+        Signed::Declined => {}
+        other => panic!("Expected a declined transfer, got {:?}", other),
+    }
+    let msg = format!("pending\t{}\talice\tcarol\t5000", id);
+    assert_eq!(
+        add_signature(&db.conn, "bob", id, &sig(&bob, &msg))
+            .unwrap_err()
+            .code(),
+        3
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "carol").unwrap(),
+        Amount::from_tcoin(1005)
+    );
+
+    // Old names find their accounts, which sign under the
+    // names they have now
+    crate::user::change_name(&db.conn, "dave", "david").unwrap();
+    let id = open(
+        &db.conn,
+        "alice",
+        "carol",
+        Amount::from_tcoin(5),
+        &signers,
+        DAY,
+    )
+    .unwrap();
+    let names = db
+        .conn
+        .prepare("SELECT name FROM pending_signers WHERE pending_id = :id ORDER BY rowid")
+        .unwrap()
+        .query_map_named(&[(":id", &id)], |row| row.get::<usize, String>(0))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .unwrap();
+    assert_eq!(names, ["bob", "david"]);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn signing_policy_holds_transfers() {
+    let path = "/tmp/rtcoinserver-pending-policy-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "dave", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    // Nobody else can give alice a policy, and she can't
+    // sign for herself
+    for args in &[
+        ["bob", tests::PASS, "alice", "dave"],
+        ["alice", tests::PASS, "alice", "alice"],
+    ] {
+        let (comm, reply) = tests::comm(db::Kind::Signers, args);
+        set_signers(comm, &mut db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Error(_) => {}
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    let (comm, reply) = tests::comm(
+        db::Kind::Signers,
+        &["alice", tests::PASS, "alice", "bob", "dave"],
+    );
+    set_signers(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.ends_with("signatures from bob, dave")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    // Direct sends are refused, and proposals go to the
+    // policy's signers rather than the recipient
+    assert_eq!(
+        ledger::transfer(&db.conn, "send", "alice", "carol", Amount::from_tcoin(5))
+            .unwrap_err()
+            .code(),
+        12
+    );
+    let (comm, reply) = tests::comm(
+        db::Kind::Propose,
+        &["alice", tests::PASS, "carol", "5", "1d"],
+    );
+    propose(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.ends_with("awaiting signatures from bob, dave")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    // Only an admin can lift it once it's set
+    let (comm, reply) = tests::comm(db::Kind::Signers, &["alice", tests::PASS, "alice", "none"]);
+    set_signers(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(msg) => assert!(msg.contains("only an admin can change")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Signers, &["root", tests::PASS, "alice", "none"]);
+    set_signers(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.ends_with("no longer need signatures")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert!(policy(&db.conn, "alice").unwrap().is_empty());
+    ledger::transfer(&db.conn, "send", "alice", "carol", Amount::from_tcoin(5)).unwrap();
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn expired_transfer_cant_be_signed() {
+    let path = "/tmp/rtcoinserver-pending-expiry-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+    let bob = keypair(&db.conn, "bob");
+
+    let id = open(
+        &db.conn,
+        "alice",
+        "bob",
+        Amount::from_tcoin(5),
+        &["bob".into()],
+        -1,
+    )
+    .unwrap();
+    let msg = format!("pending\t{}\talice\tbob\t5000", id);
+    match add_signature(&db.conn, "bob", id, &sig(&bob, &msg)).unwrap() {
+        Signed::Expired => {}
+        other => panic!("Expected an expired transfer, got {:?}", other),
+    }
+    assert_eq!(
+        add_signature(&db.conn, "bob", id, &sig(&bob, &msg))
+            .unwrap_err()
+            .code(),
+        3
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+// Gives a user a fresh Ed25519 key pair and
+// registers its public half.
+fn keypair(conn: &rusqlite::Connection, name: &str) -> Ed25519KeyPair {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    conn.execute_named(
+        "UPDATE users SET pubkey = :pubkey WHERE name = :name",
+        &[
+            (":pubkey", &ledger::to_hex(pair.public_key().as_ref())),
+            (":name", &name),
+        ],
+    )
+    .unwrap();
+    pair
+}
+
+fn sig(pair: &Ed25519KeyPair, msg: &str) -> String {
+    ledger::to_hex(pair.sign(msg.as_bytes()).as_ref())
+}
@@ -5,8 +5,11 @@
 
 extern crate test;
 
+use crate::amount::Amount;
 use crate::db;
+use crate::ledger;
 use crate::query::*;
+use crate::tests::{self, db_with_users};
 use std::fs;
 use std::sync::mpsc;
 
@@ -38,6 +41,81 @@ fn expect_no_rows() {
     fs::remove_file(path).unwrap();
 }
 
+#[test]
+fn history_filters_and_pages() {
+    let path = "/tmp/rtcoinserver-query-history-test.db";
+    let db = db_with_users(path, &["alice", "bob", "carol"]);
+    let transfers = [
+        ("alice", "bob", 1),
+        ("bob", "alice", 2),
+        ("alice", "carol", 3),
+        ("carol", "alice", 4),
+        ("alice", "bob", 5),
+    ];
+    for (src, dest, amount) in &transfers {
+        ledger::transfer(&db.conn, "send", src, dest, Amount::from_tcoin(*amount)).unwrap();
+    }
+
+    let page = |name: &str, filters: &[&str]| -> Vec<String> {
+        let mut args = vec![name, tests::PASS];
+        args.extend_from_slice(filters);
+        let (comm, reply) = tests::comm(db::Kind::History, &args);
+        history(comm, &db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Rows(rows) => rows
+                .iter()
+                .map(|row| row.split('\t').next().unwrap().to_string())
+                .collect(),
+            db::Reply::Error(err) => vec![err],
+            other => panic!("Expected Rows, got {:?}", other),
+        }
+    };
+
+    // Everyone's grant shows up below the sends
+    assert_eq!(page("alice", &[]), vec!["9", "8", "7", "6", "5", "2"]);
+    assert_eq!(page("bob", &[]), vec!["9", "6", "5", "3"]);
+    assert_eq!(page("alice", &["direction=out"]), vec!["9", "7", "5"]);
+    assert_eq!(page("alice", &["with=bob"]), vec!["9", "6", "5"]);
+    assert_eq!(page("alice", &["direction=in", "with=carol"]), vec!["8"]);
+    assert_eq!(page("alice", &["min=2", "max=4"]), vec!["8", "7", "6"]);
+    assert!(page("alice", &["since=9999-01-01T00:00:00Z"]).is_empty());
+    assert!(page("alice", &["until=2000-01-01T00:00:00+01:00"]).is_empty());
+
+    // Walk it two at a time
+    assert_eq!(page("alice", &["limit=2"]), vec!["9", "8", "cursor"]);
+    let rows = {
+        let (comm, reply) = tests::comm(db::Kind::History, &["alice", tests::PASS, "limit=2"]);
+        history(comm, &db.conn);
+        match reply.recv().unwrap() {
+            db::Reply::Rows(rows) => rows,
+            other => panic!("Expected Rows, got {:?}", other),
+        }
+    };
+    let first = rows[0].split('\t').collect::<Vec<&str>>();
+    assert_eq!(
+        [first[0], first[2], first[3], first[4], first[5]],
+        ["9", "send", "alice", "bob", "5"]
+    );
+    assert_eq!(rows[2], "cursor\t8");
+    assert_eq!(
+        page("alice", &["limit=2", "cursor=8"]),
+        vec!["7", "6", "cursor"]
+    );
+    assert_eq!(page("alice", &["limit=2", "cursor=6"]), vec!["5", "2"]);
+
+    for bad in &[
+        "foo=bar",
+        "limit=0",
+        "direction=sideways",
+        "min=",
+        "since=yesterday",
+    ] {
+        assert!(page("alice", &[bad])[0].contains("Invalid filter"));
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
 #[ignore]
 #[bench]
 fn bench_whoami(b: &mut test::Bencher) {
@@ -48,6 +126,8 @@ fn bench_whoami(b: &mut test::Bencher) {
         kind: Some(db::Kind::Whoami),
         args: Some(vec!["testuser".into()]),
         origin: Some(otx),
+        key: None,
+        internal: false,
     };
     b.iter(|| whoami(comm.clone(), &db.conn))
 }
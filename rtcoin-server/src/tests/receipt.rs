@@ -0,0 +1,91 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::ledger;
+use crate::receipt::*;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn receipt_checks_against_ledger() {
+    let path = "/tmp/rtcoinserver-receipt-check-test.db";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    let entry = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    assert_ne!(entry.receipt_id, 0);
+    assert_eq!(
+        entry.receipt_hash,
+        receipt_hash(entry.receipt_id, &entry.ledger_hash)
+    );
+
+    let checked = check(&db.conn, entry.receipt_id, &entry.receipt_hash).unwrap();
+    assert_eq!(checked.id, entry.id);
+
+    let forged = receipt_hash(entry.receipt_id, ledger::GENESIS_HASH);
+    assert_eq!(
+        check(&db.conn, entry.receipt_id, &forged)
+            .unwrap_err()
+            .code(),
+        11
+    );
+    assert_eq!(
+        check(
+            &db.conn,
+            entry.receipt_id.wrapping_add(1),
+            &entry.receipt_hash
+        )
+        .unwrap_err()
+        .code(),
+        11
+    );
+
+    // Quietly change who the money went to
+    db.conn
+        .execute("UPDATE ledger SET destination = 'carol'", NO_PARAMS)
+        .unwrap();
+    assert_eq!(
+        check(&db.conn, entry.receipt_id, &entry.receipt_hash)
+            .unwrap_err()
+            .code(),
+        10
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn recipient_can_list_receipts() {
+    let path = "/tmp/rtcoinserver-receipt-list-test.db";
+    let db = db_with_users(path, &["alice", "bob", "carol"]);
+
+    let first = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(1)).unwrap();
+    ledger::transfer(&db.conn, "send", "alice", "carol", Amount::from_tcoin(2)).unwrap();
+    let last = ledger::transfer(&db.conn, "send", "carol", "bob", Amount::from_tcoin(3)).unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Receipts, &["bob", tests::PASS]);
+    list(comm, &db.conn);
+    let rows = match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => rows,
+        other => panic!("Expected Rows, got {:?}", other),
+    };
+    // Bob's grant comes with a receipt too
+    assert_eq!(rows.len(), 3);
+    assert!(rows[0].ends_with(&format!("{}\t{}", last.receipt_id, last.receipt_hash)));
+    assert!(rows[1].ends_with(&format!("{}\t{}", first.receipt_id, first.receipt_hash)));
+
+    let (comm, reply) = tests::comm(db::Kind::Receipts, &["bob", tests::PASS, "1"]);
+    list(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows.len(), 1),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
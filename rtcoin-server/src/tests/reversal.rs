@@ -0,0 +1,128 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::audit;
+use crate::db;
+use crate::dispute;
+use crate::ledger;
+use crate::reversal::*;
+use crate::tests::{self, db_with_users};
+
+#[test]
+fn recipient_or_admin_reverses_once() {
+    let path = "/tmp/rtcoinserver-reversal-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let sent = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(10)).unwrap();
+    let other =
+        ledger::transfer(&db.conn, "send", "alice", "carol", Amount::from_tcoin(5)).unwrap();
+
+    let reverse_as = |conn: &mut rusqlite::Connection, name: &str, id: u32| -> db::Reply {
+        let id = id.to_string();
+        let (comm, reply) = tests::comm(db::Kind::Reverse, &[name, tests::PASS, &id]);
+        reverse(comm, conn);
+        reply.recv().unwrap()
+    };
+
+    // The sender can't claw it back on their own
+    match reverse_as(&mut db.conn, "alice", sent.id) {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    let msg = format!("Reversed ledger entry {}: 10 tcoin back to alice.", sent.id);
+    match reverse_as(&mut db.conn, "bob", sent.id) {
+        db::Reply::Info(reply) => assert!(reply.starts_with(&msg)),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    let reversal = reversed_by(&db.conn, sent.id).unwrap().unwrap();
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_tcoin(1000), Amount::from_tcoin(1000))
+    );
+
+    // Not twice, not the reversal itself, and not by way
+    // of a dispute either
+    match reverse_as(&mut db.conn, "root", sent.id) {
+        db::Reply::Error(err) => assert!(err.contains("already reversed")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match reverse_as(&mut db.conn, "alice", reversal) {
+        db::Reply::Error(err) => assert!(err.contains("can't be reversed")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(
+        dispute::open(&db.conn, "alice", sent.id, "oops")
+            .unwrap_err()
+            .code(),
+        3
+    );
+
+    // An admin can reverse someone else's transfer, but
+    // only between users
+    match reverse_as(&mut db.conn, "root", other.id) {
+        db::Reply::Info(reply) => assert!(reply.contains("5 tcoin back to alice")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    match reverse_as(&mut db.conn, "root", 2) {
+        db::Reply::Error(err) => assert!(err.contains("between users")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    assert_eq!(
+        ledger::balance_of(&db.conn, "alice").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    // The link is hashed with the row, so it can't be
+    // quietly undone to let the transfer be reversed again
+    db.conn
+        .execute_named(
+            "UPDATE ledger SET reverses = NULL WHERE id = :id",
+            &[(":id", &reversal)],
+        )
+        .unwrap();
+    assert_eq!(
+        ledger::verify_chain(&db.conn).unwrap(),
+        ledger::Chain::Broken(reversal)
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn archived_reversal_still_counts() {
+    let path = "/tmp/rtcoinserver-reversal-archive-test.db";
+    let dir = "/tmp/rtcoinserver-reversal-archive-test";
+    let db = db_with_users(path, &["alice", "bob"]);
+
+    let sent = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(5)).unwrap();
+    let reversal = apply(&db.conn, &sent).unwrap();
+    crate::archive::close_before(&db.conn, "9999-01-01T00:00:00Z", dir).unwrap();
+
+    // The link survives archiving, and still keeps the
+    // transfer from being reversed a second time
+    assert_eq!(reversed_by(&db.conn, sent.id).unwrap(), Some(reversal.id));
+    assert_eq!(apply(&db.conn, &sent).unwrap_err().code(), 3);
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1000)
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,93 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use chrono::prelude::*;
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::db;
+use crate::ledger;
+use crate::stats::*;
+use crate::tests::{self, db_with_users};
+use crate::user;
+
+#[test]
+fn gini_runs_from_even_to_lopsided() {
+    let tcoin = |vals: &[i64]| {
+        vals.iter()
+            .map(|val| Amount::from_tcoin(*val))
+            .collect::<Vec<Amount>>()
+    };
+    assert_eq!(gini(&[]), 0.0);
+    assert_eq!(gini(&tcoin(&[5, 5, 5, 5])), 0.0);
+    assert_eq!(gini(&tcoin(&[0, 0, 0, 10])), 0.75);
+    assert!((gini(&tcoin(&[1, 2, 3])) - 2.0 / 9.0).abs() < 1e-12);
+}
+
+#[test]
+fn stats_leave_private_accounts_off_the_list() {
+    let path = "/tmp/rtcoinserver-stats-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+    ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(300)).unwrap();
+    ledger::transfer(&db.conn, "send", "carol", "bob", Amount::from_tcoin(200)).unwrap();
+    let (comm, reply) = tests::comm(
+        db::Kind::Deactivate,
+        &["carol", tests::PASS, "carol", "alice"],
+    );
+    user::deactivate(comm, &mut db.conn);
+    reply.recv().unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Privacy, &["bob", tests::PASS, "private"]);
+    privacy(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert_eq!(msg, "You're off the public rich list"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let today = Utc::now().format("%Y-%m-%d").to_string();
+    let (comm, reply) = tests::comm(db::Kind::Stats, &["2"]);
+    stats(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(
+            rows,
+            vec![
+                "supply\t4000".to_string(),
+                "accounts\t3".to_string(),
+                "gini\t0.0833".to_string(),
+                "rich\t1\talice\t1500".to_string(),
+                "rich\t2\troot\t1000".to_string(),
+                format!("day\t{}\t3\t1300", today),
+            ]
+        ),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    // Admins see everyone, and a bit more
+    let (comm, reply) = tests::comm(db::Kind::Report, &["bob", tests::PASS]);
+    report(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Report, &["root", tests::PASS, "2"]);
+    report(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => {
+            assert_eq!(rows[4], "rich\t2\tbob\t1500");
+            assert_eq!(
+                &rows[rows.len() - 4..],
+                &["treasury\t0", "escrow\t0", "inactive\t1", "frozen\t0"]
+            );
+        }
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,92 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use std::fs;
+
+use rusqlite::NO_PARAMS;
+
+use crate::amount::Amount;
+use crate::audit;
+use crate::db;
+use crate::ledger;
+use crate::tests::{self, db_with_users};
+use crate::treasury::*;
+use crate::user;
+
+#[test]
+fn grants_come_out_of_the_treasury() {
+    let path = "/tmp/rtcoinserver-treasury-test.db";
+    let mut db = db_with_users(path, &["alice", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    // db_with_users() minted exactly what the grants needed
+    assert_eq!(balance(&db.conn).unwrap(), Amount::ZERO);
+    assert_eq!(issued(&db.conn).unwrap(), Amount::from_tcoin(2000));
+
+    // So the next registration has nothing to draw on,
+    // and the account opens without a grant
+    let (comm, reply) = tests::comm(db::Kind::Register, &["carol", tests::PASS, "key"]);
+    user::register(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("No grant was paid")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(ledger::balance_of(&db.conn, "carol").unwrap(), Amount::ZERO);
+
+    let (comm, reply) = tests::comm(db::Kind::Mint, &["root", tests::PASS, "600"]);
+    mint(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => {
+            assert!(msg.starts_with("Minted 600 tcoin. The treasury holds 600 tcoin."))
+        }
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Grant, &["root", tests::PASS, "250"]);
+    set_grant(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert_eq!(msg, "New accounts will be granted 250 tcoin"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let (comm, reply) = tests::comm(db::Kind::Register, &["bob", tests::PASS, "key"]);
+    user::register(comm, &mut db.conn);
+    reply.recv().unwrap();
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_tcoin(250), Amount::from_tcoin(250))
+    );
+
+    // Only admins can change the supply, and only what
+    // the treasury holds can be burned
+    let (comm, reply) = tests::comm(db::Kind::Mint, &["alice", tests::PASS, "5"]);
+    mint(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(
+        destroy(&db.conn, Amount::from_tcoin(351))
+            .unwrap_err()
+            .code(),
+        7
+    );
+    let (comm, reply) = tests::comm(db::Kind::Burn, &["root", tests::PASS, "100"]);
+    burn(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(msg) => assert!(msg.contains("The treasury holds 250 tcoin")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    let findings = audit::report(&db.conn).unwrap();
+    assert!(findings.iter().all(|finding| finding.ok));
+    assert_eq!(
+        findings[0].to_string(),
+        "supply\tok\t2500 tcoin held, 2500 tcoin issued"
+    );
+
+    fs::remove_file(path).unwrap();
+}
@@ -3,15 +3,20 @@
 // See LICENSE file for detailed license information.
 //
 
-// Since tildecoin isn't supposed to be used for anything serious,
-// the rounding issues in floating point arithmetic are acceptable.
-#![allow(clippy::float_cmp)]
-
 extern crate test;
 
-use std::sync::mpsc;
+use std::{fs, sync::mpsc};
+
+use rusqlite::NO_PARAMS;
 
+use crate::amount::Amount;
+use crate::audit;
 use crate::db;
+use crate::ledger;
+use crate::query;
+use crate::reversal;
+use crate::tests::{self, db_with_users};
+use crate::treasury;
 use crate::user::*;
 
 #[test]
@@ -23,11 +28,12 @@ fn create_user_check_name_and_balance() {
     let bal_str = format!("{}", user.balance());
 
     assert_eq!(name, "Bob Bobson");
-    assert_eq!(bal, 1000.0);
-    assert_eq!(bal_str, "1000");
+    assert_eq!(bal, Amount::ZERO);
+    assert_eq!(bal_str, "0");
 
     let (_, rx) = mpsc::channel::<db::Comm>();
-    let db = db::DB::connect(db::PATH, "password".into(), rx);
+    let mut db = db::DB::connect(db::PATH, "password".into(), rx);
+    treasury::issue(&db.conn, treasury::DEFAULT_GRANT).unwrap();
     let (tx, _) = mpsc::channel::<db::Reply>();
     register(
         db::Comm {
@@ -38,8 +44,10 @@ fn create_user_check_name_and_balance() {
                 "testpubkeyhere".into(),
             ]),
             origin: Some(tx),
+            key: None,
+            internal: false,
         },
-        &db.conn,
+        &mut db.conn,
     );
 
     let auth_out = auth("gbmor", "testpasswordhere", &db.conn);
@@ -65,7 +73,7 @@ fn bench_check_pass(b: &mut test::Bencher) {
 #[bench]
 fn bench_register(b: &mut test::Bencher) {
     let (_, rx) = mpsc::channel::<db::Comm>();
-    let db = db::DB::connect(db::PATH, "password".into(), rx);
+    let mut db = db::DB::connect(db::PATH, "password".into(), rx);
     let (otx, _) = mpsc::channel::<db::Reply>();
     let comm = db::Comm {
         kind: Some(db::Kind::Register),
@@ -75,6 +83,241 @@ fn bench_register(b: &mut test::Bencher) {
             "pubkey".into(),
         ]),
         origin: Some(otx),
+        key: None,
+        internal: false,
     };
-    b.iter(|| register(comm.clone(), &db.conn))
+    b.iter(|| register(comm.clone(), &mut db.conn))
+}
+
+#[test]
+fn send_transfers_and_rolls_back() {
+    let path = "/tmp/rtcoinserver-send-test.db";
+    let mut db = db_with_users(path, &["alice", "bob"]);
+
+    let (comm, reply) = tests::comm(db::Kind::Send, &["alice", tests::PASS, "bob", "100"]);
+    send(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Info(_) => {}
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::balance_of(&db.conn, "alice").unwrap(),
+        Amount::from_tcoin(900)
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1100)
+    );
+
+    let (comm, reply) = tests::comm(db::Kind::Send, &["alice", "wrongpassword", "bob", "100"]);
+    send(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Authentication Error")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    let (comm, reply) = tests::comm(db::Kind::Send, &["alice", tests::PASS, "bob", "9000"]);
+    send(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Insufficient Funds")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::balance_of(&db.conn, "alice").unwrap(),
+        Amount::from_tcoin(900)
+    );
+    assert_eq!(
+        ledger::balance_of(&db.conn, "bob").unwrap(),
+        Amount::from_tcoin(1100)
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn balance_reconciles_with_ledger() {
+    let path = "/tmp/rtcoinserver-balance-test.db";
+    let mut db = db_with_users(path, &["alice", "bob"]);
+
+    let (comm, reply) = tests::comm(db::Kind::Send, &["alice", tests::PASS, "bob", "12.5"]);
+    send(comm, &mut db.conn);
+    reply.recv().unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Balance, &["alice", tests::PASS]);
+    balance(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Data(bal) => assert_eq!(bal, "987.5"),
+        other => panic!("Expected Data, got {:?}", other),
+    }
+
+    // Someone edits the stored balance by hand
+    db.conn
+        .execute(
+            "UPDATE users SET balance = 5000000 WHERE name = 'bob'",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+    let (comm, reply) = tests::comm(db::Kind::Balance, &["bob", tests::PASS]);
+    balance(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Balance Mismatch")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rename_keeps_history() {
+    let path = "/tmp/rtcoinserver-rename-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol"]);
+    let sent = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(10)).unwrap();
+
+    let rename_as = |conn: &mut rusqlite::Connection, args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(db::Kind::Rename, args);
+        rename(comm, conn);
+        reply.recv().unwrap()
+    };
+    match rename_as(&mut db.conn, &["bob", "robert", "wrongpassword"]) {
+        db::Reply::Error(err) => assert!(err.contains("Authentication Error")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match rename_as(&mut db.conn, &["bob", "robert", tests::PASS]) {
+        db::Reply::Info(msg) => assert_eq!(msg, "Username update successful"),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+
+    // The balance and history come along; the ledger row
+    // still says bob
+    assert_eq!(
+        ledger::reconcile(&db.conn, "robert").unwrap(),
+        (Amount::from_tcoin(1010), Amount::from_tcoin(1010))
+    );
+    assert_eq!(ledger::balance_of(&db.conn, "bob").unwrap_err().code(), 8);
+    let (comm, reply) = tests::comm(db::Kind::History, &["robert", tests::PASS]);
+    query::history(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => {
+            assert_eq!(rows.len(), 2);
+            assert!(rows[0].ends_with("\tsend\talice\tbob\t10"));
+        }
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+
+    // The old name still reaches robert, and nobody else
+    // can have it
+    let entry = ledger::transfer(&db.conn, "send", "alice", "bob", Amount::from_tcoin(1)).unwrap();
+    assert_eq!(entry.destination, "robert");
+    let (comm, reply) = tests::comm(db::Kind::History, &["alice", tests::PASS, "with=bob"]);
+    query::history(comm, &db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Rows(rows) => assert_eq!(rows.len(), 2),
+        other => panic!("Expected Rows, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Register, &["bob", tests::PASS, "key"]);
+    register(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("is taken")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    let (comm, reply) = tests::comm(db::Kind::Register, &[]);
+    register(comm, &mut db.conn);
+    match reply.recv().unwrap() {
+        db::Reply::Error(err) => assert!(err.contains("Invalid Request")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    for taken in &["bob", "robert", "escrow"] {
+        match rename_as(&mut db.conn, &["carol", taken, tests::PASS]) {
+            db::Reply::Error(_) => {}
+            other => panic!("Expected Error for {}, got {:?}", taken, other),
+        }
+    }
+
+    // But robert can go back to it
+    match rename_as(&mut db.conn, &["robert", "bob", tests::PASS]) {
+        db::Reply::Info(_) => {}
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        id_of(&db.conn, "robert").unwrap(),
+        id_of(&db.conn, "bob").unwrap()
+    );
+    assert!(reversal::apply(&db.conn, &sent).is_ok());
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn deactivate_sweeps_and_locks_out() {
+    let path = "/tmp/rtcoinserver-deactivate-test.db";
+    let mut db = db_with_users(path, &["alice", "bob", "carol", "root"]);
+    db.conn
+        .execute("UPDATE users SET admin = 1 WHERE name = 'root'", NO_PARAMS)
+        .unwrap();
+
+    let deactivate_as = |conn: &mut rusqlite::Connection, args: &[&str]| -> db::Reply {
+        let (comm, reply) = tests::comm(db::Kind::Deactivate, args);
+        deactivate(comm, conn);
+        reply.recv().unwrap()
+    };
+
+    // Only alice or an admin can close alice's account
+    match deactivate_as(&mut db.conn, &["bob", tests::PASS, "alice", "bob"]) {
+        db::Reply::Error(err) => assert!(err.contains("Permission Denied")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match deactivate_as(&mut db.conn, &["alice", tests::PASS, "alice", "bob"]) {
+        db::Reply::Info(msg) => {
+            assert!(msg.starts_with("Deactivated alice. Swept 1000 tcoin to bob."))
+        }
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        ledger::reconcile(&db.conn, "bob").unwrap(),
+        (Amount::from_tcoin(2000), Amount::from_tcoin(2000))
+    );
+
+    // alice can't log in or be paid, but her history is
+    // still there
+    assert!(!auth("alice", tests::PASS, &db.conn));
+    assert_eq!(
+        ledger::transfer(&db.conn, "send", "bob", "alice", Amount::from_tcoin(1))
+            .unwrap_err()
+            .code(),
+        15
+    );
+    assert_eq!(
+        ledger::reconcile(&db.conn, "alice").unwrap(),
+        (Amount::ZERO, Amount::ZERO)
+    );
+    match deactivate_as(&mut db.conn, &["root", tests::PASS, "alice"]) {
+        db::Reply::Error(err) => assert!(err.contains("Inactive Account")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // An admin closing carol's account can't pick who gets
+    // her balance; it goes back to the treasury
+    match deactivate_as(&mut db.conn, &["root", tests::PASS, "carol", "root"]) {
+        db::Reply::Error(err) => assert!(err.contains("Only the account holder")),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match deactivate_as(&mut db.conn, &["root", tests::PASS, "carol"]) {
+        db::Reply::Info(msg) => assert!(msg.contains("Swept 1000 tcoin to treasury")),
+        other => panic!("Expected Info, got {:?}", other),
+    }
+    assert_eq!(
+        treasury::balance(&db.conn).unwrap(),
+        Amount::from_tcoin(1000)
+    );
+    assert!(audit::report(&db.conn)
+        .unwrap()
+        .iter()
+        .all(|finding| finding.ok));
+
+    fs::remove_file(path).unwrap();
 }
@@ -0,0 +1,244 @@
+//
+// rtcoin - Copyright (c) 2019 Ben Morrison (gbmor)
+// See LICENSE file for detailed license information.
+//
+
+use rusqlite::{OptionalExtension, NO_PARAMS};
+
+use crate::{amount::Amount, db, err, ledger, user};
+
+// The ledger account new tcoin is minted into and the
+// onboarding grant is paid out of. Like escrow, it isn't
+// a user, so nobody can log in as it.
+pub const ACCOUNT: &str = "treasury";
+
+// Where minted tcoin comes from and burned tcoin goes.
+// Its ledger history runs negative by exactly the
+// supply in circulation.
+pub const MINT: &str = "mint";
+
+// What every account was handed at registration before
+// the treasury existed, and what new accounts get until
+// an admin sets the grant to something else.
+pub const DEFAULT_GRANT: Amount = Amount::from_tcoin(1000);
+
+// Issues new tcoin into the treasury. Accepts the args
+//     vec![admin, password, amount]
+// Admin only.
+pub fn mint(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    adjust(comm, conn, true);
+}
+
+// Destroys tcoin held in the treasury. Accepts the args
+//     vec![admin, password, amount]
+// Admin only.
+pub fn burn(comm: db::Comm, conn: &mut rusqlite::Connection) {
+    adjust(comm, conn, false);
+}
+
+fn adjust(mut comm: db::Comm, conn: &mut rusqlite::Connection, minting: bool) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, amount",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let amount = match args[2].parse::<Amount>() {
+        Ok(val) => val,
+        Err(details) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    let verb = if minting { "Minted" } else { "Burned" };
+    match adjust_tx(conn, amount, minting) {
+        Ok((entry, held)) => {
+            log::info!(
+                "{} {} tcoin on behalf of {} in ledger entry {}",
+                verb,
+                amount,
+                args[0],
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "{} {} tcoin. The treasury holds {} tcoin. Receipt {}: {}",
+                verb, amount, held, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "{} {} tcoin for {} failed: {}",
+                verb,
+                amount,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn adjust_tx(
+    conn: &mut rusqlite::Connection,
+    amount: Amount,
+    minting: bool,
+) -> Result<(db::LedgerEntry, Amount), err::Resp> {
+    let tx = conn.transaction()?;
+    let entry = if minting {
+        issue(&tx, amount)?
+    } else {
+        destroy(&tx, amount)?
+    };
+    let held = balance(&tx)?;
+    tx.commit()?;
+    Ok((entry, held))
+}
+
+// Appends a mint row moving new tcoin into the treasury.
+// Like ledger::transfer(), this expects to be handed a
+// transaction.
+pub fn issue(conn: &rusqlite::Connection, amount: Amount) -> Result<db::LedgerEntry, err::Resp> {
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Amount must be greater than zero",
+        ));
+    }
+    ledger::append(conn, "mint", MINT, ACCOUNT, amount)
+}
+
+// Appends a burn row taking tcoin out of the treasury
+// for good. Only what the treasury holds can be burned.
+pub fn destroy(conn: &rusqlite::Connection, amount: Amount) -> Result<db::LedgerEntry, err::Resp> {
+    if !amount.is_positive() {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Amount must be greater than zero",
+        ));
+    }
+    let held = balance(conn)?;
+    if held < amount {
+        let details = format!(
+            "The treasury holds {} tcoin, tried to burn {}",
+            held, amount
+        );
+        return Err(err::Resp::new(7, "Insufficient Funds", &details));
+    }
+    ledger::append(conn, "burn", ACCOUNT, MINT, amount)
+}
+
+// Sets how much each new account is granted. Accepts
+// the args
+//     vec![admin, password, amount]
+// Zero turns the grant off. Admin only.
+pub fn set_grant(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: admin, password, amount",
+        ));
+        return;
+    }
+    if !user::auth_admin(&comm, &mut args, conn) {
+        return;
+    }
+    let amount = match args[2].parse::<Amount>() {
+        Ok(val) if val >= Amount::ZERO => val,
+        Ok(_) => {
+            comm.reply_error(err::Resp::new(
+                3,
+                "Invalid Request",
+                "The grant can't be negative",
+            ));
+            return;
+        }
+        Err(details) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+
+    let set = conn.execute_named(
+        "INSERT OR REPLACE INTO settings (name, value) VALUES ('grant', :value)",
+        &[(":value", &amount)],
+    );
+    match set {
+        Ok(_) => {
+            log::info!("Onboarding grant set to {} tcoin by {}", amount, args[0]);
+            comm.reply(db::Reply::Info(format!(
+                "New accounts will be granted {} tcoin",
+                amount
+            )));
+        }
+        Err(error) => {
+            let resp: err::Resp = error.into();
+            log::error!("Setting the grant failed: {}", resp.details());
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// How much each new account is granted.
+pub fn grant(conn: &rusqlite::Connection) -> Result<Amount, err::Resp> {
+    let grant = conn
+        .query_row(
+            "SELECT value FROM settings WHERE name = 'grant'",
+            NO_PARAMS,
+            |row| row.get::<usize, Amount>(0),
+        )
+        .optional()?;
+    Ok(grant.unwrap_or(DEFAULT_GRANT))
+}
+
+// Pays the onboarding grant to a newly registered user.
+// Nothing is created out of thin air, so if the treasury
+// can't cover the grant, none is paid and the account
+// starts out empty, same as when the grant is turned
+// off. Either way this returns None. Expects to be handed
+// a transaction.
+pub fn pay_grant(
+    conn: &rusqlite::Connection,
+    name: &str,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let amount = grant(conn)?;
+    if !amount.is_positive() {
+        return Ok(None);
+    }
+    let held = balance(conn)?;
+    if held < amount {
+        log::warn!(
+            "The treasury holds {} tcoin, not enough for a {} tcoin grant to {}",
+            held,
+            amount,
+            name
+        );
+        return Ok(None);
+    }
+    ledger::credit(conn, "grant", ACCOUNT, name, amount).map(Some)
+}
+
+// What the treasury holds. It has no row in the users
+// table, so this comes straight from its ledger history.
+pub fn balance(conn: &rusqlite::Connection) -> Result<Amount, err::Resp> {
+    ledger::net(conn, ACCOUNT)
+}
+
+// Everything ever minted, less everything burned.
+pub fn issued(conn: &rusqlite::Connection) -> Result<Amount, err::Resp> {
+    let net = ledger::net(conn, MINT)?;
+    Amount::ZERO.checked_sub(net).ok_or_else(|| {
+        let details = format!("Issuance of {} tcoin overflows", net);
+        err::Resp::new(9, "Balance Mismatch", &details)
+    })
+}
@@ -6,16 +6,25 @@
 use std::fmt;
 
 use chrono::prelude::*;
+use rusqlite::OptionalExtension;
 use zeroize::Zeroize;
 
-use crate::db;
+use crate::{amount::Amount, db, err, escrow, ledger, message, treasury};
+
+// Work factor for stored password hashes. The tests
+// drop it to the minimum so they don't spend all
+// their time hashing.
+#[cfg(not(test))]
+const BCRYPT_COST: u32 = 12;
+#[cfg(test)]
+const BCRYPT_COST: u32 = 4;
 
 #[derive(Debug)]
 pub struct User {
     name: String,
     created: String,
     pass: String,
-    balance: f64,
+    balance: Amount,
     messages: Vec<String>,
     last_login: String,
 }
@@ -61,7 +70,7 @@ impl User {
             name,
             created: now.clone(),
             pass,
-            balance: 1000.0,
+            balance: Amount::ZERO,
             messages: Vec::new(),
             last_login: now,
         }
@@ -83,22 +92,37 @@ impl User {
         &self.name
     }
 
-    pub fn balance(&self) -> f64 {
+    pub fn balance(&self) -> Amount {
         self.balance
     }
 }
 
-// Accepts a registration request and adds a new user to the database
-pub fn register(comm: db::Comm, db: &rusqlite::Connection) {
+// Accepts a registration request and adds a new user to the database,
+// then pays them the onboarding grant out of the treasury, if it
+// can cover it.
+pub fn register(comm: db::Comm, db: &mut rusqlite::Connection) {
     let tx = match &comm.origin {
         Some(t) => t,
         None => return,
     };
-    let query = "INSERT INTO users (name, pass, pubkey, balance, created, last_login) VALUES (:name, :pass, :pubkey, :balance, :created, :last_login)";
     let args = match &comm.args {
         Some(val) => val,
         None => return,
     };
+    if args.len() < 3 {
+        let resp = err::Resp::new(3, "Invalid Request", "Expected: user, password, pubkey");
+        if let Err(err) = tx.send(db::Reply::Error(resp.to_string())) {
+            log::warn!("{:?}", err);
+        }
+        return;
+    }
+    if reserved(&args[0]) {
+        let resp = err::Resp::new(3, "Invalid Request", "That name is reserved");
+        if let Err(err) = tx.send(db::Reply::Error(resp.to_string())) {
+            log::warn!("{:?}", err);
+        }
+        return;
+    }
     let mut user = User::new(&args[0]);
     let pass = args[1].clone();
     let pubkey = args[2].clone();
@@ -110,7 +134,7 @@ pub fn register(comm: db::Comm, db: &rusqlite::Connection) {
         return;
     }
 
-    let mut pass = match bcrypt::hash(&pass, 12) {
+    let mut pass = match bcrypt::hash(&pass, BCRYPT_COST) {
         Ok(hash) => hash,
         Err(err) => {
             log::error!("Failed to hash password: {:?}", err);
@@ -123,34 +147,26 @@ pub fn register(comm: db::Comm, db: &rusqlite::Connection) {
 
     user.set_pass(&pass);
 
-    let mut stmt = match db.prepare(query) {
-        Ok(st) => st,
-        Err(err) => {
-            let err = format!("Internal Error: {:?}", err);
-            if let Err(err) = tx.send(db::Reply::Error(err)) {
+    let granted = match enroll_tx(db, &mut user, &pass, &pubkey) {
+        Ok(val) => val,
+        Err(resp) => {
+            log::error!("Registration of {} failed: {}", user.name(), resp.details());
+            if let Err(err) = tx.send(db::Reply::Error(resp.to_string())) {
                 log::warn!("{:?}", err);
             }
+            pass.zeroize();
+            user.scrub_pass();
             return;
         }
     };
 
-    if let Err(err) = stmt.execute_named(&[
-        (":name", &user.name()),
-        (":pass", &pass),
-        (":pubkey", &pubkey),
-        (":balance", &user.balance()),
-        (":created", &user.get_ctime()),
-        (":last_login", &user.get_ctime()),
-    ]) {
-        let err = format!("Internal Error: {:?}", err);
-        if let Err(err) = tx.send(db::Reply::Error(err)) {
-            log::warn!("{:?}", err);
-        }
-        return;
-    }
-
     log::info!("Registration Successful: {}", user);
-    if let Err(err) = tx.send(db::Reply::Info("Registration Successful".into())) {
+    let reply = if granted {
+        "Registration Successful"
+    } else {
+        "Registration Successful. No grant was paid, so the account starts out empty"
+    };
+    if let Err(err) = tx.send(db::Reply::Info(reply.into())) {
         log::warn!("{:?}", err);
     }
 
@@ -158,6 +174,43 @@ pub fn register(comm: db::Comm, db: &rusqlite::Connection) {
     user.scrub_pass();
 }
 
+// The new row and its grant go in together. Returns
+// whether a grant was paid. Names in use, now or before a
+// rename, can't be taken.
+fn enroll_tx(
+    conn: &mut rusqlite::Connection,
+    user: &mut User,
+    pass: &str,
+    pubkey: &str,
+) -> Result<bool, err::Resp> {
+    let tx = conn.transaction()?;
+    if id_of(&tx, user.name())?.is_some() {
+        let details = format!("The name {} is taken", user.name());
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+    tx.execute_named(
+        "INSERT INTO users (name, pass, pubkey, balance, created, last_login)
+            VALUES (:name, :pass, :pubkey, :balance, :created, :last_login)",
+        &[
+            (":name", &user.name()),
+            (":pass", &pass),
+            (":pubkey", &pubkey),
+            (":balance", &user.balance()),
+            (":created", &user.get_ctime()),
+            (":last_login", &user.get_ctime()),
+        ],
+    )?;
+    let granted = match treasury::pay_grant(&tx, user.name())? {
+        Some(entry) => {
+            user.balance = entry.amount;
+            true
+        }
+        None => false,
+    };
+    tx.commit()?;
+    Ok(granted)
+}
+
 // Right now this just checks for a minimum password length
 pub fn check_pass(pass: &str) -> AuthResult<()> {
     if pass.len() < 12 {
@@ -166,63 +219,432 @@ pub fn check_pass(pass: &str) -> AuthResult<()> {
     Ok(())
 }
 
-// Change a username. This is incomplete. I'll need to address the
-// historical transactions associated with the old user somehow.
-pub fn rename(comm: db::Comm, db: &rusqlite::Connection) {
-    let mut args = match comm.args {
-        Some(val) => val,
-        None => {
-            log::error!("Received none value from client comm");
-            return;
-        }
-    };
+// Changes a username. Accepts the args
+//     vec![user, new_name, password]
+// Ledger rows keep the name they were written under, but
+// they're tied to the account by id, so its history and
+// balance come along. The old name stays reserved for the
+// account and still resolves to it.
+pub fn rename(mut comm: db::Comm, db: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, new_name, password",
+        ));
+        return;
+    }
     let old_user = args[0].clone();
     let new_user = args[1].clone();
     let mut pass = args[2].clone();
     args[2].zeroize();
 
-    if auth(&old_user, &pass, &db) {
-        log::info!(
-            "User {} authenticated for: username change to {}",
-            old_user,
-            new_user
-        );
-    } else {
+    let authed = auth(&old_user, &pass, db);
+    pass.zeroize();
+    if !authed {
         log::error!("Auth failed for user {}", old_user);
+        comm.reply_error(err::Resp::new(
+            6,
+            "Authentication Error",
+            "Invalid username or password",
+        ));
         return;
     }
+    log::info!(
+        "User {} authenticated for: username change to {}",
+        old_user,
+        new_user
+    );
+
+    match rename_tx(db, &old_user, &new_user) {
+        Ok(()) => {
+            log::info!("User {} is now {}", old_user, new_user);
+            comm.reply(db::Reply::Info("Username update successful".into()));
+        }
+        Err(resp) => {
+            log::error!(
+                "Rename of {} to {} failed: {}",
+                old_user,
+                new_user,
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
 
-    let stmt = "UPDATE users SET name = :new_user WHERE name = :old_user";
-    let mut stmt = match db.prepare(stmt) {
-        Ok(s) => s,
-        Err(err) => {
-            log::error!("Failed to prepare update username statement: {:?}", err);
-            return;
+fn rename_tx(conn: &mut rusqlite::Connection, old: &str, new: &str) -> Result<(), err::Resp> {
+    let tx = conn.transaction()?;
+    change_name(&tx, old, new)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Tables that refer to users by name and describe
+// something still in progress, so they follow a rename.
+// The ledger and batches are history and keep the name
+// things happened under.
+const NAME_COLUMNS: [(&str, &str); 15] = [
+    ("orders", "owner"),
+    ("orders", "destination"),
+    ("escrow", "source"),
+    ("escrow", "destination"),
+    ("escrow", "arbiter"),
+    ("invoices", "payee"),
+    ("invoices", "payer"),
+    ("disputes", "contestant"),
+    ("disputes", "resolver"),
+    ("dispute_seconds", "name"),
+    ("pending", "source"),
+    ("pending", "destination"),
+    ("pending_signers", "name"),
+    ("archive_balances", "name"),
+    ("idempotency", "name"),
+];
+
+// Renames an account, keeping the old name as an alias
+// and carrying the rename through to everything that
+// refers to it by name. A user can take back one of
+// their own old names. Expects to be handed a
+// transaction.
+pub fn change_name(conn: &rusqlite::Connection, old: &str, new: &str) -> Result<(), err::Resp> {
+    if reserved(new) {
+        return Err(err::Resp::new(
+            3,
+            "Invalid Request",
+            "That name is reserved",
+        ));
+    }
+    let id = match id_of(conn, old)? {
+        Some(val) => val,
+        None => {
+            let details = format!("No such user: {}", old);
+            return Err(err::Resp::new(8, "Unknown User", &details));
         }
     };
+    match id_of(conn, new)? {
+        Some(owner) if owner != id || new == old => {
+            let details = format!("The name {} is taken", new);
+            return Err(err::Resp::new(3, "Invalid Request", &details));
+        }
+        Some(_) => {
+            conn.execute_named("DELETE FROM aliases WHERE name = :name", &[(":name", &new)])?;
+        }
+        None => {}
+    }
 
-    match stmt.execute_named(&[(":new_user", &new_user), (":old_user", &old_user)]) {
-        Ok(_) => {
-            if let Some(tx) = comm.origin {
-                if let Err(err) = tx.send(db::Reply::Info("Username update successful".into())) {
-                    log::error!("Failed to send success message: {:?}", err);
-                    return;
-                }
-            }
+    conn.execute_named(
+        "UPDATE users SET name = :new WHERE id = :id",
+        &[(":new", &new), (":id", &id)],
+    )?;
+    conn.execute_named(
+        "INSERT INTO aliases (name, user_id, retired) VALUES (:name, :user_id, :retired)",
+        &[
+            (":name", &old),
+            (":user_id", &id),
+            (":retired", &ledger::now()),
+        ],
+    )?;
+    for (table, column) in NAME_COLUMNS.iter() {
+        conn.execute_named(
+            &format!(
+                "UPDATE {table} SET {column} = :new WHERE {column} = :old",
+                table = table,
+                column = column
+            ),
+            &[(":new", &new), (":old", &old)],
+        )?;
+    }
+    Ok(())
+}
+
+// Closes an account. Accepts the args
+//     vec![user, password, account, (recipient)]
+// Users can close their own account, and an admin can
+// close anyone's. Whatever the account holds is swept to
+// the recipient, or back to the treasury if none is
+// named, in an ordinary ledger row. Only the account
+// holder can name a recipient; an admin closing someone
+// else's account always sweeps it to the treasury. The users row stays,
+// marked inactive, so the account's history still adds
+// up, but nobody can log in as it or send to it again.
+pub fn deactivate(mut comm: db::Comm, conn: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 3 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, account, (recipient)",
+        ));
+        return;
+    }
+    if !auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let account = args[2].clone();
+    let recipient = args.get(3).cloned();
+
+    let own = match (id_of(conn, &args[0]), id_of(conn, &account)) {
+        (Ok(user), Ok(target)) => user == target,
+        (Err(resp), _) | (_, Err(resp)) => {
+            comm.reply_error(resp);
+            return;
         }
-        Err(err) => {
-            log::error!("Failed to execute update username statement: {:?}", err);
+    };
+    let admin = match is_admin(conn, &args[0]) {
+        Ok(val) => val,
+        Err(resp) => {
+            comm.reply_error(resp);
             return;
         }
+    };
+    if !own && !admin {
+        log::error!(
+            "{} tried to deactivate {} without admin authority",
+            args[0],
+            account
+        );
+        comm.reply_error(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Only the account holder or an admin can deactivate an account",
+        ));
+        return;
+    }
+    if !own && recipient.is_some() {
+        comm.reply_error(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Only the account holder can name a recipient",
+        ));
+        return;
+    }
+
+    match deactivate_tx(conn, &account, recipient.as_deref(), !own || admin) {
+        Ok((name, Some(entry))) => {
+            log::info!(
+                "Account {} deactivated by {}, {} tcoin swept to {} in ledger entry {}",
+                name,
+                args[0],
+                entry.amount,
+                entry.destination,
+                entry.id
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Deactivated {}. Swept {} tcoin to {}. Receipt {}: {}",
+                name, entry.amount, entry.destination, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Ok((name, None)) => {
+            log::info!("Account {} deactivated by {}", name, args[0]);
+            comm.reply(db::Reply::Info(format!("Deactivated {}", name)));
+        }
+        Err(resp) => {
+            log::error!(
+                "Deactivation of {} by {} failed: {}",
+                account,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
     }
+}
 
-    pass.zeroize();
+// Returns the account's current name and the sweep, if
+// there was anything to sweep.
+fn deactivate_tx(
+    conn: &mut rusqlite::Connection,
+    account: &str,
+    recipient: Option<&str>,
+    admin: bool,
+) -> Result<(String, Option<db::LedgerEntry>), err::Resp> {
+    let tx = conn.transaction()?;
+    let name = current_name(&tx, account)?;
+    let swept = close(&tx, &name, recipient, admin)?;
+    tx.commit()?;
+    Ok((name, swept))
+}
+
+// Sweeps an account's balance and marks it inactive. An
+// account with tcoin still held in escrow, coming or
+// going, has to see it settled first. Its standing
+// orders are cancelled. A frozen account can only be
+// closed by an admin sweeping it to the treasury. Expects
+// to be handed a transaction.
+pub fn close(
+    conn: &rusqlite::Connection,
+    name: &str,
+    recipient: Option<&str>,
+    admin: bool,
+) -> Result<Option<db::LedgerEntry>, err::Resp> {
+    let id = match id_of(conn, name)? {
+        Some(val) => val,
+        None => {
+            let details = format!("No such user: {}", name);
+            return Err(err::Resp::new(8, "Unknown User", &details));
+        }
+    };
+    ensure_active(conn, name)?;
+    if let Some(recipient) = recipient {
+        if id_of(conn, recipient)?.is_none() {
+            let details = format!("No such user: {}", recipient);
+            return Err(err::Resp::new(8, "Unknown User", &details));
+        }
+    }
+    let held = conn
+        .query_row_named(
+            "SELECT id FROM escrow WHERE state = 'held' AND (source = :name OR destination = :name)
+                ORDER BY id LIMIT 1",
+            &[(":name", &name)],
+            |row| row.get::<usize, u32>(0),
+        )
+        .optional()?;
+    if let Some(escrow_id) = held {
+        let details = format!("Escrow {} involving {} is still held", escrow_id, name);
+        return Err(err::Resp::new(3, "Invalid Request", &details));
+    }
+
+    conn.execute_named(
+        "UPDATE orders SET state = 'cancelled' WHERE owner = :name AND state = 'active'",
+        &[(":name", &name)],
+    )?;
+    let balance = ledger::balance_of(conn, name)?;
+    let swept = if !balance.is_positive() {
+        None
+    } else if let Some(recipient) = recipient {
+        Some(ledger::transfer(conn, "sweep", name, recipient, balance)?)
+    } else if admin {
+        Some(ledger::reclaim(
+            conn,
+            "sweep",
+            name,
+            treasury::ACCOUNT,
+            balance,
+        )?)
+    } else {
+        Some(ledger::debit(
+            conn,
+            "sweep",
+            name,
+            treasury::ACCOUNT,
+            balance,
+        )?)
+    };
+    conn.execute_named(
+        "UPDATE users SET active = 0 WHERE id = :id",
+        &[(":id", &id)],
+    )?;
+    Ok(swept)
+}
+
+// Refuses accounts that have been deactivated. Names that
+// aren't users pass, and are left for whoever asked to
+// deal with.
+pub fn ensure_active(conn: &rusqlite::Connection, name: &str) -> Result<(), err::Resp> {
+    let active = conn
+        .query_row_named(
+            "SELECT active FROM users WHERE name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, bool>(0),
+        )
+        .optional()?;
+    match active {
+        Some(false) => {
+            let details = format!("{} has been deactivated", name);
+            Err(err::Resp::new(15, "Inactive Account", &details))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn is_admin(conn: &rusqlite::Connection, name: &str) -> Result<bool, err::Resp> {
+    let admin = conn
+        .query_row_named(
+            "SELECT admin FROM users WHERE name = :name",
+            &[(":name", &name)],
+            |row| row.get::<usize, bool>(0),
+        )
+        .optional()?;
+    Ok(admin.unwrap_or(false))
+}
+
+// Looks up the id of the account a name belongs to,
+// whether it's the account's name now or one it used to
+// have. The ledger's own accounts have no id.
+pub fn id_of(conn: &rusqlite::Connection, name: &str) -> Result<Option<u32>, err::Resp> {
+    Ok(conn
+        .query_row_named(
+            "SELECT id FROM users WHERE name = :name
+                UNION ALL
+                SELECT user_id FROM aliases WHERE name = :name
+                LIMIT 1",
+            &[(":name", &name)],
+            |row| row.get::<usize, u32>(0),
+        )
+        .optional()?)
+}
+
+// Every name an account has gone by, its current one
+// first.
+pub fn names_of(conn: &rusqlite::Connection, id: u32) -> Result<Vec<String>, err::Resp> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM users WHERE id = :id
+            UNION ALL
+            SELECT name FROM aliases WHERE user_id = :id",
+    )?;
+    let names = stmt.query_map_named(&[(":id", &id)], |row| row.get::<usize, String>(0))?;
+    Ok(names.collect::<rusqlite::Result<Vec<String>>>()?)
+}
+
+// An account's name as of now.
+pub fn name_of(conn: &rusqlite::Connection, id: u32) -> Result<String, err::Resp> {
+    let name = conn
+        .query_row_named(
+            "SELECT name FROM users WHERE id = :id",
+            &[(":id", &id)],
+            |row| row.get::<usize, String>(0),
+        )
+        .optional()?;
+    name.ok_or_else(|| {
+        let details = format!("No user with id {}", id);
+        err::Resp::new(8, "Unknown User", &details)
+    })
+}
+
+// Follows an old name to the account's current one.
+// Names nobody has had come back unchanged.
+pub fn current_name(conn: &rusqlite::Connection, name: &str) -> Result<String, err::Resp> {
+    match id_of(conn, name)? {
+        Some(id) => name_of(conn, id),
+        None => Ok(name.to_string()),
+    }
+}
+
+// Same, but the name has to belong to someone. For
+// checking a recipient or co-signer before anything is
+// recorded against them.
+pub fn resolve(conn: &rusqlite::Connection, name: &str) -> Result<String, err::Resp> {
+    match id_of(conn, name)? {
+        Some(id) => name_of(conn, id),
+        None => {
+            let details = format!("No such user: {}", name);
+            Err(err::Resp::new(8, "Unknown User", &details))
+        }
+    }
+}
+
+// Names that belong to the ledger's own accounts.
+fn reserved(name: &str) -> bool {
+    [escrow::ACCOUNT, treasury::ACCOUNT, treasury::MINT].contains(&name)
 }
 
 // Authenticates a user's provided password hash against the
-// hash stored in the database.
+// hash stored in the database. Deactivated accounts can't
+// log in at all.
 pub fn auth(user: &str, pass: &str, db: &rusqlite::Connection) -> bool {
-    let pass_verify_stmt = "SELECT pass FROM users WHERE name = :user";
+    let pass_verify_stmt = "SELECT pass FROM users WHERE name = :user AND active = 1";
 
     let mut stored_pass: String =
         match db.query_row_named(pass_verify_stmt, &[(":user", &user)], |row| {
@@ -255,26 +677,269 @@ pub fn auth(user: &str, pass: &str, db: &rusqlite::Connection) -> bool {
     }
 }
 
-// TODO: send tildecoin from one user to another
-pub fn send(comm: db::Comm, _db: &rusqlite::Connection) {
-    let _args = if let Some(args) = comm.args {
-        args
+fn is_active(user: &str, db: &rusqlite::Connection) -> bool {
+    db.query_row_named(
+        "SELECT COUNT(*) FROM users WHERE name = :user AND active = 1",
+        &[(":user", &user)],
+        |row| row.get::<usize, i64>(0),
+    )
+    .map(|count| count > 0)
+    .unwrap_or(false)
+}
+
+// Most requests lead with a username and password. This
+// checks them, scrubs the password from the args, and
+// lets the client know if they didn't check out. The
+// server's own requests on a user's behalf have no
+// password to check, only an account that's still active.
+pub fn auth_args(comm: &db::Comm, args: &mut [String], db: &rusqlite::Connection) -> bool {
+    let authed = if comm.internal {
+        is_active(&args[0], db)
     } else {
-        vec![]
+        auth(&args[0], &args[1], db)
+    };
+    args[1].zeroize();
+
+    if !authed {
+        log::error!("Auth failed for user {}", args[0]);
+        comm.reply_error(err::Resp::new(
+            6,
+            "Authentication Error",
+            "Invalid username or password",
+        ));
+    }
+    authed
+}
+
+// Same as auth_args(), but the user also has to be an
+// admin. Nothing hands out admin rights over the socket;
+// whoever runs the server sets users.admin directly.
+pub fn auth_admin(comm: &db::Comm, args: &mut [String], db: &rusqlite::Connection) -> bool {
+    if !auth_args(comm, args, db) {
+        return false;
+    }
+
+    let admin = match is_admin(db, &args[0]) {
+        Ok(val) => val,
+        Err(resp) => {
+            log::error!("Admin check for {} failed: {}", args[0], resp.details());
+            comm.reply_error(resp);
+            return false;
+        }
+    };
+
+    if !admin {
+        log::error!("Admin request refused for user {}", args[0]);
+        comm.reply_error(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Admin authority required",
+        ));
+    }
+    admin
+}
+
+// Sends tildecoin from one user to another. Accepts the args
+//     vec![sender, password, recipient, amount, (message...)]
+// Both balance updates and the ledger row are written in a
+// single transaction, so either all of it happens or none
+// of it does. The reply carries the transfer's receipt; the
+// recipient can pick theirs up with a receipts request.
+pub fn send(mut comm: db::Comm, db: &mut rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: sender, password, recipient, amount, (message...)",
+        ));
+        return;
+    }
+    if !auth_args(&comm, &mut args, db) {
+        return;
+    }
+
+    let source = args[0].clone();
+    let destination = args[2].clone();
+    let amount = match args[3].parse::<Amount>() {
+        Ok(val) => val,
+        Err(details) => {
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
+            return;
+        }
+    };
+    let message = match message::from_args(&args[4..]) {
+        Ok(val) => val,
+        Err(resp) => {
+            comm.reply_error(resp);
+            return;
+        }
     };
 
-    unimplemented!();
+    match send_tx(db, &source, &destination, amount, message.as_deref()) {
+        Ok(entry) => {
+            log::info!(
+                "Transfer {}: {} tcoin from {} to {}",
+                entry.id,
+                amount,
+                source,
+                destination
+            );
+            comm.reply(db::Reply::Info(format!(
+                "Sent {} tcoin to {}. Receipt {}: {}",
+                amount, destination, entry.receipt_id, entry.receipt_hash
+            )));
+        }
+        Err(resp) => {
+            log::error!(
+                "Transfer from {} to {} failed: {}",
+                source,
+                destination,
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+// Dropping a rusqlite::Transaction without committing rolls
+// it back, so every early return here undoes the transfer.
+fn send_tx(
+    db: &mut rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    message: Option<&str>,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let tx = db.transaction()?;
+    let entry = transfer_with_message(&tx, source, destination, amount, message)?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+// What a send request does once it's been checked over.
+// Expects to be handed a transaction.
+pub fn transfer_with_message(
+    conn: &rusqlite::Connection,
+    source: &str,
+    destination: &str,
+    amount: Amount,
+    message: Option<&str>,
+) -> Result<db::LedgerEntry, err::Resp> {
+    let entry = ledger::transfer(conn, "send", source, destination, amount)?;
+    if let Some(text) = message {
+        message::attach(conn, entry.id, text)?;
+    }
+    Ok(entry)
+}
+
+// Retrieves the balance for a user. Accepts the args
+//     vec![user, password]
+// The stored balance is checked against the one rebuilt
+// from the user's ledger history. If they disagree, the
+// client gets an error carrying both numbers instead of
+// a balance nobody should trust.
+pub fn balance(mut comm: db::Comm, db: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 2 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password",
+        ));
+        return;
+    }
+    if !auth_args(&comm, &mut args, db) {
+        return;
+    }
+
+    let user = args[0].clone();
+
+    match ledger::reconcile(db, &user) {
+        Ok((stored, computed)) if stored == computed => {
+            comm.reply(db::Reply::Data(format!("{}", stored)));
+        }
+        Ok((stored, computed)) => {
+            let details = format!(
+                "Stored balance for {} is {} tcoin, ledger history says {}",
+                user, stored, computed
+            );
+            log::error!("Balance mismatch: {}", details);
+            comm.reply_error(err::Resp::new(9, "Balance Mismatch", &details));
+        }
+        Err(resp) => {
+            log::error!("Balance lookup for {} failed: {}", user, resp.details());
+            comm.reply_error(resp);
+        }
+    }
 }
 
-// TODO: retrieve the balance for a user
-pub fn balance(comm: db::Comm, _db: &rusqlite::Connection) {
-    let _args = match comm.args {
+// Rebuilds what an account held at a given time. Accepts
+// the args
+//     vec![user, password, account, timestamp]
+// where the timestamp is RFC3339. Replies with
+//     balance  last_ledger_id
+// the id being that of the newest row counted. Users can
+// look up their own account; admins, auditing, can look
+// up any account, the ledger's own included. On a ledger
+// that predates the treasury, grants only appear from the
+// time it was migrated; see db::migrate_treasury().
+pub fn balance_as_of(mut comm: db::Comm, conn: &rusqlite::Connection) {
+    let mut args = comm.args.take().unwrap_or_default();
+    if args.len() < 4 {
+        comm.reply_error(err::Resp::new(
+            3,
+            "Invalid Request",
+            "Expected: user, password, account, timestamp",
+        ));
+        return;
+    }
+    if !auth_args(&comm, &mut args, conn) {
+        return;
+    }
+    let timestamp = match ledger::parse_timestamp(&args[3]) {
         Some(val) => val,
         None => {
-            log::error!("Received None for Comm args");
+            let details = format!("Invalid timestamp: {}", args[3]);
+            comm.reply_error(err::Resp::new(3, "Invalid Request", &details));
             return;
         }
     };
 
-    unimplemented!();
+    match as_of(conn, &args[0], &args[2], &timestamp) {
+        Ok((balance, last_id)) => {
+            comm.reply(db::Reply::Data(format!("{}\t{}", balance, last_id)));
+        }
+        Err(resp) => {
+            log::error!(
+                "Balance of {} as of {} for {} failed: {}",
+                args[2],
+                timestamp,
+                args[0],
+                resp.details()
+            );
+            comm.reply_error(resp);
+        }
+    }
+}
+
+fn as_of(
+    conn: &rusqlite::Connection,
+    name: &str,
+    account: &str,
+    timestamp: &str,
+) -> Result<(Amount, u32), err::Resp> {
+    let target = id_of(conn, account)?;
+    if target.is_none() && !reserved(account) {
+        let details = format!("No such user: {}", account);
+        return Err(err::Resp::new(8, "Unknown User", &details));
+    }
+    if (target.is_none() || target != id_of(conn, name)?) && !is_admin(conn, name)? {
+        return Err(err::Resp::new(
+            12,
+            "Permission Denied",
+            "Only the account holder or an admin can look up a past balance",
+        ));
+    }
+    ledger::net_as_of(conn, account, timestamp)
 }